@@ -0,0 +1,69 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+// Offset of the squashfs image in the selftest archive, after its header.
+const SELFTEST_OFFSET: usize = 4096;
+
+#[cfg(feature = "ffi")]
+fn generate_ffi_header() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let header = std::path::Path::new(&crate_dir).join("include/xarfuse.h");
+    cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_language(cbindgen::Language::C)
+        .with_include_guard("XARFUSE_H")
+        .generate()
+        .expect("Unable to generate xarfuse.h")
+        .write_to_file(header);
+}
+
+// Build the archive embedded for `xarfuse selftest`: a sentinel file and a
+// trivial target. Without mksquashfs the archive is left empty and selftest
+// reports that it's unavailable.
+fn generate_selftest_xar() {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let out_dir = Path::new(&out_dir);
+    let xar = out_dir.join("selftest.xar");
+    let root = out_dir.join("selftest");
+    let image = out_dir.join("selftest.squashfs");
+
+    let _ = fs::remove_dir_all(&root);
+    let _ = fs::remove_file(&image);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("sentinel"), "xarfuse selftest\n").unwrap();
+    let target = root.join("run");
+    fs::write(&target, "#!/bin/sh\nexit 0\n").unwrap();
+    fs::set_permissions(&target, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let built = Command::new("mksquashfs")
+        .arg(&root)
+        .arg(&image)
+        .args(&["-all-root", "-noappend", "-no-progress"])
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    if !built {
+        println!("cargo:warning=mksquashfs not found, selftest will be unavailable");
+        fs::write(&xar, b"").unwrap();
+        return;
+    }
+
+    let mut header = format!(
+        "#!/usr/bin/env xarexec_fuse\nOFFSET=\"{}\"\nUUID=\"5e1f7e57\"\nVERSION=\"1\"\nXAREXEC_TARGET=\"run\"\n#xar_stop\n",
+        SELFTEST_OFFSET
+    )
+    .into_bytes();
+    header.resize(SELFTEST_OFFSET, 0);
+    let mut file = fs::File::create(&xar).unwrap();
+    file.write_all(&header).unwrap();
+    file.write_all(&fs::read(&image).unwrap()).unwrap();
+}
+
+fn main() {
+    #[cfg(feature = "ffi")]
+    generate_ffi_header();
+    generate_selftest_xar();
+}
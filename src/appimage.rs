@@ -0,0 +1,55 @@
+// Type 2 AppImages are an ELF runtime with a squashfs image appended, and
+// mark themselves with "AI\x02" in the ELF identification padding. The image
+// starts where the ELF ends, after its section header table.
+
+// Enough for either ELF class's file header.
+pub const HEADER_SIZE: usize = 64;
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const APPIMAGE_MAGIC: &[u8] = b"AI\x02";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+
+// The entry point every AppImage has at its root.
+pub const ENTRY_POINT: &str = "AppRun";
+
+pub fn is_appimage(header: &[u8]) -> bool {
+    header.len() >= HEADER_SIZE && header.starts_with(ELF_MAGIC) && &header[8..11] == APPIMAGE_MAGIC
+}
+
+fn read(field: &[u8], big_endian: bool) -> u64 {
+    let bytes = field.iter().map(|byte| u64::from(*byte));
+    if big_endian {
+        bytes.fold(0, |value, byte| value << 8 | byte)
+    } else {
+        bytes.rev().fold(0, |value, byte| value << 8 | byte)
+    }
+}
+
+// Offset of the squashfs image, from the ELF file header.
+pub fn payload_offset(header: &[u8]) -> Result<u64, failure::Error> {
+    if !is_appimage(header) {
+        bail!("not a type 2 AppImage");
+    }
+    let big_endian = match header[5] {
+        ELFDATA2LSB => false,
+        ELFDATA2MSB => true,
+        data => bail!("AppImage has unknown ELF data encoding {}", data),
+    };
+    // e_shoff, e_shentsize and e_shnum.
+    let (shoff, shentsize, shnum) = match header[4] {
+        ELFCLASS32 => (
+            &header[0x20..0x24],
+            &header[0x2e..0x30],
+            &header[0x30..0x32],
+        ),
+        ELFCLASS64 => (
+            &header[0x28..0x30],
+            &header[0x3a..0x3c],
+            &header[0x3c..0x3e],
+        ),
+        class => bail!("AppImage has unknown ELF class {}", class),
+    };
+    Ok(read(shoff, big_endian) + read(shentsize, big_endian) * read(shnum, big_endian))
+}
@@ -0,0 +1,192 @@
+use crate::mount::directory::Directory;
+use crate::mount::lock::lockfile;
+use crate::xar::Xar;
+
+use nix::unistd::geteuid;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+// The mountpoint rules of the reference xarexec_fuse, kept deliberately
+// independent of Directory so the two can be checked against each other:
+// the first 0o01777 root from MOUNT_ROOT or the defaults, then uid-<euid>,
+// then UUID with optional -seed-<XAR_MOUNT_SEED> and -ns-<mnt ns inode>.
+const REFERENCE_MOUNT_ROOTS: &[&str] = &["/mnt/xarfuse", "/dev/shm"];
+
+// The reference locks a mount with flock on lockfile.<mount dir> beside it,
+// created empty with O_RDWR | O_CREAT and touched on every use. It reads
+// nothing from the lockfile and opens no other file in the user directory,
+// so on fleets running both, our lockfiles must stay flockable at that name
+// and nothing else may take a name it computes.
+pub const LEGACY_LOCK_KIND: &str = "lockfile";
+
+// Whether state files named <kind>.<mount dir> could be mistaken by the
+// reference for its own: its lockfiles, or mount directories, which start
+// with the archive's hexadecimal UUID.
+pub fn is_reserved_kind(kind: &str) -> bool {
+    kind == LEGACY_LOCK_KIND || kind.is_empty() || kind.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Where the reference would look for the lockfile of `mount`, spelled out
+// rather than built from LEGACY_LOCK_KIND so a change there shows up here.
+pub fn reference_lockfile(mount: &Path) -> PathBuf {
+    let name = mount.file_name().unwrap_or_default().to_string_lossy();
+    mount.with_file_name(format!("lockfile.{}", name))
+}
+
+pub struct Divergence {
+    pub component: &'static str,
+    pub ours: String,
+    pub reference: String,
+}
+
+fn is_sticky_world_writable(root: &str) -> bool {
+    fs::metadata(root)
+        .map(|attr| attr.permissions().mode() & 0o7777 == 0o1777)
+        .unwrap_or(false)
+}
+
+// The header as the reference reads it: KEY="value" lines up to #xar_stop,
+// quotes stripped and nothing validated or normalized.
+pub struct ReferenceHeader {
+    pub uuid: String,
+    pub mount_root: Option<String>,
+}
+
+pub fn reference_header(reader: impl BufRead) -> Result<ReferenceHeader, failure::Error> {
+    let mut uuid = None;
+    let mut mount_root = None;
+    for line in reader.split(b'\n') {
+        let line = line?;
+        if line.starts_with(b"#xar_stop") {
+            return Ok(ReferenceHeader {
+                uuid: uuid.ok_or_else(|| format_err!("reference finds no UUID"))?,
+                mount_root,
+            });
+        }
+        let line = String::from_utf8_lossy(&line);
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim_end();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value)
+                .to_string();
+            match key {
+                "UUID" => uuid = Some(value),
+                "MOUNT_ROOT" => mount_root = Some(value),
+                _ => (),
+            }
+        }
+    }
+    bail!("reference finds no #xar_stop")
+}
+
+pub fn reference_mount_path(header: &ReferenceHeader) -> Result<PathBuf, failure::Error> {
+    let root = match &header.mount_root {
+        Some(root) if is_sticky_world_writable(root) => root.as_str(),
+        Some(root) => bail!("reference rejects MOUNT_ROOT {}", root),
+        None => *REFERENCE_MOUNT_ROOTS
+            .iter()
+            .find(|root| is_sticky_world_writable(root))
+            .ok_or_else(|| format_err!("reference finds no 0o01777 mount root"))?,
+    };
+
+    let mut dir = header.uuid.clone();
+    if let Ok(seed) = env::var("XAR_MOUNT_SEED") {
+        if !seed.is_empty() && !seed.contains('/') {
+            dir.push_str(&format!("-seed-{}", seed));
+        }
+    }
+    if let Ok(attr) = fs::metadata("/proc/self/ns/mnt") {
+        dir.push_str(&format!("-ns-{}", attr.ino()));
+    }
+
+    Ok(Path::new(root).join(format!("uid-{}", geteuid())).join(dir))
+}
+
+fn component(path: &Path, depth: usize) -> String {
+    let mut path = path;
+    for _ in 0..depth {
+        path = path.parent().unwrap_or(path);
+    }
+    if depth == 2 {
+        path.display().to_string()
+    } else {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+// Returns our mountpoint, the reference mountpoint, and where they differ.
+pub fn check(xar: &Xar) -> Result<(PathBuf, PathBuf, Vec<Divergence>), failure::Error> {
+    let ours = Directory::from_xar(xar, xar.logger.clone())?.path;
+    let our_lockfile = lockfile(&ours);
+    let raw = BufReader::new(File::open(xar.source_path())?);
+    let reference = reference_mount_path(&reference_header(raw)?)?;
+
+    let mut divergences = Vec::new();
+    for (depth, name) in [
+        (2, "mount root"),
+        (1, "user directory"),
+        (0, "mount directory"),
+    ]
+    .iter()
+    {
+        let (a, b) = (component(&ours, *depth), component(&reference, *depth));
+        if a != b {
+            divergences.push(Divergence {
+                component: *name,
+                ours: a,
+                reference: b,
+            });
+        }
+    }
+    if our_lockfile != reference_lockfile(&ours) {
+        divergences.push(Divergence {
+            component: "lockfile",
+            ours: our_lockfile.display().to_string(),
+            reference: reference_lockfile(&ours).display().to_string(),
+        });
+    }
+    Ok((ours, reference, divergences))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reference_header_is_raw() {
+        let header = b"#!/usr/bin/env xarexec_fuse\nOFFSET=\"4096\"\n\
+            UUID=\"0123456789ABCDEF0123456789ABCDEF\"\nMOUNT_ROOT=\"/mnt/xar\"\n\
+            #xar_stop\nUUID=\"after\"\n";
+        let header = reference_header(&header[..]).unwrap();
+        assert_eq!(header.uuid, "0123456789ABCDEF0123456789ABCDEF");
+        assert_eq!(header.mount_root.as_deref(), Some("/mnt/xar"));
+        assert!(reference_header(&b"UUID=\"abc\"\n"[..]).is_err());
+    }
+
+    #[test]
+    fn lockfile_matches_reference() {
+        let mount = PathBuf::from("/mnt/xarfuse/uid-1000/0123abcd-seed-x-ns-4026531840");
+        assert_eq!(
+            lockfile(&mount),
+            Path::new("/mnt/xarfuse/uid-1000/lockfile.0123abcd-seed-x-ns-4026531840")
+        );
+        assert_eq!(lockfile(&mount), reference_lockfile(&mount));
+    }
+
+    #[test]
+    fn reserved_kinds() {
+        for kind in &["lockfile", "", "0123abcd", "ABCDEF", "dead"] {
+            assert!(is_reserved_kind(kind), "{:?}", kind);
+        }
+        for kind in &["pinned", "failed", "origin", "work", "held", "holder"] {
+            assert!(!is_reserved_kind(kind), "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+// Conversion between XARs and single layer OCI image layouts, so the same
+// build can ship as a container. An archive's payload becomes an
+// uncompressed tar layer with its target as the entrypoint. Going back, the
+// layer is unpacked and packed with mksquashfs, as build.rs does.
+use crate::mount::directory::create_private_directory;
+use crate::squashfs::tar::write_tar;
+use crate::squashfs::SquashFs;
+use crate::xar::{normalize_uuid, Xar};
+
+use failure::ResultExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+const GZIP_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+// Header space before the payload in converted archives.
+const XAR_OFFSET: usize = 4096;
+
+#[derive(Serialize, Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct RunConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entrypoint: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cmd: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    working_dir: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RootFs {
+    #[serde(rename = "type")]
+    kind: String,
+    diff_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Config {
+    architecture: String,
+    os: String,
+    #[serde(default)]
+    config: RunConfig,
+    rootfs: RootFs,
+}
+
+// Counts and hashes what's written through it.
+struct Digesting<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Write for Digesting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn digest_of(hasher: Sha256) -> String {
+    let hex: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256:{}", hex)
+}
+
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf, failure::Error> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(layout.join("blobs/sha256").join(hex))
+        }
+        _ => bail!("unsupported blob digest {}", digest),
+    }
+}
+
+// Store `write`'s output as a blob, named by its digest once it's known.
+fn write_blob<F>(layout: &Path, media_type: &str, write: F) -> Result<Descriptor, failure::Error>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), failure::Error>,
+{
+    let blobs = layout.join("blobs/sha256");
+    let temporary = blobs.join(format!(".incoming.{}", std::process::id()));
+    let mut out = Digesting {
+        inner: io::BufWriter::new(File::create(&temporary)?),
+        hasher: Sha256::new(),
+        size: 0,
+    };
+    write(&mut out)?;
+    out.flush()?;
+    let digest = digest_of(out.hasher);
+    fs::rename(&temporary, blob_path(layout, &digest)?)?;
+    Ok(Descriptor {
+        media_type: media_type.to_string(),
+        digest,
+        size: out.size,
+        annotations: BTreeMap::new(),
+    })
+}
+
+fn write_json_blob<T: Serialize>(
+    layout: &Path,
+    media_type: &str,
+    value: &T,
+) -> Result<Descriptor, failure::Error> {
+    write_blob(layout, media_type, |out| {
+        serde_json::to_writer(out, value)?;
+        Ok(())
+    })
+}
+
+// OCI names architectures as Go does.
+fn oci_architecture() -> String {
+    match nix::sys::utsname::uname().machine() {
+        "x86_64" => "amd64".to_string(),
+        "aarch64" => "arm64".to_string(),
+        "i686" | "i386" => "386".to_string(),
+        machine => machine.to_string(),
+    }
+}
+
+// Write `xar` as an OCI image layout in `layout`, tagged with its UUID.
+pub fn to_oci(xar: &Xar, layout: &Path, logger: &slog::Logger) -> Result<(), failure::Error> {
+    fs::create_dir_all(layout.join("blobs/sha256"))?;
+    let fs = SquashFs::from_xar(xar)?;
+    // Uncompressed, so the layer's digest is also its diff ID.
+    let layer = write_blob(layout, LAYER_MEDIA_TYPE, |out| write_tar(&fs, out, logger))?;
+
+    let target = xar.header.xarexec_target.trim_start_matches('/');
+    let config = Config {
+        architecture: oci_architecture(),
+        os: "linux".to_string(),
+        config: RunConfig {
+            entrypoint: if target.is_empty() {
+                None
+            } else {
+                Some(vec![format!("/{}", target)])
+            },
+            cmd: None,
+            working_dir: xar.header.run_cwd.as_ref().map(|cwd| format!("/{}", cwd)),
+        },
+        rootfs: RootFs {
+            kind: "layers".to_string(),
+            diff_ids: vec![layer.digest.clone()],
+        },
+    };
+    let config = write_json_blob(layout, CONFIG_MEDIA_TYPE, &config)?;
+    let manifest = Manifest {
+        schema_version: 2,
+        media_type: MANIFEST_MEDIA_TYPE.to_string(),
+        config,
+        layers: vec![layer],
+    };
+    let mut manifest = write_json_blob(layout, MANIFEST_MEDIA_TYPE, &manifest)?;
+    manifest
+        .annotations
+        .insert(REF_NAME.to_string(), xar.header.uuid.clone());
+    let index = Index {
+        schema_version: 2,
+        manifests: vec![manifest],
+    };
+    fs::write(layout.join("index.json"), serde_json::to_vec(&index)?)?;
+    fs::write(
+        layout.join("oci-layout"),
+        r#"{"imageLayoutVersion":"1.0.0"}"#,
+    )?;
+    info!(
+        logger,
+        "Wrote OCI image";
+        "layout" => layout.display().to_string(),
+        "layer" => &index.manifests[0].digest
+    );
+    Ok(())
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, failure::Error> {
+    let contents = fs::read(path).with_context(|_| format!("while reading {}", path.display()))?;
+    serde_json::from_slice(&contents)
+        .with_context(|_| format!("while parsing {}", path.display()))
+        .map_err(failure::Error::from)
+}
+
+// Removes the unpacked layer and intermediate image however conversion ends.
+struct Scratch(PathBuf);
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn run(cmd: &mut Command) -> Result<(), failure::Error> {
+    let status = cmd
+        .status()
+        .with_context(|_| format!("while running {:?}", cmd))?;
+    if !status.success() {
+        bail!("{:?} failed: {}", cmd, status);
+    }
+    Ok(())
+}
+
+// Write the single layer image in `layout` as a XAR at `archive`. The target
+// is `target`, or the image's entrypoint or command.
+pub fn from_oci(
+    layout: &Path,
+    archive: &Path,
+    target: Option<&str>,
+    logger: &slog::Logger,
+) -> Result<(), failure::Error> {
+    let index: Index = read_json(&layout.join("index.json"))?;
+    if index.manifests.len() != 1 {
+        bail!(
+            "{} has {} manifests, only single image layouts can be converted",
+            layout.display(),
+            index.manifests.len()
+        );
+    }
+    let manifest: Manifest = read_json(&blob_path(layout, &index.manifests[0].digest)?)?;
+    if manifest.layers.len() != 1 {
+        bail!(
+            "image has {} layers, only single layer images can be converted",
+            manifest.layers.len()
+        );
+    }
+    let layer = &manifest.layers[0];
+    let gzip = match layer.media_type.as_str() {
+        LAYER_MEDIA_TYPE => false,
+        GZIP_LAYER_MEDIA_TYPE => true,
+        media_type => bail!("unsupported layer media type {}", media_type),
+    };
+    let config: Config = read_json(&blob_path(layout, &manifest.config.digest)?)?;
+    let target = match target {
+        Some(target) => target.to_string(),
+        None => match config.config.entrypoint.or(config.config.cmd) {
+            Some(command) if !command.is_empty() => command[0].clone(),
+            _ => bail!("image has no entrypoint or command, pass --target"),
+        },
+    };
+
+    let scratch = Scratch(env::temp_dir().join(format!("xarfuse-convert.{}", std::process::id())));
+    let _ = fs::remove_dir_all(&scratch.0);
+    create_private_directory(logger, &scratch.0)?;
+    let root = scratch.0.join("root");
+    let image = scratch.0.join("image.squashfs");
+    fs::create_dir(&root)?;
+    let mut tar = Command::new("tar");
+    tar.arg(if gzip { "-xzf" } else { "-xf" })
+        .arg(blob_path(layout, &layer.digest)?)
+        .arg("-C")
+        .arg(&root);
+    run(&mut tar)?;
+    let mut mksquashfs = Command::new("mksquashfs");
+    mksquashfs
+        .arg(&root)
+        .arg(&image)
+        .args(&["-all-root", "-noappend", "-no-progress"]);
+    run(&mut mksquashfs)?;
+
+    let hex = layer.digest.trim_start_matches("sha256:");
+    let uuid = normalize_uuid(&hex[..hex.len().min(32)])?;
+    let mut header = format!(
+        "#!/usr/bin/env xarexec_fuse\nOFFSET=\"{}\"\nUUID=\"{}\"\nVERSION=\"1\"\n\
+         XAREXEC_TARGET=\"{}\"\n",
+        XAR_OFFSET,
+        uuid,
+        target.trim_start_matches('/')
+    );
+    if let Some(cwd) = &config.config.working_dir {
+        let cwd = cwd.trim_start_matches('/');
+        if !cwd.is_empty() {
+            header.push_str(&format!("RUN_CWD=\"{}\"\n", cwd));
+        }
+    }
+    header.push_str("#xar_stop\n");
+    let mut header = header.into_bytes();
+    if header.len() > XAR_OFFSET {
+        bail!("header doesn't fit in {} bytes", XAR_OFFSET);
+    }
+    header.resize(XAR_OFFSET, 0);
+    let mut out = File::create(archive)?;
+    out.write_all(&header)?;
+    io::copy(&mut File::open(&image)?, &mut out)?;
+    info!(
+        logger,
+        "Wrote archive";
+        "archive" => archive.display().to_string(),
+        "uuid" => uuid
+    );
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use crate::mount::directory::Directory;
+use crate::xar::Xar;
+
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+#[derive(PartialEq)]
+enum Entry {
+    Directory {
+        mode: u32,
+    },
+    File {
+        mode: u32,
+        size: u64,
+        checksum: Vec<u8>,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+pub enum Change {
+    Header {
+        key: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    Added(PathBuf),
+    Removed(PathBuf),
+    Changed(PathBuf),
+}
+
+// One tab separated record per line, for consumption by release tooling.
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Header { key, old, new } => write!(
+                f,
+                "H\t{}\t{}\t{}",
+                key,
+                old.as_ref().map_or("", String::as_str),
+                new.as_ref().map_or("", String::as_str)
+            ),
+            Change::Added(path) => write!(f, "A\t{}", path.display()),
+            Change::Removed(path) => write!(f, "D\t{}", path.display()),
+            Change::Changed(path) => write!(f, "M\t{}", path.display()),
+        }
+    }
+}
+
+fn header_table(xar: &Xar) -> Result<toml::value::Table, failure::Error> {
+    match toml::Value::try_from(&xar.header)? {
+        toml::Value::Table(table) => Ok(table),
+        _ => Err(format_err!("header did not serialize to a table")),
+    }
+}
+
+fn diff_headers(a: &Xar, b: &Xar, changes: &mut Vec<Change>) -> Result<(), failure::Error> {
+    let old = header_table(a)?;
+    let new = header_table(b)?;
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        let old_value = old.get(key).map(|value| value.to_string());
+        let new_value = new.get(key).map(|value| value.to_string());
+        if old_value != new_value {
+            changes.push(Change::Header {
+                key: key.clone(),
+                old: old_value,
+                new: new_value,
+            });
+        }
+    }
+    Ok(())
+}
+
+// SHA-256, so files differing only in content can't compare equal by
+// chance, unlike with a 64 bit hash.
+fn checksum(path: &Path) -> Result<Vec<u8>, failure::Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+fn walk(
+    root: &Path,
+    dir: &Path,
+    entries: &mut BTreeMap<PathBuf, Entry>,
+) -> Result<(), failure::Error> {
+    for dirent in fs::read_dir(dir)? {
+        let path = dirent?.path();
+        let attr = fs::symlink_metadata(&path)?;
+        let relative = path.strip_prefix(root)?.to_path_buf();
+        let mode = attr.permissions().mode();
+        let file_type = attr.file_type();
+        if file_type.is_dir() {
+            entries.insert(relative, Entry::Directory { mode });
+            walk(root, &path, entries)?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&path)?;
+            entries.insert(relative, Entry::Symlink { target });
+        } else {
+            let checksum = checksum(&path)?;
+            let size = attr.len();
+            entries.insert(
+                relative,
+                Entry::File {
+                    mode,
+                    size,
+                    checksum,
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+// Archives diff had to mount are unmounted again once listed, ones which
+// were already mounted stay as they were.
+fn list_files(xar: &Xar, mount: Directory) -> Result<BTreeMap<PathBuf, Entry>, failure::Error> {
+    let unmount = !mount.is_mounted()?;
+    let mount = xar.mount_guarded(mount, unmount)?;
+
+    let mut entries = BTreeMap::new();
+    walk(mount.path(), mount.path(), &mut entries)?;
+    Ok(entries)
+}
+
+// Compare the headers and file listings of two archives, mounting each in
+// turn.
+pub fn diff(a: &Xar, b: &Xar) -> Result<Vec<Change>, failure::Error> {
+    let mut changes = Vec::new();
+    diff_headers(a, b, &mut changes)?;
+
+    let a_mount = Directory::from_xar(a, a.logger.clone())?;
+    let b_mount = Directory::from_xar(b, b.logger.clone())?;
+    if a_mount.path == b_mount.path {
+        bail!(
+            "Both archives share the mountpoint {}, cannot compare files",
+            a_mount.path.display()
+        );
+    }
+
+    let old = list_files(a, a_mount)?;
+    let new = list_files(b, b_mount)?;
+    for (path, entry) in &old {
+        match new.get(path) {
+            None => changes.push(Change::Removed(path.clone())),
+            Some(other) if other != entry => changes.push(Change::Changed(path.clone())),
+            Some(_) => (),
+        }
+    }
+    for path in new.keys() {
+        if !old.contains_key(path) {
+            changes.push(Change::Added(path.clone()));
+        }
+    }
+    Ok(changes)
+}
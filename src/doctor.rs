@@ -0,0 +1,151 @@
+use crate::mount::capabilities;
+use crate::mount::directory::find_mount_root_skipping;
+use crate::mount::SQUASHFUSE_BIN;
+use crate::selinux;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        };
+        write!(f, "{:<4} {}: {}", status, self.name, self.detail)
+    }
+}
+
+fn find_in_path(bin: &str) -> Option<PathBuf> {
+    env::var_os("PATH").and_then(|path| {
+        env::split_paths(&path)
+            .map(|dir| dir.join(bin))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+fn check_squashfuse() -> Check {
+    match find_in_path(SQUASHFUSE_BIN) {
+        Some(path) => {
+            let capabilities = capabilities::squashfuse();
+            let mut detail = format!(
+                "{}, version {}, options: {}",
+                path.display(),
+                capabilities.version.as_deref().unwrap_or("unknown"),
+                capabilities.options().join(", ")
+            );
+            let status = if capabilities.timeout {
+                Status::Ok
+            } else {
+                detail.push_str("; no timeout=, so idle mounts are never unmounted");
+                Status::Warn
+            };
+            Check {
+                name: "squashfuse",
+                status,
+                detail,
+            }
+        }
+        None => Check {
+            name: "squashfuse",
+            status: Status::Fail,
+            detail: format!("{} not found in PATH", SQUASHFUSE_BIN),
+        },
+    }
+}
+
+// Copy a trivial binary into the mount root and run it, which fails when
+// the root is mounted noexec or execution there is denied by policy.
+fn check_exec(root: &Path) -> Check {
+    let probe = root.join(format!(".xarfuse-doctor-{}", std::process::id()));
+    let result = fs::copy("/bin/true", &probe)
+        .and_then(|_| fs::set_permissions(&probe, fs::Permissions::from_mode(0o700)))
+        .and_then(|_| Command::new(&probe).status());
+    let _ = fs::remove_file(&probe);
+    match result {
+        Ok(ref status) if status.success() => Check {
+            name: "exec",
+            status: Status::Ok,
+            detail: format!("binaries run from {}", root.display()),
+        },
+        Ok(status) => Check {
+            name: "exec",
+            status: Status::Fail,
+            detail: format!("probe in {} exited with {}", root.display(), status),
+        },
+        Err(e) => Check {
+            name: "exec",
+            status: Status::Fail,
+            detail: format!("cannot run binaries from {}: {}", root.display(), e),
+        },
+    }
+}
+
+fn check_selinux(root: &Path) -> Check {
+    if !selinux::is_enforcing() {
+        return Check {
+            name: "selinux",
+            status: Status::Ok,
+            detail: "not enforcing".to_string(),
+        };
+    }
+    let process = selinux::current_context().unwrap_or_else(|| "unknown".to_string());
+    let label = selinux::file_context(root).unwrap_or_else(|| "unknown".to_string());
+    Check {
+        name: "selinux",
+        status: Status::Warn,
+        detail: format!(
+            "enforcing, process {} and {} labelled {}; if execs from mounts are denied, \
+             pass `mount --context` with a context the process may execute",
+            process,
+            root.display(),
+            label
+        ),
+    }
+}
+
+pub fn run(logger: &slog::Logger, mount_root: &Option<String>) -> Vec<Check> {
+    let mut checks = vec![check_squashfuse()];
+    let (root, skipped) = find_mount_root_skipping(logger, mount_root);
+    for skip in skipped {
+        checks.push(Check {
+            name: "mount root",
+            status: Status::Warn,
+            detail: format!("skipped {}: {}", skip.root.display(), skip.reason),
+        });
+    }
+    match root {
+        Ok(root) => {
+            checks.push(Check {
+                name: "mount root",
+                status: Status::Ok,
+                detail: root.display().to_string(),
+            });
+            checks.push(check_exec(&root));
+            checks.push(check_selinux(&root));
+        }
+        Err(e) => checks.push(Check {
+            name: "mount root",
+            status: Status::Fail,
+            detail: e.to_string(),
+        }),
+    }
+    checks
+}
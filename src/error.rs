@@ -0,0 +1,61 @@
+use failure::Fail;
+
+#[derive(Debug, Fail)]
+pub enum XarError {
+    #[fail(
+        display = "Directory {} has mode {:o}, expected {:o}",
+        path, mode, expected
+    )]
+    DirectoryMode {
+        path: String,
+        mode: u32,
+        expected: u32,
+    },
+    #[fail(
+        display = "Directory {} is owned by {}:{}, expected {}:{}",
+        path, uid, gid, expected_uid, expected_gid
+    )]
+    DirectoryOwner {
+        path: String,
+        uid: u32,
+        gid: u32,
+        expected_uid: u32,
+        expected_gid: u32,
+    },
+    #[fail(display = "{} is unresponsive, no answer within {}s", path, secs)]
+    Unresponsive { path: String, secs: u64 },
+    #[fail(
+        display = "{} already has a {} mount from {}, pass --force-remount to replace it",
+        path, fs_type, source
+    )]
+    ForeignMount {
+        path: String,
+        fs_type: String,
+        source: String,
+    },
+    #[fail(display = "Header line {} column {}: {}", line, column, message)]
+    Header {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[fail(
+        display = "{} files open, the nofile limit is {}, raise it with ulimit -n or --raise-nofile",
+        open, limit
+    )]
+    TooManyOpenFiles { open: u64, limit: u64 },
+    #[fail(
+        display = "Archive format {} requires a newer xarfuse, this one supports up to {}, pass --ignore-version to try anyway",
+        version, supported
+    )]
+    NewerFormat { version: String, supported: String },
+    #[fail(
+        display = "Archive format {} is older than {}, the oldest this xarfuse supports",
+        version, supported
+    )]
+    OlderFormat { version: String, supported: String },
+    #[fail(display = "The mounter cannot read {}: {}", path, reason)]
+    Unreadable { path: String, reason: String },
+    #[fail(display = "Timed out {}", phase)]
+    Timeout { phase: String },
+}
@@ -0,0 +1,236 @@
+use crate::mount::directory::Directory;
+use crate::mount::resolve_in_mount;
+use crate::mount::workspace;
+use crate::sandbox;
+use crate::xar::{Format, Xar};
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Which environment variables the target sees. Clearing, or allowing any
+// pattern, starts from an empty environment and keeps only the allowed
+// variables, then `set` is applied on top.
+#[derive(Default)]
+pub struct EnvPolicy {
+    pub clear: bool,
+    pub allow: Vec<String>,
+    pub set: Vec<(OsString, OsString)>,
+}
+
+// `*` in a pattern matches any run of characters.
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+impl EnvPolicy {
+    // Parse a KEY=VAL assignment as given to --env.
+    pub fn parse_assignment(assignment: &OsStr) -> Result<(OsString, OsString), failure::Error> {
+        use std::os::unix::ffi::OsStrExt;
+        let bytes = assignment.as_bytes();
+        match bytes.iter().position(|byte| *byte == b'=') {
+            Some(index) if index > 0 => Ok((
+                OsStr::from_bytes(&bytes[..index]).to_os_string(),
+                OsStr::from_bytes(&bytes[index + 1..]).to_os_string(),
+            )),
+            _ => bail!(
+                "--env {} must be of the form KEY=VAL",
+                assignment.to_string_lossy()
+            ),
+        }
+    }
+
+    pub fn allows(&self, name: &OsStr) -> bool {
+        match name.to_str() {
+            Some(name) => self.allow.iter().any(|pattern| matches(pattern, name)),
+            None => false,
+        }
+    }
+
+    pub fn apply(&self, cmd: &mut Command) {
+        if self.clear || !self.allow.is_empty() {
+            cmd.env_clear();
+            for (name, value) in env::vars_os() {
+                if self.allows(&name) {
+                    cmd.env(name, value);
+                }
+            }
+        }
+        for (name, value) in &self.set {
+            cmd.env(name, value);
+        }
+    }
+}
+
+// Paths a confined target may use besides the mounts and the system
+// libraries and interpreters, see sandbox.rs.
+#[derive(Clone, Default)]
+pub struct Confinement {
+    // Read and execute only.
+    pub readable: Vec<PathBuf>,
+    // Anything.
+    pub writable: Vec<PathBuf>,
+}
+
+// How exec starts the target, apart from its arguments.
+#[derive(Default)]
+pub struct ExecOptions {
+    // Start in the header's RUN_CWD, or the mountpoint.
+    pub chdir: bool,
+    pub env: EnvPolicy,
+    // seccomp profile installed just before exec, see sandbox.rs.
+    pub sandbox: Option<OsString>,
+    // Give the target a scratch directory removed on unmount, named by
+    // XAR_WORKSPACE.
+    pub workspace: bool,
+    // Also mount the debug section and point debuggers at it.
+    pub with_debug: bool,
+    // Confine the target with Landlock to the mounts, read only, and these
+    // paths.
+    pub confine: Option<Confinement>,
+    // argv[0] the target sees, by default the archive's basename as invoked,
+    // like xarexec. Bootstrap scripts often dispatch on it.
+    pub argv0: Option<OsString>,
+}
+
+// debuginfod clients search these, space separated, which file:// URLs work
+// with for a section laid out as buildid/<id>/debuginfo.
+const DEBUGINFOD_URLS: &str = "DEBUGINFOD_URLS";
+// The debug section's mountpoint, for tools which just want the path.
+const XAR_DEBUG_MOUNT: &str = "XAR_DEBUG_MOUNT";
+// The canonical path of the archive the target runs from, also recorded in
+// the mount's origin file, see mount/origin.rs.
+const XAR_ORIGINAL_FILE: &str = "XAR_ORIGINAL_FILE";
+
+impl Xar {
+    // Mount this archive's debug section, returning its mountpoint.
+    fn mount_debug(&self) -> Result<PathBuf, failure::Error> {
+        let mut debug = Xar::from_file(self.archive.clone(), self.logger.clone())?;
+        debug.mount_options = self.mount_options.clone();
+        debug.select_section("debug")?;
+        let mount = Directory::from_xar(&debug, self.logger.clone())?;
+        debug.mount(&mount)?;
+        Ok(mount.path)
+    }
+
+    // Directory the target should start in when asked to chdir, RUN_CWD
+    // inside the mount if the header has one, otherwise the mount itself.
+    fn run_cwd(&self, mount: &Directory) -> Result<PathBuf, failure::Error> {
+        match &self.header.run_cwd {
+            Some(cwd) => resolve_in_mount(mount, "RUN_CWD", cwd),
+            None => Ok(mount.path.clone()),
+        }
+    }
+
+    // Mount if needed and build the command running XAREXEC_TARGET, along
+    // with what a confined target may read and write.
+    fn prepare(
+        &self,
+        mount: &Directory,
+        args: &[OsString],
+        options: &ExecOptions,
+    ) -> Result<(Command, Vec<PathBuf>, Vec<PathBuf>), failure::Error> {
+        self.mount(mount)?;
+        let target = self.target_path(mount)?;
+
+        let mut cmd = Command::new(&target);
+        let argv0 = match &options.argv0 {
+            Some(argv0) => Some(argv0.clone()),
+            None => self.original.file_name().map(OsString::from),
+        };
+        if let Some(argv0) = argv0 {
+            cmd.arg0(argv0);
+        }
+        cmd.args(args);
+        options.env.apply(&mut cmd);
+        cmd.env(XAR_ORIGINAL_FILE, &self.archive);
+        // What the AppImage runtime would have told AppRun.
+        if self.format == Format::AppImage {
+            cmd.env("APPIMAGE", &self.archive)
+                .env("APPDIR", &mount.path)
+                .env("ARGV0", &self.original);
+        }
+        let Confinement {
+            mut readable,
+            mut writable,
+        } = options.confine.clone().unwrap_or_default();
+        readable.push(mount.path.clone());
+        // A script's interpreter may live outside the system paths.
+        readable.extend(sandbox::interpreter(&target));
+        if options.workspace {
+            let workspace = workspace::allocate(&self.logger, &mount.path)?;
+            cmd.env(workspace::XAR_WORKSPACE, &workspace);
+            writable.push(workspace);
+        }
+        if options.with_debug {
+            let debug = self.mount_debug()?;
+            let mut urls = OsString::from("file://");
+            urls.push(&debug);
+            if let Some(existing) = env::var_os(DEBUGINFOD_URLS) {
+                urls.push(" ");
+                urls.push(existing);
+            }
+            cmd.env(DEBUGINFOD_URLS, urls).env(XAR_DEBUG_MOUNT, &debug);
+            readable.push(debug);
+        }
+        if options.chdir {
+            let cwd = self.run_cwd(mount)?;
+            // Shells trust $PWD over getcwd, so it has to follow the chdir.
+            if let Ok(previous) = env::current_dir() {
+                cmd.env("OLDPWD", previous);
+            }
+            cmd.current_dir(&cwd).env("PWD", &cwd);
+        }
+        debug!(
+            self.logger,
+            "Executing";
+            "target" => target.display().to_string()
+        );
+        Ok((cmd, readable, writable))
+    }
+
+    // Mount if needed and replace this process with XAREXEC_TARGET. Only
+    // returns if that fails. The lease is renewed synchronously, a background
+    // renewal wouldn't survive the exec.
+    pub fn exec(
+        &self,
+        mount: &Directory,
+        args: &[OsString],
+        options: &ExecOptions,
+    ) -> failure::Error {
+        let (mut cmd, readable, writable) = match self.prepare(mount, args, options) {
+            Ok(prepared) => prepared,
+            Err(e) => return e,
+        };
+        // Last, as these may refuse what preparing the exec needs.
+        if options.confine.is_some() {
+            if let Err(e) = sandbox::confine(&self.logger, &readable, &writable) {
+                return e;
+            }
+        }
+        if let Some(profile) = &options.sandbox {
+            if let Err(e) = sandbox::install(profile) {
+                return e;
+            }
+        }
+        cmd.exec().into()
+    }
+}
@@ -0,0 +1,119 @@
+use crate::mount::directory::Directory;
+use crate::xar::Xar;
+
+use libc::{c_char, c_int};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(err: failure::Error) {
+    let message = CString::new(err.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+unsafe fn xar_ref<'a>(xar: *const Xar) -> Result<&'a Xar, failure::Error> {
+    xar.as_ref().ok_or_else(|| format_err!("xar is NULL"))
+}
+
+unsafe fn open(path: *const c_char) -> Result<Xar, failure::Error> {
+    if path.is_null() {
+        bail!("path is NULL");
+    }
+    let path = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(path).to_bytes()));
+    Xar::from_file(path, slog::Logger::root(slog::Discard, o!()))
+}
+
+fn mountpoint(xar: &Xar) -> Result<CString, failure::Error> {
+    let mount = Directory::from_xar(xar, xar.logger.clone())?;
+    Ok(CString::new(mount.path.into_os_string().into_vec())?)
+}
+
+fn mount(xar: &Xar) -> Result<(), failure::Error> {
+    let mount = Directory::from_xar(xar, xar.logger.clone())?;
+    xar.mount(&mount)?;
+    Ok(())
+}
+
+/// Open an archive and parse its header, returning NULL on error.
+///
+/// # Safety
+/// `path` must be NULL or a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn xar_open(path: *const c_char) -> *mut Xar {
+    match open(path) {
+        Ok(xar) => Box::into_raw(Box::new(xar)),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Release an archive returned by `xar_open`.
+///
+/// # Safety
+/// `xar` must be NULL or a pointer returned by `xar_open` not yet closed.
+#[no_mangle]
+pub unsafe extern "C" fn xar_close(xar: *mut Xar) {
+    if !xar.is_null() {
+        drop(Box::from_raw(xar));
+    }
+}
+
+/// Return the mountpoint for an archive, to be released with
+/// `xar_string_free`, or NULL on error.
+///
+/// # Safety
+/// `xar` must be NULL or a live pointer returned by `xar_open`.
+#[no_mangle]
+pub unsafe extern "C" fn xar_mountpoint(xar: *const Xar) -> *mut c_char {
+    match xar_ref(xar).and_then(mountpoint) {
+        Ok(path) => path.into_raw(),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Mount an archive, returning 0 on success and -1 on error.
+///
+/// # Safety
+/// `xar` must be NULL or a live pointer returned by `xar_open`.
+#[no_mangle]
+pub unsafe extern "C" fn xar_mount(xar: *const Xar) -> c_int {
+    match xar_ref(xar).and_then(mount) {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Return the message for the last error on this thread, or NULL. The string
+/// is owned by the library and valid until the next failing call.
+#[no_mangle]
+pub extern "C" fn xar_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match *last.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Release a string returned by this library.
+///
+/// # Safety
+/// `s` must be NULL or a string returned by `xar_mountpoint`.
+#[no_mangle]
+pub unsafe extern "C" fn xar_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::error::XarError;
+
+// Older XAR producers vary in how they write the header: `KEY: value` as
+// well as `KEY="value"`, single, double or no quotes, trailing comments and
+// CRLF line endings. Rewrite every line as a TOML string assignment, keeping
+// line numbers, so serde sees one format and errors point at the right line.
+pub fn normalize(text: &str) -> Result<String, XarError> {
+    let mut lines = Vec::new();
+    for (index, line) in text.split('\n').enumerate() {
+        lines.push(normalize_line(index + 1, line.trim_end_matches('\r'))?);
+    }
+    Ok(lines.join("\n"))
+}
+
+fn error(line: usize, column: usize, message: &str) -> XarError {
+    XarError::Header {
+        line,
+        column,
+        message: message.to_string(),
+    }
+}
+
+fn normalize_line(number: usize, line: &str) -> Result<String, XarError> {
+    let trimmed = line.trim_start();
+    // Comments, the shebang and #xar_stop.
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(String::new());
+    }
+    let indent = line.len() - trimmed.len();
+
+    let key_len = trimmed
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or_else(|| trimmed.len());
+    if key_len == 0 {
+        return Err(error(number, indent + 1, "expected a key"));
+    }
+    let key = &trimmed[..key_len];
+
+    let rest = &trimmed[key_len..];
+    let after_key = rest.trim_start();
+    let separator = match after_key.chars().next() {
+        Some('=') | Some(':') => 1,
+        _ => {
+            let column = indent + key_len + (rest.len() - after_key.len()) + 1;
+            return Err(error(number, column, "expected `=` or `:` after the key"));
+        }
+    };
+    let value = after_key[separator..].trim_start();
+    let value_column = indent + trimmed.len() - value.len() + 1;
+    let value = parse_value(number, value_column, value)?;
+
+    Ok(format!("{} = \"{}\"", key, escape(&value)))
+}
+
+fn parse_value(number: usize, column: usize, value: &str) -> Result<String, XarError> {
+    let quote = match value.chars().next() {
+        Some(quote @ '"') | Some(quote @ '\'') => quote,
+        // Unquoted values end at a comment.
+        _ => {
+            let end = value.find(" #").unwrap_or_else(|| value.len());
+            return Ok(value[..end].trim_end().to_string());
+        }
+    };
+
+    let mut result = String::new();
+    let mut chars = value[1..].char_indices();
+    while let Some((offset, c)) = chars.next() {
+        if c == quote {
+            let trailing = value[offset + 2..].trim_start();
+            if !trailing.is_empty() && !trailing.starts_with('#') {
+                let at = column + value.len() - trailing.len();
+                return Err(error(number, at, "unexpected text after the value"));
+            }
+            return Ok(result);
+        }
+        if c == '\\' && quote == '"' {
+            match chars.next() {
+                Some((_, escaped)) => result.push(match escaped {
+                    'n' => '\n',
+                    't' => '\t',
+                    other => other,
+                }),
+                None => break,
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    Err(error(number, column, "unterminated quoted value"))
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Place a serde error, which only knows positions in the normalized text.
+// Lines match the original, columns only roughly.
+pub fn locate(error: toml::de::Error) -> XarError {
+    let (line, column) = error.line_col().unwrap_or((0, 0));
+    XarError::Header {
+        line: line + 1,
+        column: column + 1,
+        message: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(error: XarError) -> (usize, usize) {
+        match error {
+            XarError::Header { line, column, .. } => (line, column),
+            other => panic!("unexpected error {}", other),
+        }
+    }
+
+    #[test]
+    fn legacy_syntax_normalizes() {
+        let text =
+            "#!/bin/sh\r\nOFFSET: 4096 # bytes\r\nUUID='d2f0c1ab'\nVERSION = \"1.1\"\n#xar_stop\n";
+        assert_eq!(
+            normalize(text).unwrap(),
+            "\nOFFSET = \"4096\"\nUUID = \"d2f0c1ab\"\nVERSION = \"1.1\"\n\n"
+        );
+    }
+
+    #[test]
+    fn values_unquote_and_escape() {
+        assert_eq!(parse_value(1, 1, "plain # comment").unwrap(), "plain");
+        assert_eq!(parse_value(1, 1, "'a \\n b'").unwrap(), "a \\n b");
+        assert_eq!(parse_value(1, 1, "\"a\\tb\\\"c\"  # x").unwrap(), "a\tb\"c");
+        assert_eq!(
+            parse_value(1, 1, "\"#not a comment\"").unwrap(),
+            "#not a comment"
+        );
+        assert_eq!(escape("a\"b\\c\nd\u{1}"), "a\\\"b\\\\c\\nd\\u0001");
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(position(normalize("A=1\n  = 2").unwrap_err()), (2, 3));
+        assert_eq!(position(normalize("A=1\nKEY 2").unwrap_err()), (2, 5));
+        assert_eq!(position(normalize("KEY = \"open").unwrap_err()), (1, 7));
+        assert_eq!(position(normalize("KEY = 'a' b").unwrap_err()), (1, 11));
+    }
+}
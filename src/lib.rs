@@ -0,0 +1,29 @@
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate failure;
+
+pub mod appimage;
+pub mod compat;
+pub mod convert;
+pub mod diff;
+pub mod doctor;
+pub mod error;
+pub mod exec;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod header;
+pub mod mount;
+pub mod policy;
+#[cfg(feature = "python")]
+mod python;
+pub mod sandbox;
+pub mod selftest;
+pub mod selinux;
+pub mod squashfs;
+pub mod supervise;
+pub mod sys;
+pub mod trace;
+pub mod update;
+pub mod version;
+pub mod xar;
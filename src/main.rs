@@ -6,87 +6,1849 @@ extern crate slog_term;
 #[macro_use]
 extern crate failure;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{Fail, ResultExt};
+use serde::Serialize;
 use slog::Drain;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
-mod mount;
-mod xar;
+use xarfuse::compat;
+use xarfuse::convert;
+use xarfuse::diff;
+use xarfuse::doctor;
+use xarfuse::exec::{Confinement, EnvPolicy, ExecOptions};
+use xarfuse::mount::batch;
+use xarfuse::mount::cleanup;
+use xarfuse::mount::daemons;
+use xarfuse::mount::directory::{
+    default_mount_roots, find_mount_root_in, mount_path_for, user_directory, Directory,
+};
+use xarfuse::mount::lock;
+use xarfuse::mount::mountinfo;
+use xarfuse::mount::naming::{NamingStrategy, Template};
+use xarfuse::mount::origin;
+use xarfuse::mount::procs;
+use xarfuse::mount::repair;
+use xarfuse::mount::rlimit;
+use xarfuse::mount::stats;
+use xarfuse::mount::warm;
+use xarfuse::policy;
+use xarfuse::selftest;
+use xarfuse::squashfs::extract::{check_space, extract, extracted_size};
+use xarfuse::squashfs::inode::{Data, Inode, Kind};
+use xarfuse::squashfs::SquashFs;
+use xarfuse::supervise;
+use xarfuse::sys::System;
+use xarfuse::trace;
+use xarfuse::update;
+use xarfuse::xar::Xar;
 
-use crate::mount::directory::Directory;
-use crate::xar::Xar;
+const XAR_DEFAULT_COMMAND: &str = "XAR_DEFAULT_COMMAND";
 
-fn setup_logger(level: slog::Level) -> slog::Logger {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+// Records are written from a background thread unless `sync` is set. Loggers
+// cloned into other threads can outlive main, so the drain is flushed by
+// dropping the returned guard instead. A timeline sees records of any level.
+fn build_logger<D>(
+    drain: D,
+    level: slog::Level,
+    sync: bool,
+    timeline: Option<trace::Timeline>,
+) -> (slog::Logger, Option<slog_async::AsyncGuard>)
+where
+    D: Drain<Ok = (), Err = slog::Never> + Send + 'static,
+{
     let drain = slog::LevelFilter::new(drain, level).fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    if sync {
+        let drain = std::sync::Mutex::new(drain).fuse();
+        let drain = trace::Recorder::new(drain, timeline);
+        return (slog::Logger::root(drain, o!()), None);
+    }
+    let (drain, guard) = slog_async::Async::new(drain).build_with_guard();
+    let drain = trace::Recorder::new(drain.fuse(), timeline);
+    (slog::Logger::root(drain, o!()), Some(guard))
+}
 
-    slog::Logger::root(drain, o!())
+// Targets are stderr, file:PATH, and with their features syslog and
+// journald, which keep each record's level as its priority.
+fn setup_logger(
+    level: slog::Level,
+    target: &str,
+    sync: bool,
+    timeline: Option<trace::Timeline>,
+) -> Result<(slog::Logger, Option<slog_async::AsyncGuard>), failure::Error> {
+    if let Some(path) = target.strip_prefix("file:") {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|_| format!("while opening log file {}", path))?;
+        let decorator = slog_term::PlainDecorator::new(file);
+        let drain = slog_term::FullFormat::new(decorator).build().fuse();
+        return Ok(build_logger(drain, level, sync, timeline));
+    }
+    match target {
+        "stderr" => {
+            // Never stdout, which carries only requested output.
+            let decorator = slog_term::TermDecorator::new().stderr().build();
+            let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+            Ok(build_logger(drain, level, sync, timeline))
+        }
+        #[cfg(feature = "syslog")]
+        "syslog" => {
+            let drain = slog_syslog::unix_3164(slog_syslog::Facility::LOG_USER)?.fuse();
+            Ok(build_logger(drain, level, sync, timeline))
+        }
+        #[cfg(feature = "journald")]
+        "journald" => Ok(build_logger(
+            slog_journald::JournaldDrain.ignore_res(),
+            level,
+            sync,
+            timeline,
+        )),
+        #[cfg(not(feature = "syslog"))]
+        "syslog" => bail!("this xarfuse was built without the syslog feature"),
+        #[cfg(not(feature = "journald"))]
+        "journald" => bail!("this xarfuse was built without the journald feature"),
+        _ => bail!(
+            "log target {} must be stderr, syslog, journald or file:PATH",
+            target
+        ),
+    }
+}
+
+fn print_inode(inode: &Inode, name: &OsStr) {
+    let mut line = format!(
+        "{} {:>5} {:>5} {:>10} {}",
+        inode.mode_string(),
+        inode.uid,
+        inode.gid,
+        inode.size(),
+        name.to_string_lossy()
+    );
+    if let Data::Symlink { target } = &inode.data {
+        line.push_str(&format!(" -> {}", target.to_string_lossy()));
+    }
+    println!("{}", line);
 }
 
-fn run() -> Result<(), failure::Error> {
+fn app<'a, 'b>() -> App<'a, 'b> {
     let archive_arg = Arg::with_name("archive")
         .index(1)
         .required(true)
         .help("/path/to/file.xar, the archive to be mounted");
+    let mount_template_arg = Arg::with_name("mount_template")
+        .long("mount-template")
+        .takes_value(true)
+        .value_name("TEMPLATE")
+        .conflicts_with("naming")
+        .help("name mounts [{root}/]DIR/NAME from {uuid}, {uid}, {seed} and {ns}");
+    let naming_arg = Arg::with_name("naming")
+        .long("naming")
+        .takes_value(true)
+        .value_name("STRATEGY")
+        .help("who shares mounts: uid-ns, uid-only, global or a DIR/NAME template");
 
-    let matches = App::new("XAR Fuse")
+    App::new("XAR Fuse")
         .arg(
             Arg::with_name("verbose")
                 .long("verbose")
                 .short("v")
                 .help("display detailed output"),
         )
-        .subcommand(SubCommand::with_name("header").arg(&archive_arg))
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .conflicts_with("verbose")
+                .help("only log errors"),
+        )
+        .subcommand(
+            SubCommand::with_name("cat").arg(&archive_arg).arg(
+                Arg::with_name("path")
+                    .index(2)
+                    .required(true)
+                    .help("path of the file inside the archive"),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("compat-check")
+                .about("compare the mountpoint with the one xarexec_fuse would use")
+                .arg(&archive_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("convert between XARs and single layer OCI image layouts")
+                .arg(
+                    Arg::with_name("to_oci")
+                        .long("to-oci")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .required_unless("from_oci")
+                        .conflicts_with("from_oci")
+                        .help("write the archive as an OCI image layout in DIR"),
+                )
+                .arg(
+                    Arg::with_name("from_oci")
+                        .long("from-oci")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("write the OCI image layout in DIR as the archive"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .requires("from_oci")
+                        .help("XAREXEC_TARGET, by default the image's entrypoint"),
+                )
+                .arg(
+                    Arg::with_name("archive")
+                        .index(1)
+                        .required(true)
+                        .help("/path/to/file.xar, read with --to-oci, written with --from-oci"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .arg(
+                    Arg::with_name("a")
+                        .index(1)
+                        .required(true)
+                        .help("/path/to/old.xar"),
+                )
+                .arg(
+                    Arg::with_name("b")
+                        .index(2)
+                        .required(true)
+                        .help("/path/to/new.xar"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor").arg(
+                Arg::with_name("archive")
+                    .index(1)
+                    .help("/path/to/file.xar, to check its MOUNT_ROOT"),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("exec")
+                .alias("x")
+                .about("mount the archive and run its XAREXEC_TARGET")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(&archive_arg)
+                .arg(
+                    Arg::with_name("chdir")
+                        .long("chdir")
+                        .help("start in the header's RUN_CWD, or the mountpoint"),
+                )
+                .arg(
+                    Arg::with_name("env_clear")
+                        .long("env-clear")
+                        .help("start the target with an empty environment"),
+                )
+                .arg(
+                    Arg::with_name("env_allow")
+                        .long("env-allow")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "keep variables matching PATTERN, * matches anything, may be repeated",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("env")
+                        .long("env")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("set KEY=VAL in the target's environment, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("sandbox")
+                        .long("sandbox")
+                        .takes_value(true)
+                        .value_name("PROFILE")
+                        .help("apply a seccomp profile, no-network, read-only-fs or a JSON file"),
+                )
+                .arg(
+                    Arg::with_name("ignore_version")
+                        .long("ignore-version")
+                        .help("mount even if the archive's VERSION is newer than supported"),
+                )
+                .arg(
+                    Arg::with_name("workspace")
+                        .long("workspace")
+                        .help("give the target a scratch directory, named by XAR_WORKSPACE"),
+                )
+                .arg(
+                    Arg::with_name("with_debug")
+                        .long("with-debug")
+                        .help("also mount the debug section, named in DEBUGINFOD_URLS"),
+                )
+                .arg(
+                    Arg::with_name("confine")
+                        .long("confine")
+                        .help("use Landlock to keep the target inside the mountpoint"),
+                )
+                .arg(
+                    Arg::with_name("confine_read")
+                        .long("confine-read")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .requires("confine")
+                        .help("also let a confined target read PATH, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("confine_write")
+                        .long("confine-write")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .requires("confine")
+                        .help("also let a confined target modify PATH, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("argv0")
+                        .long("argv0")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("argv[0] the target sees, by default the archive's basename"),
+                )
+                .arg(
+                    Arg::with_name("supervise")
+                        .long("supervise")
+                        .conflicts_with_all(&["sandbox", "confine"])
+                        .help(
+                            "stay resident, forward signals to the target and exit with its status",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("tty")
+                        .long("tty")
+                        .conflicts_with_all(&["sandbox", "confine"])
+                        .help("supervise, giving the target the terminal's foreground"),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .index(2)
+                        .multiple(true)
+                        .allow_hyphen_values(true)
+                        .help("arguments passed to the target"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .arg(&archive_arg)
+                .arg(
+                    Arg::with_name("dest")
+                        .index(2)
+                        .required(true)
+                        .help("directory to extract into"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .index(3)
+                        .help("path inside the archive, defaults to the root"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("extract without checking the destination has room"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("unmount and remove mounts whose lease has expired, unless pinned")
+                .arg(
+                    Arg::with_name("archive")
+                        .index(1)
+                        .help("/path/to/file.xar, to collect under its MOUNT_ROOT"),
+                )
+                .arg(
+                    Arg::with_name("all_users")
+                        .long("all-users")
+                        .help("as root, collect in every user's directory under the mount root"),
+                )
+                .arg(
+                    Arg::with_name("as_owner")
+                        .long("as-owner")
+                        .requires("all_users")
+                        .help("unmount as each directory's owner rather than as root"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("header").arg(
+                Arg::with_name("archive")
+                    .index(1)
+                    .required(true)
+                    .help("/path/to/file.xar, or a running mount of one"),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("list running squashfuse daemons, across users and namespaces")
+                .arg(
+                    Arg::with_name("by_uuid")
+                        .long("by-uuid")
+                        .help("group daemons by archive UUID and mark redundant ones"),
+                )
+                .arg(
+                    Arg::with_name("consolidate")
+                        .long("consolidate")
+                        .requires("by_uuid")
+                        .help("unmount daemons made redundant by a --system mount"),
+                )
+                .arg(
+                    Arg::with_name("activity").long("activity").help(
+                        "add waiting FUSE requests, open fds and mount options for each mount",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("build_id")
+                        .long("build-id")
+                        .takes_value(true)
+                        .help("only daemons serving an archive with this BUILD_ID"),
+                )
+                .arg(&naming_arg)
+                .arg(&mount_template_arg),
+        )
         .subcommand(
-            SubCommand::with_name("mount").arg(&archive_arg).arg(
-                Arg::with_name("print_only")
-                    .short("n")
-                    .help("print the mountpoint but don't mount"),
+            SubCommand::with_name("ls").arg(&archive_arg).arg(
+                Arg::with_name("path")
+                    .index(2)
+                    .help("path inside the archive, defaults to the root"),
             ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("mount")
+                .alias("m")
+                .arg(
+                    Arg::with_name("archive")
+                        .index(1)
+                        .required(true)
+                        .multiple(true)
+                        .help("/path/to/file.xar, the archives to be mounted"),
+                )
+                .arg(
+                    Arg::with_name("raw_squashfs")
+                        .long("raw-squashfs")
+                        .help("the archives are plain squashfs or EROFS images without a header"),
+                )
+                .arg(
+                    Arg::with_name("uuid")
+                        .long("uuid")
+                        .takes_value(true)
+                        .requires("raw_squashfs")
+                        .help("UUID to mount the image under, derived from the file by default"),
+                )
+                .arg(
+                    Arg::with_name("auto_repair")
+                        .long("auto-repair")
+                        .conflicts_with("at")
+                        .help("repair leftovers in the mount directory before mounting"),
+                )
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .takes_value(true)
+                        .help("mount at this path instead of the default location"),
+                )
+                .arg(
+                    Arg::with_name("cgroup")
+                        .long("cgroup")
+                        .takes_value(true)
+                        .help("cgroup v2 path to run squashfuse in, relative to /sys/fs/cgroup"),
+                )
+                .arg(
+                    Arg::with_name("drop_groups")
+                        .long("drop-groups")
+                        .help("drop supplementary groups before starting squashfuse"),
+                )
+                .arg(
+                    Arg::with_name("force_remount")
+                        .long("force-remount")
+                        .help("unmount a bind mount or other filesystem found on the mountpoint"),
+                )
+                .arg(
+                    Arg::with_name("versioned").long("versioned").help(
+                        "include a content hash in the mountpoint and link it as UUID-current",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("system")
+                        .long("system")
+                        .help("as root, mount once under <mount_root>/system for all users"),
+                )
+                .arg(&naming_arg)
+                .arg(&mount_template_arg)
+                .arg(
+                    Arg::with_name("ignore_version")
+                        .long("ignore-version")
+                        .help("mount even if the archive's VERSION is newer than supported"),
+                )
+                .arg(
+                    Arg::with_name("require_ro")
+                        .long("require-ro")
+                        .help("fail if the mount turns out to be writable"),
+                )
+                .arg(
+                    Arg::with_name("section")
+                        .long("section")
+                        .takes_value(true)
+                        .help("mount the payload at OFFSET_<SECTION>, e.g. debug"),
+                )
+                .arg(
+                    Arg::with_name("require_ns_id")
+                        .long("require-ns-id")
+                        .help("fail if the mount namespace can't be read from /proc"),
+                )
+                .arg(
+                    Arg::with_name("no_failure_cache")
+                        .long("no-failure-cache")
+                        .help("mount even if mounting failed recently"),
+                )
+                .arg(
+                    Arg::with_name("raise_nofile")
+                        .long("raise-nofile")
+                        .help("raise the open files limit to fit every archive"),
+                )
+                .arg(
+                    Arg::with_name("best_effort")
+                        .long("best-effort")
+                        .help("keep mounting the remaining archives when one fails"),
+                )
+                .arg(
+                    Arg::with_name("cleanup_script")
+                        .long("cleanup-script")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with_all(&["print_only", "dry_run"])
+                        .help(
+                            "write a shell script undoing what this mount created, for supervisors",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("print_only")
+                        .short("n")
+                        .help("print the mountpoint but don't mount"),
+                )
+                .arg(
+                    Arg::with_name("allow_hooks")
+                        .long("allow-hooks")
+                        .help("run the archive's POST_MOUNT_HOOK after mounting"),
+                )
+                .arg(
+                    Arg::with_name("context")
+                        .long("context")
+                        .takes_value(true)
+                        .help("SELinux context to apply to the mounted files"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .help("seconds idle before squashfuse unmounts, overriding the header"),
+                )
+                .arg(
+                    Arg::with_name("fuse_nice")
+                        .long("fuse-nice")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .value_name("N")
+                        .help("niceness squashfuse runs with"),
+                )
+                .arg(
+                    Arg::with_name("fuse_ionice")
+                        .long("fuse-ionice")
+                        .takes_value(true)
+                        .value_name("CLASS:LEVEL")
+                        .help("I/O priority squashfuse runs with, e.g. best-effort:7 or idle"),
+                )
+                .arg(
+                    Arg::with_name("warm")
+                        .long("warm")
+                        .takes_value(true)
+                        .value_name("PATHLIST")
+                        .help(
+                            "read these colon separated paths once mounted, overriding WARM_PATHS",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .conflicts_with("dry_run")
+                        .help("print a JSON object per archive with its mountpoint"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("print the operations mount would perform but don't execute them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("owner")
+                .about("show which mount and archive a path belongs to")
+                .arg(
+                    Arg::with_name("path")
+                        .index(1)
+                        .required(true)
+                        .help("a path inside a mounted archive, e.g. from /proc/PID/exe"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pin")
+                .about("exempt the archive's mount from garbage collection")
+                .arg(&archive_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("print where the archive would be mounted on another host")
+                .arg(&archive_arg)
+                .arg(
+                    Arg::with_name("uid")
+                        .long("uid")
+                        .takes_value(true)
+                        .required(true)
+                        .help("user the archive is mounted as"),
+                )
+                .arg(
+                    Arg::with_name("ns_id")
+                        .long("ns-id")
+                        .takes_value(true)
+                        .help("the host's mount namespace id, the inode of /proc/self/ns/mnt"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .help("the host's XAR_MOUNT_SEED"),
+                )
+                .arg(
+                    Arg::with_name("root")
+                        .long("root")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("mount root to assume, by default the first default root"),
+                )
+                .arg(&naming_arg)
+                .arg(&mount_template_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("procs")
+                .about("list processes using an archive's mount, by cwd, exe, fds or maps")
+                .arg(
+                    Arg::with_name("archive")
+                        .index(1)
+                        .required(true)
+                        .help("/path/to/file.xar, or the mountpoint itself"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("remove orphaned lockfiles, stale directories and aborted mounts")
+                .arg(
+                    Arg::with_name("archive")
+                        .index(1)
+                        .help("/path/to/file.xar, to repair under its MOUNT_ROOT"),
+                )
+                .arg(
+                    Arg::with_name("all_users")
+                        .long("all-users")
+                        .help("as root, repair every user's directory under the mount root"),
+                )
+                .arg(
+                    Arg::with_name("as_owner")
+                        .long("as-owner")
+                        .requires("all_users")
+                        .help("unmount as each directory's owner rather than as root"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("run-many")
+                .about("run several commands from one mount of the archive")
+                .arg(&archive_arg)
+                .arg(
+                    Arg::with_name("cmd")
+                        .long("cmd")
+                        .takes_value(true)
+                        .value_name("PATH [ARG]... ;")
+                        .multiple(true)
+                        .allow_hyphen_values(true)
+                        .value_terminator(";")
+                        .required(true)
+                        .help(
+                            "path inside the archive and its arguments, ended by ; \
+                             unless last, may be repeated",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("unmount")
+                        .long("unmount")
+                        .help("unmount the archive once every command has exited"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("self-update")
+                .about("replace this binary with the latest signed release")
+                .arg(
+                    Arg::with_name("channel")
+                        .long("channel")
+                        .takes_value(true)
+                        .default_value("stable")
+                        .help("release channel to follow"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("reinstall even if already on the channel's version"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("selftest")
+                .about("mount a built in archive, read from it, run it and unmount it"),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("show an archive's mount, its lease, activity, flags and provenance")
+                .arg(&archive_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("summarize mounts, workspace usage and stale entries under the mount roots")
+                .arg(
+                    Arg::with_name("root")
+                        .long("root")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("mount root to summarize, by default every default root"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("target")
+                .about("mount the archive and print the path of its XAREXEC_TARGET")
+                .arg(&archive_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("unmount")
+                .about("unmount the archive")
+                .arg(&archive_arg)
+                .arg(
+                    Arg::with_name("abort")
+                        .long("abort")
+                        .help("abort the FUSE connection of a hung mount, then detach it"),
+                )
+                .arg(
+                    Arg::with_name("kill")
+                        .long("kill")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .value_name("SIGNAL")
+                        .help("signal processes using the mount first, SIGTERM by default"),
+                )
+                .arg(
+                    Arg::with_name("wait")
+                        .long("wait")
+                        .takes_value(true)
+                        .value_name("SECS")
+                        .help("wait for processes using the mount to exit before unmounting"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unpin")
+                .about("allow the archive's mount to be garbage collected")
+                .arg(&archive_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("wait")
+                .about("wait until the archive is mounted and its target is readable")
+                .arg(&archive_arg)
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .default_value("30")
+                        .help("seconds to wait before failing"),
+                )
+                .arg(
+                    Arg::with_name("system")
+                        .long("system")
+                        .help("wait for the mount shared by all users"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("warm")
+                .setting(AppSettings::Hidden)
+                .about("read files in a mount to populate the page cache, run by mount --warm")
+                .arg(
+                    Arg::with_name("mount")
+                        .index(1)
+                        .required(true)
+                        .help("the mountpoint"),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .index(2)
+                        .multiple(true)
+                        .help("paths relative to the mountpoint"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("mount the archive and renew its lease whenever it's accessed")
+                .arg(&archive_arg),
+        )
+        .arg(
+            Arg::with_name("sync_logging")
+                .long("sync-logging")
+                .help("write each log record before continuing, to debug ordering"),
+        )
+        .arg(
+            Arg::with_name("log_target")
+                .long("log-target")
+                .takes_value(true)
+                .default_value("stderr")
+                .help("where logs go: stderr, syslog, journald or file:PATH"),
+        )
+        .arg(
+            Arg::with_name("error_format")
+                .long("error-format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("how errors are printed, json is a single object on stderr"),
+        )
+        .arg(
+            Arg::with_name("trace_bundle")
+                .long("trace-bundle")
+                .takes_value(true)
+                .value_name("PATH")
+                .help(
+                    "write a gzipped support bundle of the run for bug reports, \
+                     not written when exec succeeds",
+                ),
+        )
+}
 
+fn run(matches: &ArgMatches) -> Result<(), failure::Error> {
+    // Before any thread starts, see hold_signals.
+    let held = match matches.subcommand() {
+        ("exec", Some(sub_m)) if sub_m.is_present("supervise") || sub_m.is_present("tty") => {
+            Some(supervise::hold_signals()?)
+        }
+        _ => None,
+    };
     let level = if matches.is_present("verbose") {
         slog::Level::Debug
+    } else if matches.is_present("quiet") {
+        slog::Level::Error
     } else {
         slog::Level::Info
     };
 
-    let root_log = setup_logger(level);
+    // exec replaces this process, which would lose anything still buffered.
+    let sync = matches.is_present("sync_logging") || matches.subcommand_name() == Some("exec");
+    // Held until run returns, on success or error, so buffered records are
+    // written before main exits.
+    let target = matches.value_of("log_target").unwrap();
+    let bundle = matches.value_of_os("trace_bundle").map(PathBuf::from);
+    let timeline = match bundle {
+        Some(_) => Some(trace::Timeline::new()?),
+        None => None,
+    };
+    let (root_log, _guard) = setup_logger(level, target, sync, timeline.clone())?;
+    let result = run_subcommand(matches, root_log.clone(), held);
+    if let (Some(bundle), Some(timeline)) = (bundle, timeline) {
+        let target_args = matches
+            .subcommand_matches("exec")
+            .and_then(|sub_m| sub_m.values_of_os("args"))
+            .map_or(0, |args| args.count());
+        if let Err(e) = trace::write(&bundle, &timeline, target_args, result.as_ref().err()) {
+            error!(
+                root_log,
+                "Unable to write trace bundle";
+                "path" => bundle.display().to_string(),
+                "error" => e.to_string()
+            );
+        }
+    }
+    result
+}
+
+// The supervised target's exit status, returned as an error so run unwinds
+// and flushes the logger before main exits with it.
+#[derive(Debug, Fail)]
+#[fail(display = "target exited with status {}", _0)]
+struct Exited(i32);
+
+fn run_subcommand(
+    matches: &ArgMatches,
+    root_log: slog::Logger,
+    held: Option<supervise::Held>,
+) -> Result<(), failure::Error> {
     match matches.subcommand() {
-        ("header", Some(sub_m)) => {
-            let archive = sub_m.value_of("archive").unwrap();
+        ("cat", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let fs = SquashFs::from_xar(&xar)?;
+            let inode = fs.lookup(&PathBuf::from(sub_m.value_of("path").unwrap()))?;
+            let stdout = std::io::stdout();
+            fs.read_file(&inode, &mut stdout.lock())
+        }
+        ("compat-check", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let (ours, reference, divergences) = compat::check(&xar)?;
+            println!("ours: {}", ours.display());
+            println!("reference: {}", reference.display());
+            for divergence in &divergences {
+                println!(
+                    "diverges {}: ours={} reference={}",
+                    divergence.component, divergence.ours, divergence.reference
+                );
+            }
+            if !divergences.is_empty() {
+                bail!("mountpoint differs from xarexec_fuse");
+            }
+            Ok(())
+        }
+        ("convert", Some(sub_m)) => {
+            let archive = Path::new(sub_m.value_of_os("archive").unwrap());
+            match sub_m.value_of_os("to_oci") {
+                Some(layout) => {
+                    let xar = Xar::from_file(archive.to_path_buf(), root_log.clone())?;
+                    convert::to_oci(&xar, Path::new(layout), &root_log)
+                }
+                None => {
+                    let layout = Path::new(sub_m.value_of_os("from_oci").unwrap());
+                    convert::from_oci(layout, archive, sub_m.value_of("target"), &root_log)
+                }
+            }
+        }
+        ("diff", Some(sub_m)) => {
+            let a = Xar::from_file(
+                PathBuf::from(sub_m.value_of_os("a").unwrap()),
+                root_log.clone(),
+            )?;
+            let b = Xar::from_file(
+                PathBuf::from(sub_m.value_of_os("b").unwrap()),
+                root_log.clone(),
+            )?;
+            for change in diff::diff(&a, &b)? {
+                println!("{}", change);
+            }
+            Ok(())
+        }
+        ("doctor", Some(sub_m)) => {
+            let mount_root = match sub_m.value_of_os("archive") {
+                Some(archive) => Xar::from_file(PathBuf::from(archive), root_log.clone())?
+                    .header
+                    .mount_root
+                    .clone(),
+                None => None,
+            };
+            for check in doctor::run(&root_log, &mount_root) {
+                println!("{}", check);
+            }
+            Ok(())
+        }
+        ("exec", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let mut xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            xar.mount_options.ignore_version = sub_m.is_present("ignore_version");
+            let mount = Directory::from_xar(&xar, root_log.clone())?;
+            let args: Vec<OsString> = sub_m
+                .values_of_os("args")
+                .map(|args| args.map(OsString::from).collect())
+                .unwrap_or_default();
+            let mut options = ExecOptions::default();
+            options.chdir = sub_m.is_present("chdir");
+            options.env.clear = sub_m.is_present("env_clear");
+            if let Some(patterns) = sub_m.values_of("env_allow") {
+                options.env.allow = patterns.map(String::from).collect();
+            }
+            if let Some(assignments) = sub_m.values_of_os("env") {
+                for assignment in assignments {
+                    options
+                        .env
+                        .set
+                        .push(EnvPolicy::parse_assignment(assignment)?);
+                }
+            }
+            options.sandbox = sub_m.value_of_os("sandbox").map(OsString::from);
+            options.with_debug = sub_m.is_present("with_debug");
+            options.argv0 = sub_m.value_of_os("argv0").map(OsString::from);
+            options.workspace = sub_m.is_present("workspace");
+            if sub_m.is_present("confine") {
+                let paths = |name| -> Vec<PathBuf> {
+                    sub_m
+                        .values_of_os(name)
+                        .map(|paths| paths.map(PathBuf::from).collect())
+                        .unwrap_or_default()
+                };
+                options.confine = Some(Confinement {
+                    readable: paths("confine_read"),
+                    writable: paths("confine_write"),
+                });
+            }
+            if let Some(held) = held {
+                let tty = sub_m.is_present("tty");
+                return match xar.supervise(held, &mount, &args, &options, tty)? {
+                    0 => Ok(()),
+                    code => Err(Exited(code).into()),
+                };
+            }
+            Err(xar.exec(&mount, &args, &options))
+        }
+        ("extract", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
             let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let fs = SquashFs::from_xar(&xar)?;
+            let inode = fs.lookup(&PathBuf::from(sub_m.value_of("path").unwrap_or("/")))?;
+            let dest = PathBuf::from(sub_m.value_of_os("dest").unwrap());
+            if !sub_m.is_present("force") {
+                check_space(&dest, extracted_size(&fs, &inode)?)?;
+            }
+            extract(&fs, &inode, &dest, &root_log)
+        }
+        ("gc", Some(sub_m)) => {
+            let mount_root = match sub_m.value_of_os("archive") {
+                Some(archive) => Xar::from_file(PathBuf::from(archive), root_log.clone())?
+                    .header
+                    .mount_root
+                    .clone(),
+                None => None,
+            };
+            let collected = if sub_m.is_present("all_users") {
+                let root = find_mount_root_in(&root_log, &mount_root, &System, &System)?;
+                repair::collect_all_users(&root_log, &root, sub_m.is_present("as_owner"))?
+            } else {
+                repair::collect(&root_log, &user_directory(&root_log, &mount_root)?)?
+            };
+            for collected in collected {
+                println!("{}", collected);
+            }
+            Ok(())
+        }
+        ("header", Some(sub_m)) => {
+            let archive = Path::new(sub_m.value_of_os("archive").unwrap());
+            let archive = if archive.is_dir() {
+                archive_of_mount(archive)?
+            } else {
+                archive.to_path_buf()
+            };
+            let xar = Xar::from_file(archive, root_log.clone())?;
             info!(&root_log, ""; "header" => format!("{:?}", xar.header));
             Ok(())
         }
+        ("list", Some(sub_m)) => {
+            let mut running = daemons::list()?;
+            let naming = match cli_naming(sub_m)? {
+                Some(naming) => Some(naming),
+                None => policy::load()?.mount_naming,
+            };
+            if let Some(naming) = naming {
+                daemons::decode_uuids(&mut running, &naming);
+            }
+            let build_ids: HashMap<u32, String> = running
+                .iter()
+                .filter_map(|daemon| Some((daemon.pid, build_id_of(daemon, &root_log)?)))
+                .collect();
+            if let Some(wanted) = sub_m.value_of("build_id") {
+                running.retain(|daemon| {
+                    build_ids.get(&daemon.pid).map(String::as_str) == Some(wanted)
+                });
+            }
+            let activity = if sub_m.is_present("activity") {
+                Some(daemons::activity(&running))
+            } else {
+                None
+            };
+            if !sub_m.is_present("by_uuid") {
+                for daemon in &running {
+                    println!("{}", describe_daemon(daemon, &activity, &build_ids));
+                }
+                return Ok(());
+            }
+            for (uuid, group) in daemons::by_uuid(&running) {
+                println!("{}\t{}", uuid, group.len());
+                let redundant = daemons::redundant(&group);
+                for daemon in &group {
+                    let marker = if redundant.iter().any(|other| other.pid == daemon.pid) {
+                        "\tredundant"
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "\t{}{}",
+                        describe_daemon(daemon, &activity, &build_ids),
+                        marker
+                    );
+                }
+                if sub_m.is_present("consolidate") {
+                    daemons::consolidate(&root_log, &group)?;
+                }
+            }
+            Ok(())
+        }
+        ("ls", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let fs = SquashFs::from_xar(&xar)?;
+            let path = PathBuf::from(sub_m.value_of("path").unwrap_or("/"));
+            let inode = fs.lookup(&path)?;
+            if inode.kind != Kind::Directory {
+                print_inode(&inode, path.as_os_str());
+                return Ok(());
+            }
+            for entry in fs.read_dir(&inode)? {
+                print_inode(&fs.inode(entry.inode)?, &entry.name);
+            }
+            Ok(())
+        }
         ("mount", Some(sub_m)) => {
-            let archive = sub_m.value_of("archive").unwrap();
+            let timeout = sub_m.value_of("timeout").map(str::parse).transpose()?;
+            let nice = sub_m.value_of("fuse_nice").map(str::parse).transpose()?;
+            let ionice = sub_m.value_of("fuse_ionice").map(str::parse).transpose()?;
+            let archives: Vec<PathBuf> = sub_m
+                .values_of_os("archive")
+                .unwrap()
+                .map(PathBuf::from)
+                .collect();
+            if sub_m.is_present("raise_nofile") {
+                let wanted = rlimit::open_fds()? + rlimit::FDS_PER_MOUNT * archives.len() as u64;
+                rlimit::raise_nofile(&root_log, wanted)?;
+            }
+            if sub_m.is_present("at") && archives.len() > 1 {
+                bail!("--at can only be used with a single archive");
+            }
+            if sub_m.is_present("uuid") && archives.len() > 1 {
+                bail!("--uuid can only be used with a single image");
+            }
+            let created = Mutex::new(Vec::new());
+            let result = batch::mount_all(
+                &root_log,
+                &archives,
+                sub_m.is_present("best_effort"),
+                |archive| {
+                    let mut xar = if sub_m.is_present("raw_squashfs") {
+                        let uuid = sub_m.value_of("uuid");
+                        Xar::from_squashfs(archive.to_path_buf(), uuid, root_log.clone())?
+                    } else {
+                        Xar::from_file(archive.to_path_buf(), root_log.clone())?
+                    };
+                    xar.mount_options.context = sub_m.value_of("context").map(String::from);
+                    xar.mount_options.timeout = timeout;
+                    xar.mount_options.nice = nice;
+                    xar.mount_options.ionice = ionice;
+                    xar.mount_options.skip_failure_cache = sub_m.is_present("no_failure_cache");
+                    xar.mount_options.require_ns_id = sub_m.is_present("require_ns_id");
+                    xar.mount_options.cgroup = sub_m.value_of_os("cgroup").map(PathBuf::from);
+                    xar.mount_options.drop_groups = sub_m.is_present("drop_groups");
+                    xar.mount_options.force_remount = sub_m.is_present("force_remount");
+                    xar.mount_options.versioned = sub_m.is_present("versioned");
+                    xar.mount_options.system = sub_m.is_present("system");
+                    xar.mount_options.naming = cli_naming(sub_m)?;
+                    xar.mount_options.ignore_version = sub_m.is_present("ignore_version");
+                    xar.mount_options.require_ro = sub_m.is_present("require_ro");
+                    if let Some(section) = sub_m.value_of("section") {
+                        xar.select_section(section)?;
+                    }
+                    let mount = match sub_m.value_of_os("at") {
+                        Some(at) => Directory::at(Path::new(at), root_log.clone())?,
+                        None => Directory::from_xar(&xar, root_log.clone())?,
+                    };
+                    if sub_m.is_present("auto_repair") {
+                        repair::repair(&root_log, mount.path.parent().unwrap())?;
+                    }
+                    // Printed in archive order once every mount is done.
+                    let mut output = Vec::new();
+                    let json = sub_m.is_present("json");
+                    if sub_m.is_present("print_only") && json {
+                        output = mount_json(archive, &mount.path, None)?;
+                    } else if sub_m.is_present("print_only") {
+                        // Raw bytes, so non-UTF-8 paths survive for scripts.
+                        output.extend_from_slice(mount.path.as_os_str().as_bytes());
+                        output.push(b'\n');
+                    } else if sub_m.is_present("dry_run") {
+                        for step in xar.plan_mount(&mount)? {
+                            output.extend_from_slice(format!("{}\n", step).as_bytes());
+                        }
+                    } else {
+                        let spawned = xar.mount(&mount)?;
+                        let mounted = cleanup::Created::of(&mount, spawned, xar.mounter());
+                        created.lock().unwrap().push(mounted);
+                        if spawned && sub_m.is_present("allow_hooks") {
+                            xar.run_post_mount_hook(&mount)?;
+                        } else if spawned && xar.header.post_mount_hook.is_some() {
+                            warn!(
+                                &root_log,
+                                "Skipping POST_MOUNT_HOOK, pass --allow-hooks to run it"
+                            );
+                        }
+                        let warm = xar.warm_paths(sub_m.value_of("warm"));
+                        xar.warm_in_background(&mount, &warm)?;
+                        if json {
+                            output = mount_json(archive, &mount.path, Some(spawned))?;
+                        }
+                    }
+                    Ok(output)
+                },
+                |_, output| {
+                    use std::io::Write;
+                    std::io::stdout().write_all(&output)?;
+                    Ok(())
+                },
+            );
+            // Also after failures, for whatever the other archives created.
+            if let Some(path) = sub_m.value_of_os("cleanup_script") {
+                cleanup::write(Path::new(path), &created.into_inner().unwrap())?;
+            }
+            let summary = result?;
+            if summary.failed > 0 {
+                bail!("{}", summary);
+            }
+            Ok(())
+        }
+        ("owner", Some(sub_m)) => {
+            let path = Path::new(sub_m.value_of_os("path").unwrap());
+            let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+            let running = daemons::list()?;
+            let daemon = match daemons::owning(&running, &path) {
+                Some(daemon) => daemon,
+                None => bail!("{} is not inside a mounted archive", path.display()),
+            };
+            println!("mount\t{}", daemon.mount_point.display());
+            println!("pid\t{}", daemon.pid);
+            if let Some(uuid) = &daemon.uuid {
+                println!("uuid\t{}", uuid);
+            }
+            if let Some(origin) = origin::read(&daemon.mount_point) {
+                println!("origin\t{}", origin.archive.display());
+                println!("inode\t{}:{}", origin.dev, origin.ino);
+            }
+            if let Some((_, archive)) = daemons::archive_of(daemon) {
+                println!("archive\t{}", archive.display());
+            }
+            if let Some(build_id) = build_id_of(daemon, &root_log) {
+                println!("build_id\t{}", build_id);
+            }
+            Ok(())
+        }
+        ("pin", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
             let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
-            let mount = Directory::from_xar(&xar, root_log.clone())?;
-            if sub_m.is_present("print_only") {
-                println!("{}", mount.path.to_str().unwrap());
-                Ok(())
+            Directory::from_xar(&xar, root_log.clone())?.pin()
+        }
+        ("plan", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let uid = sub_m
+                .value_of("uid")
+                .unwrap()
+                .parse()
+                .with_context(|_| "while parsing --uid")?;
+            let roots = match sub_m.values_of_os("root") {
+                Some(roots) => roots.map(PathBuf::from).collect(),
+                None => default_mount_roots(),
+            };
+            let naming = match cli_naming(sub_m)? {
+                Some(naming) => naming,
+                None => xar.naming()?,
+            };
+            let path = mount_path_for(
+                &xar.header,
+                &naming,
+                uid,
+                sub_m.value_of("ns_id"),
+                sub_m.value_of("seed"),
+                &roots,
+            )?;
+            println!("{}", path.display());
+            Ok(())
+        }
+        ("procs", Some(sub_m)) => {
+            let mount = mount_of(sub_m.value_of_os("archive").unwrap(), &root_log)?;
+            for process in procs::using(&mount.path)? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    process.pid,
+                    process.uid,
+                    process.comm,
+                    process.uses.join(",")
+                );
+            }
+            Ok(())
+        }
+        ("repair", Some(sub_m)) => {
+            let mount_root = match sub_m.value_of_os("archive") {
+                Some(archive) => Xar::from_file(PathBuf::from(archive), root_log.clone())?
+                    .header
+                    .mount_root
+                    .clone(),
+                None => None,
+            };
+            let repairs = if sub_m.is_present("all_users") {
+                let root = find_mount_root_in(&root_log, &mount_root, &System, &System)?;
+                repair::repair_all_users(&root_log, &root, sub_m.is_present("as_owner"))?
             } else {
-                xar.mount(&mount)?;
-                Ok(())
+                repair::repair(&root_log, &user_directory(&root_log, &mount_root)?)?
+            };
+            for repair in repairs {
+                println!("{}", repair);
+            }
+            Ok(())
+        }
+        ("run-many", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let mount = Directory::from_xar(&xar, root_log.clone())?;
+            let commands = commands(sub_m);
+            let statuses = xar.run_many(&mount, &commands, sub_m.is_present("unmount"))?;
+            let failed = statuses.iter().filter(|status| !status.success()).count();
+            if failed > 0 {
+                bail!("{} of {} commands failed", failed, statuses.len());
             }
+            Ok(())
+        }
+        ("self-update", Some(sub_m)) => {
+            let channel = sub_m.value_of("channel").unwrap();
+            match update::self_update(&root_log, channel, sub_m.is_present("force"))? {
+                update::Outcome::UpToDate(version) => println!("up to date at {}", version),
+                update::Outcome::Updated { from, to } => println!("updated {} to {}", from, to),
+            }
+            Ok(())
+        }
+        ("selftest", Some(_)) => selftest::run(&root_log),
+        ("status", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let mount = Directory::from_xar(&xar, root_log.clone())?;
+            print_status(&xar, &mount)
+        }
+        ("stats", Some(sub_m)) => {
+            let roots: Vec<PathBuf> = match sub_m.values_of_os("root") {
+                Some(roots) => roots.map(PathBuf::from).collect(),
+                None => default_mount_roots(),
+            };
+            let stats = stats::gather(&root_log, &roots);
+            for root in &stats.roots {
+                println!("root\t{}", root.display());
+            }
+            for (owner, mounts) in &stats.mounts {
+                println!("mounts\t{}\t{}", owner, mounts);
+            }
+            println!(
+                "workspaces\t{}\t{} bytes",
+                stats.workspaces, stats.workspace_bytes
+            );
+            if let (Some(oldest), Some(newest)) = (stats.oldest_lock, stats.newest_lock) {
+                println!("oldest lockfile\t{}s", oldest.as_secs());
+                println!("newest lockfile\t{}s", newest.as_secs());
+            }
+            println!("expired leases\t{}", stats.expired);
+            println!("unmounted directories\t{}", stats.unmounted);
+            println!("orphaned lockfiles\t{}", stats.orphaned_locks);
+            Ok(())
+        }
+        ("target", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let mount = Directory::from_xar(&xar, root_log.clone())?;
+            xar.mount_if_needed(&mount)?;
+            println!("{}", xar.target_path(&mount)?.display());
+            Ok(())
+        }
+        ("unmount", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let mount = Directory::from_xar(&xar, root_log.clone())?;
+            if sub_m.is_present("abort") {
+                return mount.abort();
+            }
+            if sub_m.is_present("kill") {
+                let signal = procs::parse_signal(sub_m.value_of("kill").unwrap_or("TERM"))?;
+                procs::signal_all(&root_log, &procs::using(&mount.path)?, signal)?;
+            }
+            if let Some(wait) = sub_m.value_of("wait") {
+                let busy =
+                    procs::wait_until_unused(&mount.path, Duration::from_secs(wait.parse()?))?;
+                if !busy.is_empty() {
+                    let pids: Vec<String> =
+                        busy.iter().map(|process| process.pid.to_string()).collect();
+                    bail!(
+                        "{} is still used by {} after {}s",
+                        mount.path.display(),
+                        pids.join(", "),
+                        wait
+                    );
+                }
+            }
+            mount.unmount()
+        }
+        ("unpin", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            Directory::from_xar(&xar, root_log.clone())?.unpin()
+        }
+        ("wait", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let mut xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            xar.mount_options.system = sub_m.is_present("system");
+            let mount = Directory::from_xar(&xar, root_log.clone())?;
+            let timeout = Duration::from_secs(sub_m.value_of("timeout").unwrap().parse()?);
+            xar.wait_until_ready(&mount, timeout)?;
+            Ok(())
+        }
+        ("warm", Some(sub_m)) => {
+            let mount = Directory::at(
+                Path::new(sub_m.value_of_os("mount").unwrap()),
+                root_log.clone(),
+            )?;
+            let paths: Vec<String> = sub_m
+                .values_of("paths")
+                .map(|paths| paths.map(String::from).collect())
+                .unwrap_or_default();
+            warm::warm(&root_log, &mount, &paths);
+            Ok(())
+        }
+        ("watch", Some(sub_m)) => {
+            let archive = sub_m.value_of_os("archive").unwrap();
+            let xar = Xar::from_file(PathBuf::from(archive), root_log.clone())?;
+            let mount = Directory::from_xar(&xar, root_log.clone())?;
+            xar.mount(&mount)?;
+            xar.watch_access(&mount)
         }
         _ => Err(format_err!("invalid subcommand")),
     }
 }
 
+#[derive(Serialize)]
+struct MountJson {
+    archive: String,
+    mount: String,
+    // Whether this run mounted the archive, absent with -n.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spawned: Option<bool>,
+}
+
+// One line per archive. Paths that aren't UTF-8 are converted lossily, -n
+// without --json gives the exact bytes.
+fn mount_json(
+    archive: &Path,
+    mount: &Path,
+    spawned: Option<bool>,
+) -> Result<Vec<u8>, failure::Error> {
+    let json = MountJson {
+        archive: archive.to_string_lossy().into_owned(),
+        mount: mount.to_string_lossy().into_owned(),
+        spawned,
+    };
+    let mut line = serde_json::to_vec(&json)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+// The mount for an archive, or a mountpoint given directly.
+fn mount_of(path: &OsStr, logger: &slog::Logger) -> Result<Directory, failure::Error> {
+    let path = Path::new(path);
+    if path.is_dir() {
+        return Directory::at(&path.canonicalize()?, logger.clone());
+    }
+    let xar = Xar::from_file(path.to_path_buf(), logger.clone())?;
+    Directory::from_xar(&xar, logger.clone())
+}
+
+// The archive a running mount was made from: the daemon's open descriptor,
+// which survives the file being replaced, or else the path recorded at mount.
+fn archive_of_mount(path: &Path) -> Result<PathBuf, failure::Error> {
+    let path = path.canonicalize()?;
+    let running = daemons::list()?;
+    let mount = match daemons::owning(&running, &path) {
+        Some(daemon) => {
+            if let Some((descriptor, _)) = daemons::archive_of(daemon) {
+                return Ok(descriptor);
+            }
+            daemon.mount_point.clone()
+        }
+        None => path.clone(),
+    };
+    match origin::read(&mount) {
+        Some(origin) => Ok(origin.archive),
+        None => bail!("Unable to find the archive mounted at {}", path.display()),
+    }
+}
+
+// --naming or --mount-template, which is a custom naming strategy.
+fn cli_naming(matches: &ArgMatches) -> Result<Option<NamingStrategy>, failure::Error> {
+    if let Some(template) = matches.value_of("mount_template") {
+        Template::parse(template)?;
+        return Ok(Some(NamingStrategy::Custom(template.to_string())));
+    }
+    matches.value_of("naming").map(str::parse).transpose()
+}
+
+// The BUILD_ID of the archive a daemon serves, read through its descriptor,
+// which only its owner and root can follow.
+fn build_id_of(daemon: &daemons::Daemon, logger: &slog::Logger) -> Option<String> {
+    let (descriptor, _) = daemons::archive_of(daemon)?;
+    Xar::from_file(descriptor, logger.clone())
+        .ok()?
+        .header
+        .build_id
+}
+
+// PID, uid and mountpoint, followed by waiting requests, open fds and mount
+// options when activity was gathered, then the archive's BUILD_ID. Waiting
+// and options are - when the mount isn't visible to us, the build id when
+// the archive isn't or has none.
+fn describe_daemon(
+    daemon: &daemons::Daemon,
+    activity: &Option<HashMap<u32, daemons::Activity>>,
+    build_ids: &HashMap<u32, String>,
+) -> String {
+    let mut line = format!(
+        "{}\t{}\t{}",
+        daemon.pid,
+        daemon.uid,
+        daemon.mount_point.display()
+    );
+    if let Some(activity) = activity.as_ref().and_then(|all| all.get(&daemon.pid)) {
+        let waiting = activity
+            .waiting
+            .map(|waiting| waiting.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let options = activity
+            .options
+            .as_ref()
+            .map(|options| options.join(","))
+            .unwrap_or_else(|| "-".to_string());
+        line.push_str(&format!(
+            "\t{}\t{}\t{}",
+            waiting, activity.open_fds, options
+        ));
+    }
+    let build_id = build_ids.get(&daemon.pid).map_or("-", String::as_str);
+    line.push_str(&format!("\t{}", build_id));
+    line
+}
+
+// run-many's commands, each the values of one --cmd.
+fn commands(matches: &ArgMatches) -> Vec<Vec<OsString>> {
+    let values = matches.values_of_os("cmd").into_iter().flatten();
+    let indices = matches.indices_of("cmd").into_iter().flatten();
+    let mut commands: Vec<Vec<OsString>> = Vec::new();
+    let mut previous = None;
+    for (index, value) in indices.zip(values) {
+        // Values of one occurrence are adjacent, --cmd separates the next.
+        match commands.last_mut() {
+            Some(command) if previous.map(|previous| previous + 1) == Some(index) => {
+                command.push(value.into())
+            }
+            _ => commands.push(vec![value.into()]),
+        }
+        previous = Some(index);
+    }
+    commands
+}
+
+// One `key\tvalue` line per fact, like owner. Provenance is the header's,
+// the rest is only shown once the archive has been mounted.
+fn print_status(xar: &Xar, mount: &Directory) -> Result<(), failure::Error> {
+    let header = &xar.header;
+    println!("archive\t{}", xar.archive.display());
+    println!("uuid\t{}", header.uuid);
+    let provenance = [
+        ("build_id", header.build_id.clone()),
+        (
+            "source_date",
+            header.source_date.map(|date| date.to_string()),
+        ),
+        ("vcs_revision", header.vcs_revision.clone()),
+        ("builder", header.builder.clone()),
+    ];
+    for (key, value) in &provenance {
+        if let Some(value) = value {
+            println!("{}\t{}", key, value);
+        }
+    }
+    println!("mount\t{}", mount.path.display());
+    let mounted = mount.is_mounted()?;
+    println!("mounted\t{}", if mounted { "yes" } else { "no" });
+    println!("pinned\t{}", if mount.is_pinned() { "yes" } else { "no" });
+    let lockfile = lock::lockfile(&mount.path);
+    if lockfile.exists() {
+        let lease = if lock::is_expired_at(&lockfile)? {
+            "expired"
+        } else {
+            "live"
+        };
+        println!("lease\t{}", lease);
+    }
+    if let Some(holder) = lock::holder(&mount.path) {
+        println!("lock_holder\t{}", holder);
+    }
+    if let Some(origin) = origin::read(&mount.path) {
+        println!("origin\t{}", origin.archive.display());
+        println!("inode\t{}:{}", origin.dev, origin.ino);
+    }
+    if !mounted {
+        return Ok(());
+    }
+    if let Some(info) = mountinfo::find(&mount.path).ok().flatten() {
+        let read_only = if info.is_read_only() { "yes" } else { "no" };
+        println!("read_only\t{}", read_only);
+        println!("options\t{}", info.options.join(","));
+        println!("super_options\t{}", info.super_options.join(","));
+    }
+    let running: Vec<daemons::Daemon> = daemons::list()?
+        .into_iter()
+        .filter(|daemon| daemon.mount_point == mount.path)
+        .collect();
+    let activity = daemons::activity(&running);
+    for daemon in &running {
+        println!("pid\t{}", daemon.pid);
+        if let Some(activity) = activity.get(&daemon.pid) {
+            if let Some(waiting) = activity.waiting {
+                println!("waiting\t{}", waiting);
+            }
+            println!("open_fds\t{}", activity.open_fds);
+        }
+    }
+    Ok(())
+}
+
+// Where the subcommand, or a bare archive, sits in `args`, skipping global
+// flags and their values.
+fn first_positional(args: &[OsString]) -> Option<usize> {
+    let valued = global_options();
+    let mut position = 1;
+    while let Some(arg) = args.get(position) {
+        let arg = arg.to_string_lossy();
+        if valued.iter().any(|option| *option == arg) {
+            position += 2;
+        } else if arg.starts_with('-') {
+            position += 1;
+        } else {
+            return Some(position);
+        }
+    }
+    None
+}
+
+// The global options which take their value as the next argument, as
+// spelled on the command line.
+fn global_options() -> Vec<String> {
+    app()
+        .p
+        .opts
+        .iter()
+        .flat_map(|opt| {
+            let long = opt.s.long.map(|long| format!("--{}", long));
+            let short = opt.s.short.map(|short| format!("-{}", short));
+            long.into_iter().chain(short)
+        })
+        .collect()
+}
+
+// Rewrite the command line before clap sees it. `xarfuse ARCHIVE [ARGS]`
+// runs the default subcommand, exec unless XAR_DEFAULT_COMMAND says mount. An
+// archive is told apart from a subcommand by containing a / or ending in .xar
+// or .AppImage, which no subcommand does. `ls` names the list subcommand
+// unless an archive follows, which lists the archive's contents.
+fn rewrite_args(mut args: Vec<OsString>, default: &str) -> Vec<OsString> {
+    let position = match first_positional(&args) {
+        Some(position) => position,
+        None => return args,
+    };
+    let arg = args[position].to_string_lossy().into_owned();
+    if arg.contains('/') || arg.ends_with(".xar") || arg.ends_with(".AppImage") {
+        args.insert(position, OsString::from(default));
+    } else if arg == "ls"
+        && !args[position + 1..]
+            .iter()
+            .any(|arg| !arg.to_string_lossy().starts_with('-'))
+    {
+        args[position] = OsString::from("list");
+    }
+    args
+}
+
+// The default subcommand, from XAR_DEFAULT_COMMAND's value if set.
+fn default_command(value: Option<String>) -> Result<String, failure::Error> {
+    match value {
+        Some(command) => match command.as_str() {
+            "exec" | "mount" => Ok(command),
+            _ => bail!(
+                "{} must be exec or mount, not {}",
+                XAR_DEFAULT_COMMAND,
+                command
+            ),
+        },
+        None => Ok("exec".to_string()),
+    }
+}
+
+// Each cause on one line, outermost first: "while mounting X: while spawning
+// squashfuse_ll: No such file or directory".
+fn error_chain(e: &failure::Error) -> Vec<String> {
+    e.iter_chain().map(|cause| cause.to_string()).collect()
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    error: String,
+    chain: Vec<String>,
+}
+
 // Boilerplate main to print errors nicely.
 fn main() {
     std::env::set_var("RUST_BACKTRACE", "1");
-    if let Err(ref e) = run() {
+    let args = std::env::args_os().collect();
+    let result = default_command(std::env::var(XAR_DEFAULT_COMMAND).ok())
+        .map_err(|e| (e, false))
+        .and_then(|default| {
+            let matches = app().get_matches_from(rewrite_args(args, &default));
+            let json = matches.value_of("error_format") == Some("json");
+            run(&matches).map_err(|e| (e, json))
+        });
+    if let Err((ref e, json)) = result {
+        if let Some(Exited(code)) = e.downcast_ref::<Exited>() {
+            ::std::process::exit(*code);
+        }
         use std::io::Write; // trait which holds `display`
         let stderr = &mut ::std::io::stderr();
         let errmsg = "Error writing to stderr";
 
-        writeln!(stderr, "{}, {}", e.as_fail(), e.backtrace()).expect(errmsg);
+        let chain = error_chain(e);
+        if json {
+            let json = JsonError {
+                error: chain.join(": "),
+                chain,
+            };
+            writeln!(stderr, "{}", serde_json::to_string(&json).expect(errmsg)).expect(errmsg);
+        } else {
+            writeln!(stderr, "{}, {}", chain.join(": "), e.backtrace()).expect(errmsg);
+        }
         ::std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn subcommand(argv: &[&str]) -> String {
+        let matches = app()
+            .get_matches_from_safe(rewrite_args(args(argv), "exec"))
+            .unwrap();
+        matches.subcommand_name().unwrap().to_string()
+    }
+
+    #[test]
+    fn bare_archive_runs_default() {
+        assert_eq!(
+            rewrite_args(args(&["xarfuse", "/a/b.xar", "--flag"]), "exec"),
+            args(&["xarfuse", "exec", "/a/b.xar", "--flag"])
+        );
+        assert_eq!(
+            rewrite_args(args(&["xarfuse", "-v", "b.xar"]), "mount"),
+            args(&["xarfuse", "-v", "mount", "b.xar"])
+        );
+        assert_eq!(
+            rewrite_args(
+                args(&["xarfuse", "--error-format", "json", "b.AppImage"]),
+                "exec"
+            ),
+            args(&["xarfuse", "--error-format", "json", "exec", "b.AppImage"])
+        );
+        assert_eq!(
+            rewrite_args(
+                args(&["xarfuse", "--log-target", "file:/tmp/x", "a.xar"]),
+                "exec"
+            ),
+            args(&["xarfuse", "--log-target", "file:/tmp/x", "exec", "a.xar"])
+        );
+    }
+
+    #[test]
+    fn commands_keep_their_arguments() {
+        let matches = app().get_matches_from(args(&[
+            "xarfuse",
+            "run-many",
+            "a.xar",
+            "--cmd",
+            "bin/a",
+            "-l",
+            "two words",
+            ";",
+            "--cmd",
+            "bin/b",
+            ";",
+            "--unmount",
+        ]));
+        let sub_m = matches.subcommand_matches("run-many").unwrap();
+        assert_eq!(
+            commands(sub_m),
+            vec![args(&["bin/a", "-l", "two words"]), args(&["bin/b"])]
+        );
+        assert!(sub_m.is_present("unmount"));
+    }
+
+    #[test]
+    fn global_options_take_values() {
+        let options = global_options();
+        for option in &["--log-target", "--error-format", "--trace-bundle"] {
+            assert!(options.iter().any(|known| known == option), "{}", option);
+        }
+        assert!(!options.iter().any(|known| known == "--verbose"));
+    }
+
+    #[test]
+    fn subcommands_are_left_alone() {
+        for argv in &[
+            &["xarfuse", "mount", "/a/b.xar"][..],
+            &["xarfuse", "ls", "/a/b.xar"][..],
+            &["xarfuse"][..],
+            &["xarfuse", "-v"][..],
+        ] {
+            assert_eq!(rewrite_args(args(argv), "exec"), args(argv));
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!(subcommand(&["xarfuse", "m", "/a/b.xar"]), "mount");
+        assert_eq!(subcommand(&["xarfuse", "x", "/a/b.xar"]), "exec");
+        assert_eq!(subcommand(&["xarfuse", "ls"]), "list");
+        assert_eq!(subcommand(&["xarfuse", "ls", "--by-uuid"]), "list");
+        assert_eq!(
+            subcommand(&["xarfuse", "--log-target", "syslog", "ls"]),
+            "list"
+        );
+        assert_eq!(subcommand(&["xarfuse", "ls", "/a/b.xar"]), "ls");
+        assert_eq!(subcommand(&["xarfuse", "/a/b.xar"]), "exec");
+    }
+
+    #[test]
+    fn default_command_values() {
+        assert_eq!(default_command(None).unwrap(), "exec");
+        assert_eq!(default_command(Some("mount".to_string())).unwrap(), "mount");
+        assert!(default_command(Some("list".to_string())).is_err());
+    }
+}
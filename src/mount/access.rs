@@ -0,0 +1,146 @@
+use crate::mount::daemons::open_files;
+use crate::mount::directory::Directory;
+use crate::mount::lock::Lock;
+use crate::xar::Xar;
+
+use std::env;
+use std::fs::File;
+use std::thread;
+use std::time::Duration;
+
+// Seconds between checks for use of the mount when fanotify is unavailable.
+const XAR_ACCESS_POLL: &str = "XAR_ACCESS_POLL";
+const DEFAULT_ACCESS_POLL: Duration = Duration::from_secs(30);
+
+fn access_poll() -> Duration {
+    env::var(XAR_ACCESS_POLL)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_ACCESS_POLL)
+}
+
+// Watch every file on the mount for opens and reads. Mount marks need
+// CAP_SYS_ADMIN, so this is None for most users.
+#[cfg(target_os = "linux")]
+fn fanotify(mount: &Directory) -> Option<File> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe {
+        libc::fanotify_init(
+            libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+            (libc::O_RDONLY | libc::O_CLOEXEC) as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    let path = CString::new(mount.path.as_os_str().as_bytes()).ok()?;
+    let marked = unsafe {
+        libc::fanotify_mark(
+            fd,
+            libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT,
+            libc::FAN_ACCESS | libc::FAN_OPEN,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+        )
+    };
+    if marked != 0 {
+        return None;
+    }
+    Some(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fanotify(_mount: &Directory) -> Option<File> {
+    None
+}
+
+// Wait up to `timeout` for events, returning whether there were any. Each
+// event carries a descriptor for the file accessed, which must be closed.
+#[cfg(target_os = "linux")]
+fn drain(events: &File, timeout: Duration) -> Result<bool, failure::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = events.as_raw_fd();
+    let mut poll = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+    if ready < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(e.into());
+    }
+
+    let mut accessed = false;
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read =
+            unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if read <= 0 {
+            return Ok(accessed);
+        }
+        let mut offset = 0;
+        let size = std::mem::size_of::<libc::fanotify_event_metadata>();
+        while offset + size <= read as usize {
+            let event = unsafe {
+                std::ptr::read_unaligned(
+                    buffer[offset..].as_ptr() as *const libc::fanotify_event_metadata
+                )
+            };
+            if event.fd >= 0 {
+                unsafe { libc::close(event.fd) };
+            }
+            accessed = true;
+            offset += event.event_len as usize;
+            if event.event_len == 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drain(_events: &File, _timeout: Duration) -> Result<bool, failure::Error> {
+    bail!("fanotify is only supported on Linux")
+}
+
+impl Xar {
+    // Renew the mount's lease whenever it's actually used, until it's
+    // unmounted, so cleanup going by the lease reflects real access rather
+    // than when xarfuse last ran. Uses fanotify where permitted, otherwise
+    // periodically looks for open files on the mount.
+    pub fn watch_access(&self, mount: &Directory) -> Result<(), failure::Error> {
+        let events = fanotify(mount);
+        debug!(
+            self.logger,
+            "Watching for access";
+            "mount" => mount.path.display().to_string(),
+            "fanotify" => events.is_some()
+        );
+        let poll = access_poll();
+        while mount.is_mounted()? {
+            let accessed = match &events {
+                Some(events) => drain(events, poll)?,
+                None => {
+                    thread::sleep(poll);
+                    open_files()
+                        .iter()
+                        .any(|file| file.starts_with(&mount.path))
+                }
+            };
+            if accessed {
+                Lock::directory(&mount.path)?.renew(self.lease())?;
+            }
+        }
+        Ok(())
+    }
+}
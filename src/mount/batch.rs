@@ -0,0 +1,228 @@
+use crate::mount::rlimit;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+// Only the first few failures are kept, preloading a fleet can fail on
+// thousands of archives for the same reason.
+const MAX_REPORTED_ERRORS: usize = 10;
+// Archives mounted at once by mount_all.
+const MAX_WORKERS: usize = 16;
+
+#[derive(Default)]
+pub struct Summary {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub errors: Vec<(PathBuf, failure::Error)>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} succeeded, {} failed", self.succeeded, self.failed)?;
+        for (archive, error) in &self.errors {
+            write!(f, "\n{}: {}", archive.display(), error)?;
+        }
+        if self.failed > self.errors.len() {
+            write!(f, "\n... {} more", self.failed - self.errors.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl Summary {
+    // Count one archive's result. Without `best_effort` its error is returned,
+    // otherwise it's logged and collected.
+    fn record(
+        self: &mut Summary,
+        logger: &slog::Logger,
+        archive: &Path,
+        result: Result<(), failure::Error>,
+        best_effort: bool,
+    ) -> Result<(), failure::Error> {
+        match result {
+            Ok(()) => self.succeeded += 1,
+            Err(e) if best_effort => {
+                warn!(
+                    logger,
+                    "Failed, continuing";
+                    "archive" => archive.display().to_string(),
+                    "error" => e.to_string()
+                );
+                self.failed += 1;
+                if self.errors.len() < MAX_REPORTED_ERRORS {
+                    self.errors.push((archive.to_path_buf(), e));
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+// Run `action` on every archive. Without `best_effort` the first error is
+// returned, otherwise failures are logged and collected into the summary.
+pub fn for_each_archive<F>(
+    logger: &slog::Logger,
+    archives: &[PathBuf],
+    best_effort: bool,
+    mut action: F,
+) -> Result<Summary, failure::Error>
+where
+    F: FnMut(&Path) -> Result<(), failure::Error>,
+{
+    let mut summary = Summary::default();
+    for archive in archives {
+        let result = rlimit::check_headroom(rlimit::FDS_PER_MOUNT).and_then(|_| action(archive));
+        summary.record(logger, archive, result, best_effort)?;
+    }
+    Ok(summary)
+}
+
+// Run `mount` on every archive, at most MAX_WORKERS at once. Readiness waits
+// share one mount table poller, so each worker is cheap. Results are handed
+// to `done` in archive order once all workers finish, so output doesn't
+// depend on which mount is quickest. Without `best_effort` no further archive
+// is started after a failure, and the first failure in archive order is
+// returned after `done` has seen the archives before it.
+pub fn mount_all<F, T, D>(
+    logger: &slog::Logger,
+    archives: &[PathBuf],
+    best_effort: bool,
+    mount: F,
+    mut done: D,
+) -> Result<Summary, failure::Error>
+where
+    F: Fn(&Path) -> Result<T, failure::Error> + Sync,
+    T: Send,
+    D: FnMut(&Path, T) -> Result<(), failure::Error>,
+{
+    let workers = archives.len().min(MAX_WORKERS);
+    // Descriptors are only held while a mount is in flight, so checking for
+    // every worker up front covers the whole batch.
+    rlimit::check_headroom(rlimit::FDS_PER_MOUNT * workers as u64)?;
+
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<T, failure::Error>>>> =
+        Mutex::new(archives.iter().map(|_| None).collect());
+    let mount = &mount;
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while !stop.load(Ordering::SeqCst) {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let archive = match archives.get(index) {
+                        Some(archive) => archive,
+                        None => break,
+                    };
+                    let result = mount(archive);
+                    if result.is_err() && !best_effort {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    let mut summary = Summary::default();
+    let results = results.into_inner().unwrap();
+    for (archive, result) in archives.iter().zip(results) {
+        match result {
+            Some(Ok(output)) => {
+                done(archive, output)?;
+                summary.succeeded += 1;
+            }
+            Some(Err(e)) => summary.record(logger, archive, Err(e), best_effort)?,
+            // Never started, after a failure.
+            None => (),
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn archives(count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| PathBuf::from(format!("/a/{}.xar", i)))
+            .collect()
+    }
+
+    #[test]
+    fn results_follow_archive_order() {
+        let archives = archives(40);
+        let mut seen = Vec::new();
+        let summary = mount_all(
+            &logger(),
+            &archives,
+            false,
+            |archive| Ok(archive.to_path_buf()),
+            |archive, output| {
+                assert_eq!(archive, output);
+                seen.push(output);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.succeeded, 40);
+        assert_eq!(seen, archives);
+    }
+
+    #[test]
+    fn first_failure_stops_scheduling() {
+        let archives = archives(200);
+        let started = AtomicUsize::new(0);
+        let mut done = 0;
+        let result = mount_all(
+            &logger(),
+            &archives,
+            false,
+            |archive| {
+                started.fetch_add(1, Ordering::SeqCst);
+                if archive == Path::new("/a/0.xar") {
+                    bail!("broken");
+                }
+                thread::sleep(Duration::from_millis(1));
+                Ok(())
+            },
+            |_, ()| {
+                done += 1;
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(done, 0);
+        assert!(started.load(Ordering::SeqCst) < archives.len());
+    }
+
+    #[test]
+    fn best_effort_mounts_everything() {
+        let archives = archives(30);
+        let summary = mount_all(
+            &logger(),
+            &archives,
+            true,
+            |archive| {
+                if archive.to_string_lossy().ends_with("3.xar") {
+                    bail!("broken");
+                }
+                Ok(())
+            },
+            |_, ()| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(summary.succeeded, 27);
+        assert_eq!(summary.failed, 3);
+    }
+}
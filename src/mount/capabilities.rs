@@ -0,0 +1,97 @@
+use crate::mount::SQUASHFUSE_BIN;
+
+use std::cell::RefCell;
+use std::process::{Command, Stdio};
+
+// What the installed squashfuse_ll accepts, going by its --version and
+// --help output. Older releases have no --version and reject `timeout=`.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub version: Option<String>,
+    pub timeout: bool,
+    pub notify_pipe: bool,
+    pub uid_gid: bool,
+}
+
+impl Capabilities {
+    // When squashfuse can't be run at all, assume it's current; the mount
+    // fails with a clearer error than anything we could say here.
+    fn assumed() -> Capabilities {
+        Capabilities {
+            version: None,
+            timeout: true,
+            notify_pipe: true,
+            uid_gid: true,
+        }
+    }
+
+    pub fn options(&self) -> Vec<&'static str> {
+        let mut options = Vec::new();
+        if self.timeout {
+            options.push("timeout");
+        }
+        if self.notify_pipe {
+            options.push("notify_pipe");
+        }
+        if self.uid_gid {
+            options.push("uid/gid");
+        }
+        options
+    }
+}
+
+thread_local! {
+    static PROBED: RefCell<Option<Capabilities>> = RefCell::new(None);
+}
+
+fn output(arg: &str) -> Option<String> {
+    let output = Command::new(SQUASHFUSE_BIN)
+        .arg(arg)
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    // Usage goes to stderr, and with a failing status, in most releases.
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Some(text)
+}
+
+// The first word made of digits and dots, e.g. "0.5.2" from
+// "squashfuse_ll (version 0.5.2) (c) 2012 Dave Vasilevsky".
+fn parse_version(text: &str) -> Option<String> {
+    text.split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .map(|word| word.trim_matches('.'))
+        .find(|word| word.contains('.') && word.starts_with(|c: char| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+// Whether `option` is documented as an option of its own, so `timeout`
+// isn't found in FUSE's `entry_timeout`.
+fn mentions(help: &str, option: &str) -> bool {
+    help.match_indices(option).any(|(start, _)| {
+        let before = help[..start].chars().last();
+        let after = help[start + option.len()..].chars().next();
+        !before.map_or(false, |c| c.is_alphanumeric() || c == '_')
+            && after.map_or(true, |c| c == '=' || c.is_whitespace() || c == ',')
+    })
+}
+
+fn probe() -> Capabilities {
+    let help = match output("--help") {
+        Some(help) => help,
+        None => return Capabilities::assumed(),
+    };
+    Capabilities {
+        version: output("--version")
+            .as_ref()
+            .and_then(|text| parse_version(text)),
+        timeout: mentions(&help, "timeout"),
+        notify_pipe: mentions(&help, "notify_pipe"),
+        uid_gid: mentions(&help, "uid") && mentions(&help, "gid"),
+    }
+}
+
+// Probed once, then cached.
+pub fn squashfuse() -> Capabilities {
+    PROBED.with(|probed| probed.borrow_mut().get_or_insert_with(probe).clone())
+}
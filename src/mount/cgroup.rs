@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Cgroups may be given absolute or relative to the cgroup v2 hierarchy.
+fn procs_file(cgroup: &Path) -> PathBuf {
+    let dir = if cgroup.starts_with(CGROUP_ROOT) {
+        cgroup.to_path_buf()
+    } else {
+        Path::new(CGROUP_ROOT).join(cgroup.strip_prefix("/").unwrap_or(cgroup))
+    };
+    dir.join("cgroup.procs")
+}
+
+// Move the child into `cgroup` between fork and exec, so everything it forks,
+// including a daemonized squashfuse, is accounted there. The returned file
+// must outlive the spawn; it's opened up front so the child only writes.
+#[cfg(target_os = "linux")]
+pub fn join_on_exec(cmd: &mut Command, cgroup: &Path) -> Result<File, failure::Error> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let path = procs_file(cgroup);
+    let procs = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .map_err(|e| format_err!("Unable to open {}: {}", path.display(), e))?;
+    let fd = procs.as_raw_fd();
+    unsafe {
+        // Writing 0 moves the writing process.
+        cmd.pre_exec(move || {
+            if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) != 1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(procs)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn join_on_exec(_cmd: &mut Command, _cgroup: &Path) -> Result<File, failure::Error> {
+    bail!("cgroups are only supported on Linux")
+}
@@ -0,0 +1,117 @@
+use crate::mount::directory::Directory;
+use crate::mount::lock::lockfile;
+use crate::mount::mounter::Mounter;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+// What one mount made, so a script can undo exactly that and no more, e.g.
+// for a job scheduler tearing down a job without knowing xarfuse internals.
+pub struct Created {
+    mount: PathBuf,
+    // The user directory, if this mount created it.
+    userdir: Option<PathBuf>,
+    directory: bool,
+    mounted: bool,
+    mounter: Mounter,
+}
+
+impl Created {
+    // After mounting `mount`, with `mounted` whether that spawned the mount.
+    pub fn of(mount: &Directory, mounted: bool, mounter: Mounter) -> Created {
+        Created {
+            mount: mount.path.clone(),
+            userdir: if mount.made.userdir.load(Ordering::Relaxed) {
+                mount.path.parent().map(Path::to_path_buf)
+            } else {
+                None
+            },
+            directory: mount.made.directory.load(Ordering::Relaxed),
+            mounted,
+            mounter,
+        }
+    }
+}
+
+// Single quoted, with embedded quotes closed, escaped and reopened. Bytes
+// rather than strings, so non-UTF-8 paths survive.
+fn quote(value: &OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for byte in value.as_bytes() {
+        if *byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(*byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+fn line(script: &mut Vec<u8>, parts: &[&[u8]]) {
+    script.extend(parts.join(&b" "[..]));
+    script.push(b'\n');
+}
+
+// Every step tolerates having been done already, so the script may run more
+// than once, e.g. from both a trap and a job epilogue. Mounts are shared, so
+// each is only undone holding its lock, which xarfuse and xarexec_fuse take
+// to mount it, and skipped while anyone else holds it. Unmounts aren't lazy,
+// so a mount still in use stays.
+pub fn script(created: &[Created]) -> Vec<u8> {
+    let mut script = b"#!/bin/sh\n# Written by xarfuse mount --cleanup-script.\n".to_vec();
+    for created in created {
+        if !created.mounted && !created.directory {
+            continue;
+        }
+        let mount = quote(created.mount.as_os_str());
+        let lockfile = quote(lockfile(&created.mount).as_os_str());
+        line(&mut script, &[b"(", b"flock -n 9 || exit 0"]);
+        if created.mounted {
+            let unmount: &[u8] = match created.mounter {
+                Mounter::Kernel => b"umount --",
+                _ => b"fusermount -u --",
+            };
+            line(&mut script, &[b" ", unmount, &mount, b"|| exit 0"]);
+        }
+        if created.directory {
+            line(
+                &mut script,
+                &[b"  rmdir --", &mount, b"&& rm -f --", &lockfile],
+            );
+        }
+        line(&mut script, &[b") 2>/dev/null 9<", &lockfile]);
+        if let Some(userdir) = &created.userdir {
+            line(
+                &mut script,
+                &[b"rmdir --", &quote(userdir.as_os_str()), b"2>/dev/null"],
+            );
+        }
+    }
+    script.extend_from_slice(b"exit 0\n");
+    script
+}
+
+// Replaces `path` atomically, so a supervisor never runs half a script.
+pub fn write(path: &Path, created: &[Created]) -> Result<(), failure::Error> {
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(format!(".{}", std::process::id()));
+    let staged = PathBuf::from(staged);
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o755)
+        .open(&staged)
+        .and_then(|mut file| file.write_all(&script(created)))
+        .and_then(|_| fs::rename(&staged, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&staged);
+        bail!("while writing {}: {}", path.display(), e);
+    }
+    Ok(())
+}
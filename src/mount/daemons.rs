@@ -0,0 +1,299 @@
+use crate::mount::directory::{Directory, FUSE_CONNECTIONS, SYSTEM_BASEDIR};
+use crate::mount::events::{self, Event};
+use crate::mount::mountinfo::{self, MountInfo};
+use crate::mount::naming::NamingStrategy;
+use crate::mount::SQUASHFUSE_BIN;
+use crate::xar::normalize_uuid;
+
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PROC: &str = "/proc";
+// Refuse to start another daemon once this many serve the same UUID, unset or
+// 0 for no limit.
+const XAR_MAX_DAEMONS_PER_UUID: &str = "XAR_MAX_DAEMONS_PER_UUID";
+const EXIT_POLL: Duration = Duration::from_millis(20);
+
+// A running squashfuse, found through /proc so daemons in every mount
+// namespace and of every user are seen.
+pub struct Daemon {
+    pub pid: u32,
+    pub uid: u32,
+    pub mount_point: PathBuf,
+    pub uuid: Option<String>,
+}
+
+// Mount directories are UUID[-seed-S][-ns-N]. Versioned ones keep their
+// content hash, so each version counts separately.
+fn uuid_of(mount_point: &Path) -> Option<String> {
+    let name = mount_point.file_name()?.to_str()?;
+    let end = ["-seed-", "-ns-"]
+        .iter()
+        .filter_map(|separator| name.find(separator))
+        .min()
+        .unwrap_or_else(|| name.len());
+    Some(name[..end].to_string())
+}
+
+fn read_daemon(pid: u32) -> Option<Daemon> {
+    let dir = Path::new(PROC).join(pid.to_string());
+    let cmdline = fs::read(dir.join("cmdline")).ok()?;
+    let args: Vec<&[u8]> = cmdline
+        .split(|byte| *byte == 0)
+        .filter(|arg| !arg.is_empty())
+        .collect();
+    let program = Path::new(std::str::from_utf8(args.first()?).ok()?);
+    if program.file_name()?.to_str()? != SQUASHFUSE_BIN {
+        return None;
+    }
+    // The mountpoint is always the last argument, see Xar::mount_args.
+    let mount_point = PathBuf::from(std::str::from_utf8(args.last()?).ok()?);
+    Some(Daemon {
+        pid,
+        uid: fs::metadata(&dir).ok()?.uid(),
+        uuid: uuid_of(&mount_point),
+        mount_point,
+    })
+}
+
+// Re-read UUIDs from mount directories named by a custom template, which
+// uuid_of can't parse.
+pub fn decode_uuids(daemons: &mut [Daemon], naming: &NamingStrategy) {
+    for daemon in daemons {
+        if let Some(decoded) = naming.decode(&daemon.mount_point) {
+            daemon.uuid = Some(decoded.uuid);
+        }
+    }
+}
+
+pub fn list() -> Result<Vec<Daemon>, failure::Error> {
+    let mut daemons = Vec::new();
+    for entry in fs::read_dir(PROC)? {
+        let pid = match entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if let Some(daemon) = read_daemon(pid) {
+            daemons.push(daemon);
+        }
+    }
+    Ok(daemons)
+}
+
+// How busy a daemon's mount is, to tell mounts in use from idle ones.
+pub struct Activity {
+    // FUSE requests waiting on the daemon, None when its mount isn't in our
+    // namespace or the fuse control filesystem isn't mounted.
+    pub waiting: Option<u64>,
+    // Descriptors open on files in the mount, across every process whose
+    // /proc/PID/fd we can read.
+    pub open_fds: usize,
+    // Mount options, None like `waiting`.
+    pub options: Option<Vec<String>>,
+}
+
+// The connection directory is named after the mount's device minor.
+fn waiting(info: &MountInfo) -> Option<u64> {
+    let path = format!("{}/{}/waiting", FUSE_CONNECTIONS, info.minor);
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// Every readable fd target, gathered once for all daemons.
+pub(crate) fn open_files() -> Vec<PathBuf> {
+    let processes = match fs::read_dir(PROC) {
+        Ok(processes) => processes,
+        Err(_) => return Vec::new(),
+    };
+    let mut files = Vec::new();
+    for process in processes.filter_map(Result::ok) {
+        let fds = match fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.filter_map(Result::ok) {
+            if let Ok(target) = fs::read_link(fd.path()) {
+                files.push(target);
+            }
+        }
+    }
+    files
+}
+
+// Activity of each daemon, by pid.
+pub fn activity(daemons: &[Daemon]) -> HashMap<u32, Activity> {
+    let files = open_files();
+    daemons
+        .iter()
+        .map(|daemon| {
+            let open_fds = files
+                .iter()
+                .filter(|file| file.starts_with(&daemon.mount_point))
+                .count();
+            let info = mountinfo::find(&daemon.mount_point).ok().flatten();
+            (
+                daemon.pid,
+                Activity {
+                    waiting: info.as_ref().and_then(waiting),
+                    open_fds,
+                    options: info.map(|info| info.options),
+                },
+            )
+        })
+        .collect()
+}
+
+// The daemon serving `path`, which must be absolute. Nested mounts resolve
+// to the innermost.
+pub fn owning<'a>(daemons: &'a [Daemon], path: &Path) -> Option<&'a Daemon> {
+    daemons
+        .iter()
+        .filter(|daemon| path.starts_with(&daemon.mount_point))
+        .max_by_key(|daemon| daemon.mount_point.components().count())
+}
+
+// The archive a daemon has open, as the daemon's descriptor, which stays
+// readable if the file is replaced, and the path it was opened by. The
+// daemon's command line may only name a /proc/PID/fd path of the xarfuse
+// which started it.
+pub fn archive_of(daemon: &Daemon) -> Option<(PathBuf, PathBuf)> {
+    let fds = Path::new(PROC).join(daemon.pid.to_string()).join("fd");
+    fs::read_dir(fds)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|fd| fd.path())
+        .filter(|fd| fs::metadata(fd).map(|attr| attr.is_file()).unwrap_or(false))
+        .filter_map(|fd| fs::read_link(&fd).ok().map(|target| (fd, target)))
+        .next()
+}
+
+// Mount directories carry the UUID as written in the header, so spellings
+// of the same UUID are grouped by their normalized form.
+fn same_uuid(uuid: &str) -> String {
+    normalize_uuid(uuid).unwrap_or_else(|_| uuid.to_string())
+}
+
+pub fn by_uuid(daemons: &[Daemon]) -> BTreeMap<String, Vec<&Daemon>> {
+    let mut groups: BTreeMap<String, Vec<&Daemon>> = BTreeMap::new();
+    for daemon in daemons {
+        if let Some(uuid) = &daemon.uuid {
+            groups.entry(same_uuid(uuid)).or_default().push(daemon);
+        }
+    }
+    groups
+}
+
+// System mounts are shared by everyone, which makes any other daemon for the
+// same UUID redundant.
+pub fn redundant<'a>(group: &[&'a Daemon]) -> Vec<&'a Daemon> {
+    let is_system = |daemon: &Daemon| {
+        daemon.mount_point.parent().and_then(Path::file_name) == Some(SYSTEM_BASEDIR.as_ref())
+    };
+    if !group.iter().any(|daemon| is_system(daemon)) {
+        return Vec::new();
+    }
+    group
+        .iter()
+        .filter(|daemon| !is_system(daemon))
+        .cloned()
+        .collect()
+}
+
+// Unmount the redundant daemons of a group from by_uuid, reporting each as
+// reaped. Failures to unmount are logged and the rest still tried.
+pub fn consolidate(logger: &slog::Logger, group: &[&Daemon]) -> Result<(), failure::Error> {
+    for daemon in redundant(group) {
+        let mount = Directory::at(&daemon.mount_point, logger.clone())?;
+        match mount.unmount() {
+            Ok(()) => events::emit(Event::Reaped {
+                path: daemon.mount_point.clone(),
+                problem: "redundant daemon",
+                action: "unmounted".to_string(),
+            }),
+            Err(e) => warn!(
+                logger,
+                "Unable to unmount";
+                "mount" => daemon.mount_point.display().to_string(),
+                "error" => e.to_string()
+            ),
+        }
+    }
+    Ok(())
+}
+
+// Checked before starting a daemon for `mount_point`.
+pub fn check_cap(mount_point: &Path) -> Result<(), failure::Error> {
+    let cap: usize = match env::var(XAR_MAX_DAEMONS_PER_UUID)
+        .ok()
+        .and_then(|value| value.parse().ok())
+    {
+        Some(cap) if cap > 0 => cap,
+        _ => return Ok(()),
+    };
+    let uuid = uuid_of(mount_point);
+    let wanted = uuid.as_deref().map(same_uuid);
+    let daemons = list()?;
+    let serving = daemons
+        .iter()
+        .filter(|daemon| daemon.uuid.as_deref().map(same_uuid) == wanted)
+        .count();
+    if serving >= cap {
+        bail!(
+            "{} squashfuse daemons already serve {}, the limit from {} is {}",
+            serving,
+            uuid.unwrap_or_default(),
+            XAR_MAX_DAEMONS_PER_UUID,
+            cap
+        );
+    }
+    Ok(())
+}
+
+fn signal(daemons: &[Daemon], signal: Signal) -> Result<(), failure::Error> {
+    for daemon in daemons {
+        match kill(Pid::from_raw(daemon.pid as i32), signal) {
+            // It exited in the meantime.
+            Err(nix::Error::Sys(Errno::ESRCH)) => (),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+fn serving(mount: &Path) -> Result<Vec<Daemon>, failure::Error> {
+    Ok(list()?
+        .into_iter()
+        .filter(|daemon| daemon.mount_point == mount)
+        .collect())
+}
+
+// SIGTERM the daemons serving `mount`, then SIGKILL any still running after
+// `grace`. Returns how many were found.
+pub fn kill_serving(mount: &Path, grace: Duration) -> Result<usize, failure::Error> {
+    let daemons = serving(mount)?;
+    signal(&daemons, Signal::SIGTERM)?;
+    let start = Instant::now();
+    loop {
+        let remaining = serving(mount)?;
+        if remaining.is_empty() {
+            break;
+        }
+        if start.elapsed() >= grace {
+            signal(&remaining, Signal::SIGKILL)?;
+            break;
+        }
+        thread::sleep(EXIT_POLL);
+    }
+    Ok(daemons.len())
+}
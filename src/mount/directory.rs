@@ -1,130 +1,987 @@
-use crate::xar::Xar;
+use crate::error::XarError;
+use crate::sys::{Attr, Env, Fs, System};
+use crate::xar::{Xar, XarHeader};
 
-use crate::mount::lock::Lock;
+use crate::mount::events::{self, Event};
+use crate::mount::lock::{lockfile, pinfile, Lock};
+#[cfg(target_os = "linux")]
+use crate::mount::mounter::EROFSFUSE_BIN;
+#[cfg(target_os = "linux")]
+use crate::mount::mountinfo;
+use crate::mount::naming::{Names, NamingStrategy};
+use crate::mount::owner::Owner;
+use crate::mount::schema;
+use crate::mount::spawn;
+use crate::mount::watchdog;
+use crate::mount::workspace;
+use nix::fcntl;
 use nix::sys::stat;
+#[cfg(target_os = "macos")]
 use nix::sys::statfs::statfs;
-use nix::unistd::{chown, getegid, geteuid, mkdir, Uid};
-use std::env;
+use nix::unistd::{chown, close, geteuid, mkdir, Uid};
+use std::ffi::OsString;
 use std::fs;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+#[cfg(not(target_os = "macos"))]
 const DEFAULT_MOUNT_ROOTS: &[&str] = &["/mnt/xarfuse", "/dev/shm"];
+// macOS has neither, and clears /private/tmp on reboot like /dev/shm.
+#[cfg(target_os = "macos")]
+const DEFAULT_MOUNT_ROOTS: &[&str] = &["/private/tmp/xarfuse"];
 const PROC_MOUNT_NAMESPACE: &str = "/proc/self/ns/mnt";
+const NAMESPACE_COOKIE_DIR: &str = "/tmp";
+const NAMESPACE_COOKIE_PREFIX: &str = ".xarfuse-ns-";
+const DEV_URANDOM: &str = "/dev/urandom";
+pub(crate) const SYSTEM_BASEDIR: &str = "system";
+// Hex digits of the content hash in versioned mount directory names.
+const VERSION_HASH_LEN: usize = 12;
 const XAR_MOUNT_SEED: &str = "XAR_MOUNT_SEED";
+// Private roots live under a per-user directory named by this variable. On
+// macOS $TMPDIR is a per-user 0700 directory under /var/folders.
+#[cfg(not(target_os = "macos"))]
+const PRIVATE_ROOT_VAR: &str = "XDG_RUNTIME_DIR";
+#[cfg(target_os = "macos")]
+const PRIVATE_ROOT_VAR: &str = "TMPDIR";
+// From linux/magic.h
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+const FUSE_SUPER_MAGIC: i64 = 0x6573_5546;
+// EROFS payloads mounted by the kernel, see mounter.rs.
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+const EROFS_SUPER_MAGIC_V1: i64 = 0xe0f5_e1e2;
+pub(crate) const FUSE_CONNECTIONS: &str = "/sys/fs/fuse/connections";
+const XAR_MOUNT_MIN_FREE_BYTES: &str = "XAR_MOUNT_MIN_FREE_BYTES";
+const XAR_MOUNT_MIN_FREE_INODES: &str = "XAR_MOUNT_MIN_FREE_INODES";
+const DEFAULT_MIN_FREE_BYTES: u64 = 1024 * 1024;
+const DEFAULT_MIN_FREE_INODES: u64 = 16;
 
 pub struct Directory {
     logger: slog::Logger,
     pub path: PathBuf,
+    // For versioned mounts, the stable symlink naming the latest version.
+    pub current: Option<PathBuf>,
+    // The invoking user, who may differ from the effective one under sudo.
+    pub owner: Owner,
+    // Under <mount_root>/system, shared by every user.
+    pub system: bool,
+    pub made: Made,
 }
 
-fn find_mount_root(mount_root: &Option<String>) -> Result<PathBuf, failure::Error> {
+// What lock_and_mkdir made rather than found, so a cleanup script removes
+// only that. Each is decided by whoever's mkdir succeeded, or for the mount
+// directory under its lock, so concurrent mounts can't both claim one.
+#[derive(Default)]
+pub struct Made {
+    pub userdir: AtomicBool,
+    pub directory: AtomicBool,
+}
+
+fn threshold(env: &dyn Env, var: &str, default: u64) -> u64 {
+    env.var(var)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// Refuse mount roots that are nearly full, rather than failing halfway through
+// creating the directory and lockfile.
+fn check_free_space(fs: &dyn Fs, env: &dyn Env, root: &Path) -> Result<(), failure::Error> {
+    let free = fs.free(root)?;
+
+    let min_bytes = threshold(env, XAR_MOUNT_MIN_FREE_BYTES, DEFAULT_MIN_FREE_BYTES);
+    if free.bytes < min_bytes {
+        bail!(
+            "Mount root {} has {} bytes free, below {} ({})",
+            root.display(),
+            free.bytes,
+            min_bytes,
+            XAR_MOUNT_MIN_FREE_BYTES
+        );
+    }
+
+    // Some filesystems allocate inodes dynamically and report zero in total.
+    let min_inodes = threshold(env, XAR_MOUNT_MIN_FREE_INODES, DEFAULT_MIN_FREE_INODES);
+    if free.total_inodes > 0 && free.inodes < min_inodes {
+        bail!(
+            "Mount root {} has {} inodes free, below {} ({})",
+            root.display(),
+            free.inodes,
+            min_inodes,
+            XAR_MOUNT_MIN_FREE_INODES
+        );
+    }
+    Ok(())
+}
+
+// Shared roots are world writable with the sticky bit set, like /tmp.
+#[cfg(not(target_os = "macos"))]
+fn is_shared_root(attr: &Attr) -> bool {
+    (attr.mode & 0o07777) == 0o01777
+}
+
+// The sticky bit alone doesn't stop other users from replacing a shared root
+// on macOS, where anyone can create /private/tmp/xarfuse, so it must also be
+// owned by root or the current user.
+#[cfg(target_os = "macos")]
+fn is_shared_root(attr: &Attr) -> bool {
+    let owner = attr.uid;
+    (attr.mode & 0o07777) == 0o01777 && (owner == 0 || owner == geteuid().as_raw())
+}
+
+// Private roots belong to the invoking user and are closed to everyone else.
+fn is_private_root(env: &dyn Env, attr: &Attr) -> bool {
+    attr.uid == env.invoking_uid() && (attr.mode & 0o077) == 0
+}
+
+// $XDG_RUNTIME_DIR/xarfuse, or $TMPDIR/xarfuse on macOS, for hosts where no
+// shared root is usable.
+fn private_mount_root(logger: &slog::Logger, fs: &dyn Fs, env: &dyn Env) -> Option<PathBuf> {
+    let runtime_dir = PathBuf::from(env.var_os(PRIVATE_ROOT_VAR)?);
+    if !is_private_root(env, &fs.attr(&runtime_dir).ok()?) {
+        debug!(logger, "Skipping mount root"; "root" => runtime_dir.display().to_string(), "reason" => "permissions");
+        return None;
+    }
+
+    let root = runtime_dir.join("xarfuse");
+    if fs.attr(&root).is_err() {
+        debug!(logger, "Creating directory"; "dir" => root.display().to_string());
+        fs.mkdir(&root, 0o700).ok()?;
+    }
+    if !is_private_root(env, &fs.attr(&root).ok()?) {
+        debug!(logger, "Skipping mount root"; "root" => root.display().to_string(), "reason" => "permissions");
+        return None;
+    }
+    Some(root)
+}
+
+// A default mount root passed over, and why.
+pub struct Skipped {
+    pub root: PathBuf,
+    pub reason: String,
+}
+
+// Roots on read-only filesystems, common on image-based systems, pass the
+// permission checks but fail at mkdir with EROFS, so probe them up front.
+fn check_usable(fs: &dyn Fs, env: &dyn Env, root: &Path) -> Result<(), failure::Error> {
+    if let Err(e) = fs.writable(root) {
+        bail!("Mount root {} isn't writable: {}", root.display(), e);
+    }
+    check_free_space(fs, env, root)
+}
+
+pub(crate) fn find_mount_root(
+    logger: &slog::Logger,
+    mount_root: &Option<String>,
+) -> Result<PathBuf, failure::Error> {
+    find_mount_root_in(logger, mount_root, &System, &System)
+}
+
+// find_mount_root, also returning the default roots skipped on the way.
+pub(crate) fn find_mount_root_skipping(
+    logger: &slog::Logger,
+    mount_root: &Option<String>,
+) -> (Result<PathBuf, failure::Error>, Vec<Skipped>) {
+    let mut skipped = Vec::new();
+    let root = find_mount_root_noting(logger, mount_root, &System, &System, &mut skipped);
+    (root, skipped)
+}
+
+// find_mount_root, on the given host.
+pub fn find_mount_root_in(
+    logger: &slog::Logger,
+    mount_root: &Option<String>,
+    fs: &dyn Fs,
+    env: &dyn Env,
+) -> Result<PathBuf, failure::Error> {
+    find_mount_root_noting(logger, mount_root, fs, env, &mut Vec::new())
+}
+
+fn find_mount_root_noting(
+    logger: &slog::Logger,
+    mount_root: &Option<String>,
+    fs: &dyn Fs,
+    env: &dyn Env,
+    skipped: &mut Vec<Skipped>,
+) -> Result<PathBuf, failure::Error> {
     // If provided, use a non-default mount root from the header.
     if let Some(root) = mount_root {
-        let attr = fs::metadata(&root)?;
-        let permissions = attr.permissions();
-        if (permissions.mode() & 0o07777) != 0o01777 {
+        let attr = fs.attr(Path::new(root))?;
+        if !is_shared_root(&attr) {
             bail!("Mount root {} permissions should be 0o01777", &root);
         }
+        check_usable(fs, env, Path::new(root))?;
         return Ok(PathBuf::from(root));
     }
 
-    // Otherwise find the first proper mount root from our list of defaults.
+    // Otherwise find the first proper mount root from our list of defaults,
+    // falling back to a private per-user root.
     for candidate in DEFAULT_MOUNT_ROOTS {
-        if let Ok(attr) = fs::metadata(candidate) {
-            let permissions = attr.permissions();
-            if (permissions.mode() & 0o07777) == 0o01777 {
-                return Ok(PathBuf::from(candidate));
+        if let Ok(attr) = fs.attr(Path::new(candidate)) {
+            if !is_shared_root(&attr) {
+                debug!(logger, "Skipping mount root"; "root" => *candidate, "reason" => "permissions");
+                skipped.push(Skipped {
+                    root: PathBuf::from(candidate),
+                    reason: "permissions should be 0o01777".to_string(),
+                });
+                continue;
+            }
+            match check_usable(fs, env, Path::new(candidate)) {
+                Ok(()) => return Ok(PathBuf::from(candidate)),
+                Err(e) => {
+                    warn!(logger, "Skipping mount root"; "root" => *candidate, "reason" => e.to_string());
+                    skipped.push(Skipped {
+                        root: PathBuf::from(candidate),
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    if let Some(root) = private_mount_root(logger, fs, env) {
+        match check_usable(fs, env, &root) {
+            Ok(()) => return Ok(root),
+            Err(e) => {
+                warn!(logger, "Skipping mount root"; "root" => root.display().to_string(), "reason" => e.to_string());
+                skipped.push(Skipped {
+                    root,
+                    reason: e.to_string(),
+                });
             }
         }
     }
-    Err(format_err!("Unable to find suitable 0o01777 mount root."))
+    Err(format_err!(
+        "Unable to find suitable 0o01777 mount root or private ${}/xarfuse.",
+        PRIVATE_ROOT_VAR
+    ))
 }
 
-fn get_user_basedir(uid: Uid) -> String {
+pub(crate) fn get_user_basedir(uid: Uid) -> String {
     format!("uid-{}", uid)
 }
 
-fn get_mount_dir(uuid: &str) -> String {
-    let mut mount_directory = String::from(uuid);
+// The current user's directory under the mount root, <mount_root>/uid-N.
+pub fn user_directory(
+    logger: &slog::Logger,
+    mount_root: &Option<String>,
+) -> Result<PathBuf, failure::Error> {
+    Ok(find_mount_root(logger, mount_root)?.join(get_user_basedir(Owner::invoking().uid)))
+}
 
-    // We optionally also take a user-specified "seed" from the environment.  We cannot rely
-    // purely on mount namespace as the kernel will aggressively re-use namespace IDs.
-    if let Ok(seed) = env::var(XAR_MOUNT_SEED) {
+// The directory shared by all users, <mount_root>/system. Only a shared mount
+// root will do, since a private one is invisible to other users.
+pub fn system_directory(
+    logger: &slog::Logger,
+    mount_root: &Option<String>,
+) -> Result<PathBuf, failure::Error> {
+    Ok(system_root(logger, mount_root)?.join(SYSTEM_BASEDIR))
+}
+
+fn system_root(
+    logger: &slog::Logger,
+    mount_root: &Option<String>,
+) -> Result<PathBuf, failure::Error> {
+    let root = find_mount_root(logger, mount_root)?;
+    if !is_shared_root(&System.attr(&root)?) {
+        bail!(
+            "System mounts need a shared mount root, {} is private",
+            root.display()
+        );
+    }
+    Ok(root)
+}
+
+// Determine our mount namespace id via the inode on /proc/self/ns/mnt
+#[cfg(not(target_os = "freebsd"))]
+fn namespace_id(fs: &dyn Fs) -> Option<String> {
+    fs.attr(Path::new(PROC_MOUNT_NAMESPACE))
+        .ok()
+        .map(|attr| attr.ino.to_string())
+}
+
+// FreeBSD has no mount namespaces or /proc by default. Jails and chroots are
+// told apart by the inode of their root directory instead.
+#[cfg(target_os = "freebsd")]
+fn namespace_id(fs: &dyn Fs) -> Option<String> {
+    fs.attr(Path::new("/"))
+        .ok()
+        .map(|attr| attr.ino.to_string())
+}
+
+fn random_cookie() -> String {
+    let mut bytes = [0u8; 8];
+    if fs::File::open(DEV_URANDOM)
+        .and_then(|mut urandom| std::io::Read::read_exact(&mut urandom, &mut bytes))
+        .is_err()
+    {
+        // Without /dev either, the time and pid are still unlikely to repeat.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.subsec_nanos())
+            .unwrap_or_default();
+        bytes = (u64::from(nanos) << 32 | u64::from(std::process::id())).to_le_bytes();
+    }
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Without /proc, e.g. in a chroot or minimal container, distinguish namespaces
+// by a random cookie kept in /tmp, which such environments rarely share.
+fn namespace_cookie(fs: &dyn Fs, env: &dyn Env) -> Option<String> {
+    let path = PathBuf::from(NAMESPACE_COOKIE_DIR).join(format!(
+        "{}{}",
+        NAMESPACE_COOKIE_PREFIX,
+        env.euid()
+    ));
+    fs.create_new(&path, random_cookie().as_bytes(), 0o600)
+        .ok()?;
+
+    // Anyone can create a file in /tmp, only trust our own.
+    let attr = fs.symlink_attr(&path).ok()?;
+    if !attr.is_file() || attr.uid != env.euid() {
+        return None;
+    }
+    let cookie = fs.read_to_string(&path).ok()?;
+    let cookie = cookie.trim();
+    if cookie.is_empty() || !cookie.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("c{}", cookie))
+}
+
+// UUID[-seed-SEED][-ns-NAMESPACE], without looking at the environment or
+// /proc. Seeds which are empty or would add a path component are ignored.
+pub(crate) fn mount_dir_name(uuid: &str, seed: Option<&str>, namespace: Option<&str>) -> String {
+    let mut mount_directory = String::from(uuid);
+    if let Some(seed) = seed {
         if !seed.is_empty() && !seed.contains('/') {
             mount_directory = format!("{}-seed-{}", mount_directory, seed);
         }
     }
+    if let Some(namespace) = namespace {
+        mount_directory = format!("{}-ns-{}", mount_directory, namespace);
+    }
+    mount_directory
+}
+
+// Where `header`'s archive would be mounted for `uid` on another host, given
+// that host's namespace id, XAR_MOUNT_SEED and candidate mount roots, without
+// touching this one. The header's MOUNT_ROOT wins, otherwise the first root
+// is assumed to be usable there.
+pub fn mount_path_for(
+    header: &XarHeader,
+    naming: &NamingStrategy,
+    uid: u32,
+    ns_id: Option<&str>,
+    seed: Option<&str>,
+    roots: &[PathBuf],
+) -> Result<PathBuf, failure::Error> {
+    let root = match (&header.mount_root, roots.first()) {
+        (Some(root), _) => PathBuf::from(root),
+        (None, Some(root)) => root.clone(),
+        (None, None) => bail!("no mount root given and the header has no MOUNT_ROOT"),
+    };
+    let (dir, name) = naming.components(&Names {
+        uuid: &header.uuid,
+        uid,
+        seed,
+        ns: ns_id,
+    })?;
+    Ok(root.join(dir).join(name))
+}
+
+// The mount roots tried, in order, when the header doesn't name one.
+pub fn default_mount_roots() -> Vec<PathBuf> {
+    DEFAULT_MOUNT_ROOTS.iter().map(PathBuf::from).collect()
+}
+
+fn get_mount_dir(
+    uuid: &str,
+    naming: &NamingStrategy,
+    uid: Uid,
+    require_ns_id: bool,
+) -> Result<(String, String), failure::Error> {
+    get_mount_dir_in(uuid, naming, uid, require_ns_id, &System, &System)
+}
+
+// get_mount_dir, on the given host. Returns the directory under the mount
+// root and the mount's name in it.
+pub fn get_mount_dir_in(
+    uuid: &str,
+    naming: &NamingStrategy,
+    uid: Uid,
+    require_ns_id: bool,
+    fs: &dyn Fs,
+    env: &dyn Env,
+) -> Result<(String, String), failure::Error> {
+    // We optionally also take a user-specified "seed" from the environment.  We cannot rely
+    // purely on mount namespace as the kernel will aggressively re-use namespace IDs.
+    let seed = env.var(XAR_MOUNT_SEED);
 
-    // Determine our mount namespace id via the inode on /proc/self/ns/mnt
-    if let Ok(attr) = fs::metadata(PROC_MOUNT_NAMESPACE) {
-        mount_directory = format!("{}-ns-{}", mount_directory, attr.ino());
+    let namespace = match namespace_id(fs) {
+        _ if !naming.uses_namespace() => None,
+        Some(namespace) => Some(namespace),
+        None if require_ns_id => bail!(
+            "Unable to determine the mount namespace from {}, is /proc mounted?",
+            PROC_MOUNT_NAMESPACE
+        ),
+        None => namespace_cookie(fs, env),
+    };
+    naming.components(&Names {
+        uuid,
+        uid: uid.as_raw(),
+        seed: seed.as_deref(),
+        ns: namespace.as_deref(),
+    })
+}
+
+// Force the mode regardless of umask, then check both mode and ownership stuck.
+fn verify_directory(
+    fd: RawFd,
+    dir: &PathBuf,
+    mode: stat::Mode,
+    owner: Owner,
+) -> Result<(), failure::Error> {
+    stat::fchmod(fd, mode)?;
+    let attr = stat::fstat(fd)?;
+
+    let actual = u32::from(attr.st_mode) & 0o7777;
+    let expected = u32::from(mode.bits());
+    if actual != expected {
+        return Err(XarError::DirectoryMode {
+            path: dir.to_string_lossy().into_owned(),
+            mode: actual,
+            expected,
+        }
+        .into());
     }
 
-    mount_directory
+    let (uid, gid) = (owner.uid.as_raw(), owner.gid.as_raw());
+    if attr.st_uid != uid || attr.st_gid != gid {
+        return Err(XarError::DirectoryOwner {
+            path: dir.to_string_lossy().into_owned(),
+            uid: attr.st_uid,
+            gid: attr.st_gid,
+            expected_uid: uid,
+            expected_gid: gid,
+        }
+        .into());
+    }
+    Ok(())
 }
 
-fn create_directory(logger: &slog::Logger, dir: &PathBuf) -> Result<(), failure::Error> {
+// Returns whether `dir` was made here rather than found.
+fn create_directory(
+    logger: &slog::Logger,
+    dir: &PathBuf,
+    owner: Owner,
+) -> Result<bool, failure::Error> {
     let mode = stat::Mode::S_IRWXU
         | stat::Mode::S_IRGRP
         | stat::Mode::S_IXGRP
         | stat::Mode::S_IROTH
         | stat::Mode::S_IXOTH;
-    if !dir.exists() {
-        debug!(logger, "Creating directory"; "dir" => dir.to_str().unwrap());
+    if dir.exists() {
+        return Ok(false);
+    }
+    debug!(logger, "Creating directory"; "dir" => dir.display().to_string());
+
+    mkdir(dir, mode)?;
+    chown(dir, Some(owner.uid), Some(owner.gid))?;
 
+    let flag = fcntl::OFlag::O_RDONLY | fcntl::OFlag::O_DIRECTORY | fcntl::OFlag::O_CLOEXEC;
+    let fd = fcntl::open(dir, flag, stat::Mode::empty())?;
+    let verified = verify_directory(fd, dir, mode, owner);
+    close(fd)?;
+    verified?;
+    Ok(true)
+}
+
+// Like create_directory, but only the owner may enter it and an existing
+// directory is checked too, as it may be used for writing.
+pub(crate) fn create_private_directory(
+    logger: &slog::Logger,
+    dir: &PathBuf,
+) -> Result<(), failure::Error> {
+    let mode = stat::Mode::S_IRWXU;
+    if !dir.exists() {
+        debug!(logger, "Creating directory"; "dir" => dir.display().to_string());
         mkdir(dir, mode)?;
-        chown(dir, Some(geteuid()), Some(getegid()))?
     }
-    Ok(())
+    let flag = fcntl::OFlag::O_RDONLY
+        | fcntl::OFlag::O_DIRECTORY
+        | fcntl::OFlag::O_NOFOLLOW
+        | fcntl::OFlag::O_CLOEXEC;
+    let fd = fcntl::open(dir, flag, stat::Mode::empty())?;
+    let verified = verify_directory(fd, dir, mode, Owner::effective());
+    close(fd)?;
+    verified
+}
+
+// A path merely inside a FUSE filesystem shares its parent's device.
+fn is_mountpoint(path: &PathBuf) -> bool {
+    match (fs::metadata(path), path.parent().map(fs::metadata)) {
+        (Ok(attr), Some(Ok(parent))) => attr.dev() != parent.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn is_fuse_mount(path: &PathBuf) -> Result<bool, failure::Error> {
+    match statfs(path) {
+        Ok(stat) => match stat.filesystem_type_name() {
+            "osxfuse" | "osxfusefs" => Ok(is_mountpoint(path)),
+            _ => Ok(false),
+        },
+        Err(_) => Ok(false),
+    }
+}
+
+// FreeBSD identifies filesystems by name, fusefs for all FUSE mounts.
+#[cfg(target_os = "freebsd")]
+fn is_fuse_mount(path: &PathBuf) -> Result<bool, failure::Error> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::statfs(c_path.as_ptr(), &mut stat) };
+    if res != 0 {
+        return Ok(false);
+    }
+    let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Ok(name.to_bytes() == b"fusefs" && is_mountpoint(path))
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+fn is_fuse_mount(path: &PathBuf) -> Result<bool, failure::Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::statfs(c_path.as_ptr(), &mut stat) };
+    if res != 0 {
+        return Ok(false);
+    }
+    let f_type = stat.f_type as i64;
+    Ok((f_type == FUSE_SUPER_MAGIC || f_type == EROFS_SUPER_MAGIC_V1) && is_mountpoint(path))
+}
+
+fn plan_directory(steps: &mut Vec<String>, dir: &PathBuf, owner: Owner) {
+    if !dir.exists() {
+        steps.push(format!("mkdir -m 0755 {}", dir.display()));
+        steps.push(format!(
+            "chown {}:{} {}",
+            owner.uid,
+            owner.gid,
+            dir.display()
+        ));
+    }
 }
 
 impl Directory {
     pub fn from_xar(xar: &Xar, logger: slog::Logger) -> Result<Directory, failure::Error> {
-        // Path is <mount_root>/uid-N/UUID-ns-Y;
-        let mount_root = find_mount_root(&xar.header.mount_root)?;
-        let user_directory = get_user_basedir(geteuid());
-        let mount_directory = get_mount_dir(&xar.header.uuid);
-
-        let mut result = PathBuf::from(mount_root);
-        result.push(user_directory);
-        result.push(mount_directory);
+        // Path is <mount_root>/uid-N/UUID-ns-Y by default, or as the naming
+        // strategy has it, or <mount_root>/system/NAME for mounts shared by
+        // all users.
+        let naming = xar.naming()?;
+        let system = xar.mount_options.system || naming == NamingStrategy::Global;
+        let owner = if system {
+            Owner::effective()
+        } else {
+            Owner::invoking()
+        };
+        let root = if system {
+            system_root(&logger, &xar.header.mount_root)?
+        } else {
+            find_mount_root(&logger, &xar.header.mount_root)?
+        };
+        let require_ns_id = xar.mount_options.require_ns_id;
+        // Sections other than the main payload are UUID-SECTION.
+        let uuid = match &xar.mount_options.section {
+            Some(section) => format!("{}-{}", xar.header.uuid, section),
+            None => xar.header.uuid.clone(),
+        };
+        let (dir, mount_dir) = get_mount_dir(&uuid, &naming, owner.uid, require_ns_id)?;
+        let userdir = if system {
+            root.join(SYSTEM_BASEDIR)
+        } else {
+            root.join(dir)
+        };
+        if !xar.mount_options.versioned {
+            return Ok(Directory {
+                logger: logger,
+                path: userdir.join(mount_dir),
+                current: None,
+                owner,
+                system,
+                made: Made::default(),
+            });
+        }
 
+        // Versioned mounts are <mount_root>/uid-N/UUID-HASH-ns-Y, with
+        // UUID-ns-Y-current linking to the most recently mounted version.
+        let hash = xar.content_hash()?;
+        let versioned = format!("{}-{}", uuid, &hash[..VERSION_HASH_LEN]);
+        let (_, versioned_dir) = get_mount_dir(&versioned, &naming, owner.uid, require_ns_id)?;
         Ok(Directory {
             logger: logger,
-            path: result,
+            path: userdir.join(versioned_dir),
+            current: Some(userdir.join(format!("{}-current", mount_dir))),
+            owner,
+            system,
+            made: Made::default(),
+        })
+    }
+
+    // Mount at a caller chosen path instead of the uid/UUID/namespace scheme.
+    // The lockfile and other state still live beside it, in its parent.
+    pub fn at(path: &Path, logger: slog::Logger) -> Result<Directory, failure::Error> {
+        if !path.is_absolute() || path.parent().is_none() || path.file_name().is_none() {
+            bail!(
+                "Mountpoint {} must be an absolute path below /",
+                path.display()
+            );
+        }
+        Ok(Directory {
+            logger,
+            path: path.to_path_buf(),
+            current: None,
+            owner: Owner::invoking(),
+            system: false,
+            made: Made::default(),
         })
     }
 
     pub fn lock_and_mkdir(self: &Directory) -> Result<Lock, failure::Error> {
+        self.lock_and_mkdir_until(None)
+    }
+
+    pub fn lock_and_mkdir_until(
+        self: &Directory,
+        deadline: Option<Instant>,
+    ) -> Result<Lock, failure::Error> {
         let userdir = PathBuf::from(self.path.parent().unwrap());
-        create_directory(&self.logger, &userdir)?;
+        if create_directory(&self.logger, &userdir, self.owner)? {
+            self.made.userdir.store(true, Ordering::Relaxed);
+        }
+        schema::ensure(&self.logger, &userdir, self.owner)?;
 
-        let lock = Lock::directory(&self.path)?;
-        create_directory(&self.logger, &self.path)?;
+        let lock = Lock::directory_until(&self.logger, &self.path, deadline)?;
+        // Made by root under sudo, the invoking user must still be able to
+        // take the lock later.
+        if !self.owner.is_effective() {
+            chown(
+                &lockfile(&self.path),
+                Some(self.owner.uid),
+                Some(self.owner.gid),
+            )?;
+        }
+        if create_directory(&self.logger, &self.path, self.owner)? {
+            self.made.directory.store(true, Ordering::Relaxed);
+        }
 
         Ok(lock)
     }
 
-    #[cfg(target_os = "macos")]
-    pub fn is_mounted(self: &Directory) -> Result<bool, failure::Error> {
-        match statfs(&self.path) {
-            Ok(stat) => match stat.filesystem_type_name() {
-                "osxfuse" | "osxfusefs" => Ok(true),
-                _ => Ok(false),
-            },
-            Err(_) => Ok(false),
+    // Atomically point the `current` symlink at this mount, so new users
+    // switch to it while existing ones keep the version they resolved.
+    pub fn publish(self: &Directory) -> Result<(), failure::Error> {
+        let current = match &self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let mut name = OsString::from(".");
+        name.push(current.file_name().unwrap());
+        name.push(format!(".{}", std::process::id()));
+        let temporary = current.with_file_name(name);
+        let _ = fs::remove_file(&temporary);
+        std::os::unix::fs::symlink(self.path.file_name().unwrap(), &temporary)?;
+        fs::rename(&temporary, current)?;
+        debug!(
+            self.logger,
+            "Published";
+            "current" => current.display().to_string(),
+            "mount" => self.path.display().to_string()
+        );
+        Ok(())
+    }
+
+    // Pinned mounts are exempt from garbage collection, regardless of their lease.
+    pub fn pin(self: &Directory) -> Result<(), failure::Error> {
+        let _lock = self.lock_and_mkdir()?;
+        fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(pinfile(&self.path))?;
+        Ok(())
+    }
+
+    pub fn unpin(self: &Directory) -> Result<(), failure::Error> {
+        if !self.is_pinned() {
+            return Ok(());
+        }
+        let _lock = Lock::directory(&self.path)?;
+        match fs::remove_file(pinfile(&self.path)) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
         }
     }
 
-    #[cfg(not(target_os = "macos"))]
+    pub fn is_pinned(self: &Directory) -> bool {
+        pinfile(&self.path).exists()
+    }
+
+    // Describe the filesystem operations lock_and_mkdir would perform.
+    pub fn plan(self: &Directory) -> Vec<String> {
+        let mut steps = Vec::new();
+        let userdir = PathBuf::from(self.path.parent().unwrap());
+        plan_directory(&mut steps, &userdir, self.owner);
+        steps.push(format!("open {}", lockfile(&self.path).display()));
+        plan_directory(&mut steps, &self.path, self.owner);
+        steps
+    }
+
+    // Probed from a watchdog thread, statfs hangs along with a wedged daemon.
     pub fn is_mounted(self: &Directory) -> Result<bool, failure::Error> {
-        match statfs(&self.path) {
-            Ok(stat) => {
-                println!("{:?}", stat.filesystem_type());
-                Ok(false)
+        let path = self.path.clone();
+        watchdog::probe(&self.path, move || is_fuse_mount(&path))
+    }
+
+    // Abort the mount's FUSE connection, failing every pending and future
+    // request, then lazily detach it. This is the way out for a daemon that
+    // has hung and can no longer answer an ordinary unmount.
+    #[cfg(target_os = "linux")]
+    pub fn abort(self: &Directory) -> Result<(), failure::Error> {
+        let info = match mountinfo::find(&self.path)? {
+            Some(info) if info.fs_type.starts_with("fuse") => info,
+            _ => bail!("{} is not a FUSE mount", self.path.display()),
+        };
+
+        let _lock = Lock::directory(&self.path)?;
+        let abort = format!("{}/{}/abort", FUSE_CONNECTIONS, info.minor);
+        debug!(
+            self.logger,
+            "Aborting";
+            "mount" => self.path.display().to_string(),
+            "connection" => &abort
+        );
+        fs::write(&abort, "1")?;
+
+        let status = Command::new("fusermount")
+            .arg("-u")
+            .arg("-z")
+            .arg(&self.path)
+            .status()?;
+        if !status.success() {
+            match status.code() {
+                Some(code) => bail!("Unmount exited with status code: {}", code),
+                None => bail!("Unmount terminated by signal: {:?}", status.signal()),
             }
-            Err(_) => Ok(false),
         }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn abort(self: &Directory) -> Result<(), failure::Error> {
+        bail!("Aborting FUSE connections is only supported on Linux")
+    }
+
+    // A mount at our path which squashfuse didn't make, e.g. a bind mount or
+    // tmpfs, which mounting on top of would hide.
+    #[cfg(target_os = "linux")]
+    pub fn foreign_mount(self: &Directory) -> Result<Option<mountinfo::MountInfo>, failure::Error> {
+        Ok(mountinfo::find(&self.path)?.filter(|info| {
+            let ours = ["squashfuse", EROFSFUSE_BIN]
+                .iter()
+                .any(|name| info.fs_type.contains(name) || info.source.contains(name));
+            !((info.fs_type.starts_with("fuse") && ours) || info.fs_type == "erofs")
+        }))
+    }
+
+    // Refuse foreign mounts, or with `force` unmount them first.
+    #[cfg(target_os = "linux")]
+    pub fn check_foreign(self: &Directory, force: bool) -> Result<(), failure::Error> {
+        let info = match self.foreign_mount()? {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        if !force {
+            return Err(XarError::ForeignMount {
+                path: self.path.to_string_lossy().into_owned(),
+                fs_type: info.fs_type,
+                source: info.source,
+            }
+            .into());
+        }
+        warn!(
+            self.logger,
+            "Unmounting foreign mount";
+            "mount" => self.path.display().to_string(),
+            "fs_type" => &info.fs_type,
+            "source" => &info.source
+        );
+        let status = if info.fs_type.starts_with("fuse") {
+            Command::new("fusermount")
+                .arg("-u")
+                .arg(&self.path)
+                .status()?
+        } else {
+            Command::new("umount").arg(&self.path).status()?
+        };
+        if !status.success() {
+            bail!(
+                "Unable to unmount foreign mount on {}: {}",
+                self.path.display(),
+                status
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn check_foreign(self: &Directory, _force: bool) -> Result<(), failure::Error> {
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    pub fn unmount(self: &Directory) -> Result<(), failure::Error> {
+        self.run_unmount(Command::new("umount").arg(&self.path))
+    }
+
+    // Kernel EROFS mounts aren't FUSE, fusermount refuses them.
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+    pub fn unmount(self: &Directory) -> Result<(), failure::Error> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(info) = mountinfo::find(&self.path)? {
+                if info.fs_type == "erofs" {
+                    return self.run_unmount(Command::new("umount").arg(&self.path));
+                }
+            }
+        }
+        self.run_unmount(Command::new("fusermount").arg("-u").arg(&self.path))
+    }
+
+    fn run_unmount(self: &Directory, cmd: &mut Command) -> Result<(), failure::Error> {
+        if !self.is_mounted()? {
+            return Ok(());
+        }
+
+        let _lock = Lock::directory(&self.path)?;
+        debug!(self.logger, "Unmounting"; "mount" => self.path.display().to_string());
+        spawn::stdout_to_stderr(cmd)?;
+        let status = cmd.status()?;
+        if !status.success() {
+            match status.code() {
+                Some(code) => bail!("Unmount exited with status code: {}", code),
+                None => bail!("Unmount terminated by signal: {:?}", status.signal()),
+            }
+        }
+        events::emit(Event::Unmounted {
+            mount: self.path.clone(),
+        });
+        workspace::remove(&self.logger, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::{Free, MapEnv, MapFs};
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn host(uid: u32) -> (MapFs, MapEnv) {
+        let fs = MapFs {
+            uid,
+            free: Free {
+                bytes: 1 << 30,
+                inodes: 1 << 20,
+                total_inodes: 1 << 20,
+            },
+            ..MapFs::default()
+        };
+        let mut env = MapEnv {
+            uid,
+            ..MapEnv::default()
+        };
+        env.vars
+            .insert(PRIVATE_ROOT_VAR.to_string(), "/run/user/1000".to_string());
+        (fs, env)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn first_shared_root_wins() {
+        let (fs, env) = host(1000);
+        fs.add_dir("/mnt/xarfuse", 0, 0o755);
+        fs.add_dir("/dev/shm", 0, 0o1777);
+        let root = find_mount_root_in(&logger(), &None, &fs, &env).unwrap();
+        assert_eq!(root, Path::new("/dev/shm"));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn private_root_belongs_to_the_invoking_user() {
+        let (fs, env) = host(1000);
+        fs.add_dir("/run/user/1000", 1000, 0o700);
+        let root = find_mount_root_in(&logger(), &None, &fs, &env).unwrap();
+        assert_eq!(root, Path::new("/run/user/1000/xarfuse"));
+
+        let (fs, env) = host(1001);
+        fs.add_dir("/run/user/1000", 1000, 0o700);
+        assert!(find_mount_root_in(&logger(), &None, &fs, &env).is_err());
+    }
+
+    #[test]
+    fn read_only_roots_are_skipped() {
+        let (mut fs, env) = host(1000);
+        fs.read_only = true;
+        fs.add_dir("/mnt/xar", 0, 0o1777);
+        let mount_root = Some("/mnt/xar".to_string());
+        assert!(find_mount_root_in(&logger(), &mount_root, &fs, &env).is_err());
+    }
+
+    #[cfg(not(target_os = "freebsd"))]
+    #[test]
+    fn cookie_stands_in_for_the_namespace() {
+        let (fs, env) = host(1000);
+        let naming = NamingStrategy::UidNs;
+        let uid = Uid::from_raw(1000);
+        let (_, first) = get_mount_dir_in("abc", &naming, uid, false, &fs, &env).unwrap();
+        let (_, second) = get_mount_dir_in("abc", &naming, uid, false, &fs, &env).unwrap();
+        assert!(first.starts_with("abc-ns-c"), "{}", first);
+        assert_eq!(first, second);
+        assert!(get_mount_dir_in("abc", &naming, uid, true, &fs, &env).is_err());
+    }
+
+    #[cfg(not(target_os = "freebsd"))]
+    #[test]
+    fn cookies_of_others_are_ignored() {
+        let (fs, env) = host(1000);
+        let cookie = format!("{}/{}1000", NAMESPACE_COOKIE_DIR, NAMESPACE_COOKIE_PREFIX);
+        let planted = Attr {
+            uid: 1001,
+            mode: libc::S_IFREG as u32 | 0o600,
+            ..Attr::default()
+        };
+        fs.attrs
+            .lock()
+            .unwrap()
+            .insert(PathBuf::from(cookie), planted);
+        let (_, name) = get_mount_dir_in(
+            "abc",
+            &NamingStrategy::UidNs,
+            Uid::from_raw(1000),
+            false,
+            &fs,
+            &env,
+        )
+        .unwrap();
+        assert_eq!(name, "abc");
     }
 }
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+// Mount lifecycle changes made by this process, for services embedding the
+// library which keep their own registry of mounts rather than polling `list`.
+#[derive(Clone, Debug)]
+pub enum Event {
+    // This process spawned the mounter and the mount became ready.
+    Mounted {
+        mount: PathBuf,
+        archive: PathBuf,
+        uuid: String,
+    },
+    Unmounted {
+        mount: PathBuf,
+    },
+    // A mount this process attempted failed, including by timing out waiting
+    // for the lock or the mounter, a cached failure or a policy refusal.
+    Failed {
+        mount: PathBuf,
+        archive: PathBuf,
+        error: String,
+    },
+    // repair removed or unmounted something left behind, or a mount was
+    // abandoned or consolidated away.
+    Reaped {
+        path: PathBuf,
+        problem: &'static str,
+        action: String,
+    },
+}
+
+static SUBSCRIBERS: Mutex<Vec<Sender<Event>>> = Mutex::new(Vec::new());
+
+// Receive every event from now on. Dropping the receiver unsubscribes.
+pub fn subscribe() -> Receiver<Event> {
+    let (sender, receiver) = channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+// Subscribers are never waited on, and those gone are forgotten.
+pub(crate) fn emit(event: Event) {
+    let mut subscribers = match SUBSCRIBERS.lock() {
+        Ok(subscribers) => subscribers,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
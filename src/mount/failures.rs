@@ -0,0 +1,64 @@
+use crate::mount::lock::failfile;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Mounts which failed within this many seconds fail again immediately with the
+// recorded error, rather than spawning squashfuse and waiting on it.
+const XAR_FAILURE_COOLDOWN: &str = "XAR_FAILURE_COOLDOWN";
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+
+fn cooldown() -> Duration {
+    env::var(XAR_FAILURE_COOLDOWN)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_COOLDOWN)
+}
+
+// Failures are recorded as `failed=<secs since epoch>` and `error=<message>`.
+pub fn check(mount: &PathBuf) -> Result<(), failure::Error> {
+    let contents = match fs::read_to_string(failfile(mount)) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(()),
+    };
+    let mut failed = None;
+    let mut error = None;
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("failed"), Some(value)) => failed = value.parse().ok(),
+            (Some("error"), Some(value)) => error = Some(value),
+            _ => (),
+        }
+    }
+    if let (Some(failed), Some(error)) = (failed, error) {
+        let failed = UNIX_EPOCH + Duration::from_secs(failed);
+        let age = SystemTime::now().duration_since(failed).unwrap_or_default();
+        let cooldown = cooldown();
+        if age < cooldown {
+            bail!(
+                "{} (cached failure, retrying in {}s)",
+                error,
+                (cooldown - age).as_secs()
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn record(mount: &PathBuf, error: &failure::Error) -> Result<(), failure::Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let message = error.to_string().replace('\n', " ");
+    fs::write(
+        failfile(mount),
+        format!("failed={}\nerror={}\n", now.as_secs(), message),
+    )?;
+    Ok(())
+}
+
+pub fn clear(mount: &PathBuf) {
+    let _ = fs::remove_file(failfile(mount));
+}
@@ -0,0 +1,76 @@
+use crate::mount::directory::Directory;
+use crate::mount::lock::{lockfile, Lock};
+use crate::xar::Xar;
+
+use std::fs;
+use std::path::Path;
+
+// Keeps an archive mounted for as long as it's alive. Dropping it, including
+// while unwinding from a panic, unmounts the archive and removes the mount
+// directory and its lockfile, unless it was created with `unmount` unset or
+// has been leaked.
+pub struct MountGuard {
+    logger: slog::Logger,
+    mount: Option<Directory>,
+    unmount: bool,
+}
+
+impl MountGuard {
+    pub fn path(&self) -> &Path {
+        &self.directory().path
+    }
+
+    pub fn directory(&self) -> &Directory {
+        self.mount.as_ref().unwrap()
+    }
+
+    // Leave the archive mounted when the guard goes away.
+    pub fn leak(mut self) -> Directory {
+        self.mount.take().unwrap()
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        let mount = match self.mount.take() {
+            Some(mount) if self.unmount => mount,
+            _ => return,
+        };
+        if let Err(e) = mount.unmount() {
+            warn!(
+                self.logger,
+                "Unable to unmount";
+                "mount" => mount.path.display().to_string(),
+                "error" => e.to_string()
+            );
+            return;
+        }
+        // Someone may have mounted again since, in which case the directory
+        // isn't empty and it and its lockfile stay.
+        let _lock = match Lock::directory(&mount.path) {
+            Ok(lock) => lock,
+            Err(_) => return,
+        };
+        if fs::remove_dir(&mount.path).is_ok() {
+            let _ = fs::remove_file(lockfile(&mount.path));
+        }
+    }
+}
+
+impl Xar {
+    // Mount the archive, returning a guard which unmounts it when dropped if
+    // `unmount` is set. Mounts are shared by every process using the same
+    // archive, so only set it when nothing else should be using the mount.
+    pub fn mount_guarded(
+        &self,
+        mount: Directory,
+        unmount: bool,
+    ) -> Result<MountGuard, failure::Error> {
+        self.mount(&mount)?;
+        Ok(MountGuard {
+            logger: self.logger.clone(),
+            mount: Some(mount),
+            unmount,
+        })
+    }
+}
@@ -0,0 +1,78 @@
+use crate::mount::directory::Directory;
+use crate::mount::resolve_in_mount;
+use crate::mount::spawn;
+use crate::mount::workspace;
+use crate::xar::Xar;
+
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+
+// Hooks only see a minimal environment.
+const HOOK_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+// Run the hook with its own user and network namespaces, so it has no
+// network access.
+#[cfg(target_os = "linux")]
+fn sandbox(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sandbox(_cmd: &mut Command) {}
+
+impl Xar {
+    pub fn run_post_mount_hook(&self, mount: &Directory) -> Result<(), failure::Error> {
+        let hook = match &self.header.post_mount_hook {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+        if !cfg!(target_os = "linux") {
+            bail!("POST_MOUNT_HOOK requires a sandbox, which is only supported on Linux");
+        }
+        // The hook would keep root's uid inside its user namespace, and with
+        // it owner access to root's files, on behalf of a user's archive.
+        if !mount.owner.is_effective() {
+            bail!(
+                "POST_MOUNT_HOOK isn't run under sudo, mount {} as {} instead",
+                self.archive.display(),
+                mount.owner.uid
+            );
+        }
+
+        let path = resolve_in_mount(mount, "POST_MOUNT_HOOK", hook)?;
+        debug!(
+            self.logger,
+            "Running post mount hook";
+            "hook" => path.display().to_string()
+        );
+        let workspace = workspace::allocate(&self.logger, &mount.path)?;
+        let mut cmd = Command::new(&path);
+        cmd.arg(&mount.path)
+            .current_dir(&mount.path)
+            .env_clear()
+            .env("PATH", HOOK_PATH)
+            .env(workspace::XAR_WORKSPACE, &workspace);
+        sandbox(&mut cmd);
+        spawn::stdout_to_stderr(&mut cmd)?;
+
+        let status = cmd.status()?;
+        if !status.success() {
+            match status.code() {
+                Some(code) => bail!("POST_MOUNT_HOOK exited with status code: {}", code),
+                None => bail!(
+                    "POST_MOUNT_HOOK terminated by signal: {:?}",
+                    status.signal()
+                ),
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,37 +1,710 @@
+use crate::compat;
+use crate::error::XarError;
+
+use nix::errno::Errno;
 use nix::fcntl;
+use nix::sys::signal::kill;
 use nix::sys::stat;
-use std::os::unix::io::RawFd;
-use std::path::PathBuf;
+use nix::unistd::{self, Pid};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Lease assumed for lockfiles written without one, matching the fuse idle timeout.
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(870);
+
+// Touches of a lockfile modified more recently than this are skipped.
+const XAR_LOCK_DEBOUNCE: &str = "XAR_LOCK_DEBOUNCE";
+const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(10);
+
+const PROC_BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+// How often a lock with a deadline is retried.
+const LOCK_POLL: Duration = Duration::from_millis(10);
+// How long a held marker may go without a pid before its writer is presumed
+// to have died between creating and writing it.
+const MARKER_GRACE: Duration = Duration::from_secs(5);
+// Selects how lockfiles are locked, see Backend.
+const XAR_LOCK_BACKEND: &str = "XAR_LOCK_BACKEND";
+// Seconds to wait for a lock when the caller sets no deadline of its own.
+const XAR_LOCK_TIMEOUT: &str = "XAR_LOCK_TIMEOUT";
+// From linux/magic.h
+#[cfg(target_os = "linux")]
+const NFS_SUPER_MAGIC: i64 = 0x6969;
+
+// How a lock on the lockfile is taken. flock is the default and the only one
+// xarexec_fuse honors. On NFS, where flock is emulated with byte-range locks,
+// open file description locks are used instead when the kernel has them.
+// Elsewhere they don't exclude flock holders at all, so they're refused.
+// `lockfile` creates held.<mount dir> exclusively, for filesystems with no
+// working locks; a holder which dies leaves it behind until a waiter finds
+// the recorded pid gone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Flock,
+    Ofd,
+    Lockfile,
+}
+
+impl FromStr for Backend {
+    type Err = failure::Error;
+
+    fn from_str(value: &str) -> Result<Backend, failure::Error> {
+        match value {
+            "flock" => Ok(Backend::Flock),
+            "ofd" => Ok(Backend::Ofd),
+            "lockfile" => Ok(Backend::Lockfile),
+            _ => bail!(
+                "{} {} must be flock, ofd or lockfile",
+                XAR_LOCK_BACKEND,
+                value
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Backend::Flock => "flock",
+            Backend::Ofd => "ofd",
+            Backend::Lockfile => "lockfile",
+        };
+        write!(f, "{}", name)
+    }
+}
 
 pub struct Lock {
     fd: RawFd,
+    debounce: Duration,
+    // held.<mount dir>, written once the lock is ours and removed on drop.
+    marker: Option<PathBuf>,
+}
+
+// Besides the wall clock, leases record which boot they were taken in and
+// the time since that boot, which is immune to clock steps.
+struct Lease {
+    duration: Duration,
+    renewed: SystemTime,
+    boot_id: Option<String>,
+    boottime: Option<Duration>,
+}
+
+fn named(mount: &PathBuf, kind: &str) -> PathBuf {
+    let mut name = OsString::from(kind);
+    name.push(".");
+    name.push(mount.file_name().unwrap());
+    mount.with_file_name(name)
+}
+
+// State files live beside the mount directory as <kind>.<mount dir>. Kinds
+// must not collide with names xarexec_fuse uses in the same directory.
+pub(crate) fn state_file(mount: &PathBuf, kind: &str) -> PathBuf {
+    debug_assert!(
+        !compat::is_reserved_kind(kind),
+        "reserved state kind {}",
+        kind
+    );
+    named(mount, kind)
+}
+
+// Shared with xarexec_fuse, which takes the same flock.
+pub fn lockfile(mount: &PathBuf) -> PathBuf {
+    named(mount, compat::LEGACY_LOCK_KIND)
+}
+
+pub fn pinfile(mount: &PathBuf) -> PathBuf {
+    state_file(mount, "pinned")
+}
+
+pub fn failfile(mount: &PathBuf) -> PathBuf {
+    state_file(mount, "failed")
+}
+
+pub fn originfile(mount: &PathBuf) -> PathBuf {
+    state_file(mount, "origin")
+}
+
+// The lock itself under the lockfile backend, recording who holds it.
+pub fn heldfile(mount: &PathBuf) -> PathBuf {
+    state_file(mount, "held")
+}
+
+// Who holds a flock or OFD lock on a mount and since when, for diagnostics.
+// Kept apart from heldfile, which peers on the lockfile backend would take
+// for their lock.
+pub fn holderfile(mount: &PathBuf) -> PathBuf {
+    state_file(mount, "holder")
+}
+
+#[cfg(target_os = "linux")]
+fn is_nfs(fd: RawFd) -> bool {
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::fstatfs(fd, &mut stat) };
+    res == 0 && stat.f_type as i64 == NFS_SUPER_MAGIC
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_nfs(_fd: RawFd) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn whole_file(lock_type: libc::c_int) -> libc::flock {
+    libc::flock {
+        l_type: lock_type as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: 0,
+        l_len: 0,
+        l_pid: 0,
+    }
+}
+
+// Open file description locks, Linux 3.15 on, are owned by the description
+// like flock, but are byte-range locks which conflict with flock only on NFS.
+#[cfg(target_os = "linux")]
+fn supports_ofd(fd: RawFd) -> bool {
+    let mut probe = whole_file(libc::F_WRLCK);
+    unsafe { libc::fcntl(fd, libc::F_OFD_GETLK, &mut probe) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn supports_ofd(_fd: RawFd) -> bool {
+    false
+}
+
+// A conflicting lock is reported as EWOULDBLOCK, as for flock.
+#[cfg(target_os = "linux")]
+fn ofd_lock(fd: RawFd, wait: bool) -> nix::Result<()> {
+    let lock = whole_file(libc::F_WRLCK);
+    let command = if wait {
+        libc::F_OFD_SETLKW
+    } else {
+        libc::F_OFD_SETLK
+    };
+    match Errno::result(unsafe { libc::fcntl(fd, command, &lock) }) {
+        Ok(_) => Ok(()),
+        Err(nix::Error::Sys(Errno::EACCES)) => Err(nix::Error::Sys(Errno::EWOULDBLOCK)),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ofd_lock(_fd: RawFd, _wait: bool) -> nix::Result<()> {
+    Err(nix::Error::Sys(Errno::EINVAL))
+}
+
+// The backend for a lockfile open on `fd`: XAR_LOCK_BACKEND if set, else
+// by filesystem, with OFD locks falling back to flock where unsupported.
+fn backend(fd: RawFd) -> Result<Backend, failure::Error> {
+    let backend = match env::var(XAR_LOCK_BACKEND) {
+        Ok(value) => value.parse()?,
+        Err(_) if is_nfs(fd) => Backend::Ofd,
+        Err(_) => Backend::Flock,
+    };
+    if backend == Backend::Ofd && !is_nfs(fd) {
+        bail!(
+            "{} ofd doesn't exclude flock holders such as xarexec_fuse off NFS",
+            XAR_LOCK_BACKEND
+        );
+    }
+    if backend == Backend::Ofd && !supports_ofd(fd) {
+        return Ok(Backend::Flock);
+    }
+    Ok(backend)
+}
+
+fn default_deadline() -> Option<Instant> {
+    env::var(XAR_LOCK_TIMEOUT)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(|secs| Instant::now() + Duration::from_secs(secs))
+}
+
+// Markers record the holder as `pid=<pid>` and `since=<secs since epoch>`.
+fn read_marker(marker: &Path) -> (Option<i32>, Option<SystemTime>) {
+    let contents = fs::read_to_string(marker).unwrap_or_default();
+    let mut pid = None;
+    let mut since = None;
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("pid"), Some(value)) => pid = value.parse().ok(),
+            (Some("since"), Some(value)) => {
+                since = value
+                    .parse()
+                    .ok()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            }
+            _ => (),
+        }
+    }
+    (pid, since)
+}
+
+fn marker_pid(marker: &Path) -> Option<i32> {
+    read_marker(marker).0
+}
+
+fn marker_contents() -> String {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("pid={}\nsince={}\n", std::process::id(), since.as_secs())
+}
+
+// Who holds a lock, for diagnosing contention.
+pub struct Holder {
+    pub pid: i32,
+    pub comm: Option<String>,
+    // Known when the holder recorded when it took the lock.
+    pub held: Option<Duration>,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pid {}", self.pid)?;
+        if let Some(comm) = &self.comm {
+            write!(f, " (comm: {})", comm)?;
+        }
+        if let Some(held) = self.held {
+            write!(f, " for {}s", held.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+// The pid with a write lock on `lockfile` in /proc/locks, whose entries look
+// like `1: FLOCK  ADVISORY  WRITE 1234 00:2e:5678 0 EOF`, with the device in
+// hex. Waiters are listed with `->` and skipped, as are OFD locks, which have
+// no pid.
+#[cfg(target_os = "linux")]
+fn locking_pid(lockfile: &Path) -> Option<i32> {
+    let attr = stat::stat(lockfile).ok()?;
+    let id = format!(
+        "{:02x}:{:02x}:{}",
+        stat::major(attr.st_dev),
+        stat::minor(attr.st_dev),
+        attr.st_ino
+    );
+    fs::read_to_string("/proc/locks")
+        .ok()?
+        .lines()
+        .filter(|line| !line.contains("->"))
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 5 && fields[3] == "WRITE" && fields[5] == id)
+        .filter_map(|fields| fields[4].parse().ok())
+        .find(|pid| *pid > 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn locking_pid(_lockfile: &Path) -> Option<i32> {
+    None
+}
+
+// Who holds the lock on `mount`: the pid the kernel reports, or else the one
+// recorded in holder.<mount dir> or held.<mount dir> if it's still running.
+pub fn holder(mount: &PathBuf) -> Option<Holder> {
+    let (recorded, since) = match read_marker(&holderfile(mount)) {
+        (None, _) => read_marker(&heldfile(mount)),
+        recorded => recorded,
+    };
+    let pid = match locking_pid(&lockfile(mount)) {
+        Some(pid) => pid,
+        None => recorded.filter(|pid| kill(Pid::from_raw(*pid), None).is_ok())?,
+    };
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|comm| comm.trim().to_string());
+    let held = match since {
+        Some(since) if recorded == Some(pid) => SystemTime::now().duration_since(since).ok(),
+        _ => None,
+    };
+    Some(Holder { pid, comm, held })
+}
+
+// A marker whose pid is gone was left by a holder which died. One still
+// being written has no pid yet, and counts as held for MARKER_GRACE.
+fn is_stale(marker: &Path) -> bool {
+    match marker_pid(marker) {
+        Some(pid) => matches!(
+            kill(Pid::from_raw(pid), None),
+            Err(nix::Error::Sys(Errno::ESRCH))
+        ),
+        None => fs::metadata(marker)
+            .and_then(|attr| attr.modified())
+            .map(|modified| modified.elapsed().unwrap_or_default() > MARKER_GRACE)
+            .unwrap_or(false),
+    }
+}
+
+fn contended(mount: &PathBuf, waited: Duration) -> failure::Error {
+    let mut phase = format!(
+        "after {}s waiting for the lock on {}",
+        waited.as_secs(),
+        lockfile(mount).display()
+    );
+    if let Some(holder) = holder(mount) {
+        phase.push_str(&format!(", held by {}", holder));
+    }
+    XarError::Timeout { phase }.into()
+}
+
+// Lease records are stored in the lockfile as `key=value` lines.
+fn parse_lease(contents: &str) -> Option<Lease> {
+    let mut duration = None;
+    let mut renewed = None;
+    let mut boot_id = None;
+    let mut boottime = None;
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("lease"), Some(value)) => duration = value.parse().ok(),
+            (Some("renewed"), Some(value)) => renewed = value.parse().ok(),
+            (Some("boot_id"), Some(value)) => boot_id = Some(value.to_string()),
+            (Some("boottime"), Some(value)) => boottime = value.parse().ok(),
+            _ => (),
+        }
+    }
+    match (duration, renewed) {
+        (Some(duration), Some(renewed)) => Some(Lease {
+            duration: Duration::from_secs(duration),
+            renewed: UNIX_EPOCH + Duration::from_secs(renewed),
+            boot_id,
+            boottime: boottime.map(Duration::from_secs),
+        }),
+        _ => None,
+    }
+}
+
+fn boot_id() -> Option<String> {
+    fs::read_to_string(PROC_BOOT_ID)
+        .ok()
+        .map(|id| id.trim().to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn boottime() -> Option<Duration> {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut now) } != 0 {
+        return None;
+    }
+    Some(Duration::new(now.tv_sec as u64, now.tv_nsec as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn boottime() -> Option<Duration> {
+    None
+}
+
+// A lockfile's mtime plus DEFAULT_LEASE, or expired if it was last touched
+// in a previous boot.
+fn touch_expired(modified: SystemTime) -> bool {
+    let now = SystemTime::now();
+    if let Some(since_boot) = boottime() {
+        if modified + since_boot < now {
+            return true;
+        }
+    }
+    now > modified + DEFAULT_LEASE
+}
+
+// Leases taken in a previous boot have always expired. Within the same boot
+// expiry is measured on the boot clock, so wall clock steps neither reap live
+// mounts nor keep dead ones. A lockfile without a lease record, as written by
+// xarfuse before leases or by xarexec_fuse, falls back to its mtime plus
+// DEFAULT_LEASE. So does one touched since its lease was renewed, since
+// xarexec_fuse keeps mounts it's using alive by touching alone.
+fn expired(lease: Option<Lease>, modified: SystemTime) -> bool {
+    let now = SystemTime::now();
+    let lease = match lease {
+        Some(lease) => lease,
+        None => return touch_expired(modified),
+    };
+    if modified > lease.renewed + Duration::from_secs(1) && !touch_expired(modified) {
+        return false;
+    }
+
+    match (&lease.boot_id, lease.boottime, boot_id(), boottime()) {
+        (Some(renewed_boot), Some(renewed_at), Some(current_boot), Some(current)) => {
+            if *renewed_boot != current_boot {
+                return true;
+            }
+            let elapsed = current.checked_sub(renewed_at).unwrap_or_default();
+            elapsed > lease.duration
+        }
+        _ => now > lease.renewed + lease.duration,
+    }
+}
+
+// Whether a lockfile's lease has expired, read without taking the lock. For
+// reporting only, the answer may be out of date by the time it's used.
+pub fn is_expired_at(lockfile: &Path) -> Result<bool, failure::Error> {
+    let contents = fs::read(lockfile)?;
+    let modified = fs::metadata(lockfile)?.modified()?;
+    Ok(expired(
+        parse_lease(&String::from_utf8_lossy(&contents)),
+        modified,
+    ))
+}
+
+fn debounce() -> Duration {
+    env::var(XAR_LOCK_DEBOUNCE)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_DEBOUNCE)
 }
 
 impl Lock {
+    #[cfg_attr(feature = "tracing", tracing::instrument)]
     pub fn directory(mount: &PathBuf) -> Result<Lock, failure::Error> {
-        let mount_dir = mount.file_name().unwrap();
-        let mut lockfile = PathBuf::from(mount.parent().unwrap());
-        lockfile.push(format!("lockfile.{}", mount_dir.to_str().unwrap()));
+        let logger = slog::Logger::root(slog::Discard, o!());
+        Lock::directory_until(&logger, mount, None)
+    }
+
+    // Like directory, but give up at `deadline`, or XAR_LOCK_TIMEOUT from now
+    // if there's none. Who we're waiting on is logged once we have to wait.
+    pub fn directory_until(
+        logger: &slog::Logger,
+        mount: &PathBuf,
+        deadline: Option<Instant>,
+    ) -> Result<Lock, failure::Error> {
+        let lockfile = lockfile(mount);
         let flag = fcntl::OFlag::O_RDWR | fcntl::OFlag::O_CREAT | fcntl::OFlag::O_CLOEXEC;
         let mode = stat::Mode::S_IRUSR | stat::Mode::S_IWUSR;
+        let deadline = deadline.or_else(default_deadline);
 
-        let fd = fcntl::open(&lockfile, flag, mode)?;
-        Ok(Lock { fd: fd })
+        // repair may unlink the lockfile while we wait on it, in which case
+        // the lock we were granted no longer excludes anyone.
+        loop {
+            let fd = fcntl::open(&lockfile, flag, mode)?;
+            let mut lock = Lock {
+                fd: fd,
+                debounce: debounce(),
+                marker: None,
+            };
+            let backend = backend(lock.fd)?;
+            lock.acquire(logger, backend, deadline, mount)?;
+            let held = stat::fstat(lock.fd)?;
+            match stat::stat(&lockfile) {
+                Ok(current) if current.st_dev == held.st_dev && current.st_ino == held.st_ino => {
+                    return Ok(lock)
+                }
+                _ => continue,
+            }
+        }
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn touch(self: &Lock) -> Result<(), failure::Error> {
-        use nix::sys::time::TimeSpec;
-        let now = TimeSpec::utime_now();
-        stat::futimens(self.fd, &now, &now)?;
+    fn try_acquire(self: &Lock, backend: Backend) -> nix::Result<()> {
+        match backend {
+            Backend::Ofd => ofd_lock(self.fd, false),
+            _ => fcntl::flock(self.fd, fcntl::FlockArg::LockExclusiveNonblock),
+        }
+    }
+
+    fn acquire(
+        self: &mut Lock,
+        logger: &slog::Logger,
+        backend: Backend,
+        deadline: Option<Instant>,
+        mount: &PathBuf,
+    ) -> Result<(), failure::Error> {
+        if backend == Backend::Lockfile {
+            self.marker = Some(take_marker(logger, mount, deadline)?);
+            return Ok(());
+        }
+        let started = Instant::now();
+        let mut waiting = false;
+        loop {
+            match self.try_acquire(backend) {
+                Err(nix::Error::Sys(Errno::EWOULDBLOCK)) => (),
+                result => break result?,
+            }
+            if !waiting {
+                waiting = true;
+                log_waiting(logger, mount);
+            }
+            match deadline {
+                Some(deadline) if Instant::now() >= deadline => {
+                    return Err(contended(mount, started.elapsed()))
+                }
+                Some(_) => thread::sleep(LOCK_POLL),
+                None => {
+                    match backend {
+                        Backend::Ofd => ofd_lock(self.fd, true)?,
+                        _ => fcntl::flock(self.fd, fcntl::FlockArg::LockExclusive)?,
+                    }
+                    break;
+                }
+            }
+        }
+
+        // Only for diagnostics, so failing to record ourselves doesn't matter.
+        let marker = holderfile(mount);
+        if fs::write(&marker, marker_contents()).is_ok() {
+            self.marker = Some(marker);
+        }
         Ok(())
     }
 
-    #[cfg(target_os = "macos")]
+    // Record a lease of `duration` starting now, and touch the lockfile for
+    // readers which only understand mtimes.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
+    pub fn renew(self: &Lock, duration: Duration) -> Result<(), failure::Error> {
+        if self.is_fresh()? {
+            if let Some(lease) = self.lease()? {
+                if lease.duration == duration {
+                    return Ok(());
+                }
+            }
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let mut contents = format!("lease={}\nrenewed={}\n", duration.as_secs(), now.as_secs());
+        if let (Some(boot_id), Some(boottime)) = (boot_id(), boottime()) {
+            contents.push_str(&format!(
+                "boot_id={}\nboottime={}\n",
+                boot_id,
+                boottime.as_secs()
+            ));
+        }
+        unistd::ftruncate(self.fd, 0)?;
+        unistd::lseek(self.fd, 0, unistd::Whence::SeekSet)?;
+        unistd::write(self.fd, contents.as_bytes())?;
+        self.touch_now()
+    }
+
+    pub fn is_expired(self: &Lock) -> Result<bool, failure::Error> {
+        Ok(expired(self.lease()?, self.modified()?))
+    }
+
+    fn modified(self: &Lock) -> Result<SystemTime, failure::Error> {
+        let attr = stat::fstat(self.fd)?;
+        Ok(UNIX_EPOCH + Duration::from_secs(attr.st_mtime as u64))
+    }
+
+    fn is_fresh(self: &Lock) -> Result<bool, failure::Error> {
+        let age = SystemTime::now()
+            .duration_since(self.modified()?)
+            .unwrap_or_default();
+        Ok(age < self.debounce)
+    }
+
     pub fn touch(self: &Lock) -> Result<(), failure::Error> {
-        use nix::errno::Errno;
-        let res = unsafe { libc::futimes(self.fd, std::ptr::null()) };
-        Errno::result(res)?;
-        Ok(())
+        if self.is_fresh()? {
+            return Ok(());
+        }
+        self.touch_now()
+    }
+
+    fn lease(self: &Lock) -> Result<Option<Lease>, failure::Error> {
+        let mut buffer = [0; 256];
+        unistd::lseek(self.fd, 0, unistd::Whence::SeekSet)?;
+        let read = unistd::read(self.fd, &mut buffer)?;
+        Ok(parse_lease(&String::from_utf8_lossy(&buffer[..read])))
+    }
+
+    fn touch_now(self: &Lock) -> Result<(), failure::Error> {
+        touch_fd(self.fd)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn touch_fd(fd: RawFd) -> Result<(), failure::Error> {
+    use nix::sys::time::TimeSpec;
+    let now = TimeSpec::utime_now();
+    stat::futimens(fd, &now, &now)?;
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn touch_fd(fd: RawFd) -> Result<(), failure::Error> {
+    let res = unsafe { libc::futimes(fd, std::ptr::null()) };
+    Errno::result(res)?;
+    Ok(())
+}
+
+// Touch the lockfile of a live mount without locking it, keeping the mount
+// from expiring for DEFAULT_LEASE past its lease as xarexec_fuse's touches
+// do. Debounced like Lock::touch, so it's cheap enough for every use.
+pub fn touch_unlocked(mount: &PathBuf) -> Result<(), failure::Error> {
+    let lockfile = lockfile(mount);
+    let age = fs::metadata(&lockfile)?
+        .modified()?
+        .elapsed()
+        .unwrap_or_default();
+    if age < debounce() {
+        return Ok(());
+    }
+    let file = OpenOptions::new().write(true).open(&lockfile)?;
+    touch_fd(file.as_raw_fd())
+}
+
+fn log_waiting(logger: &slog::Logger, mount: &PathBuf) {
+    let holder = match holder(mount) {
+        Some(holder) => holder.to_string(),
+        None => "unknown".to_string(),
+    };
+    info!(logger, "Waiting for lock"; "lockfile" => lockfile(mount).display().to_string(), "holder" => holder);
+}
+
+// Create held.<mount dir> recording our pid, waiting while a live holder
+// has it. Two waiters breaking the same stale marker can race, which is
+// why this backend is only for filesystems where nothing better works.
+fn take_marker(
+    logger: &slog::Logger,
+    mount: &PathBuf,
+    deadline: Option<Instant>,
+) -> Result<PathBuf, failure::Error> {
+    let marker = heldfile(mount);
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&marker);
+        match created {
+            Ok(mut file) => {
+                file.write_all(marker_contents().as_bytes())?;
+                return Ok(marker);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if is_stale(&marker) {
+                    let _ = fs::remove_file(&marker);
+                    continue;
+                }
+                if !waiting {
+                    waiting = true;
+                    log_waiting(logger, mount);
+                }
+                if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                    return Err(contended(mount, started.elapsed()));
+                }
+                thread::sleep(LOCK_POLL);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Some(marker) = &self.marker {
+            let _ = fs::remove_file(marker);
+        }
+        let _ = unistd::close(self.fd);
     }
 }
@@ -1,66 +1,663 @@
 extern crate failure;
 
+pub mod access;
+pub mod batch;
+pub mod capabilities;
+pub mod cgroup;
+pub mod cleanup;
+pub mod daemons;
 pub mod directory;
+pub mod events;
+pub mod failures;
+pub mod guard;
+pub mod hook;
 pub mod lock;
+pub mod mounter;
+pub mod mountinfo;
+pub mod naming;
+pub mod origin;
+pub mod owner;
+pub mod permission;
+pub mod procs;
+pub mod repair;
+pub mod rlimit;
+pub mod run;
+pub mod schema;
+pub mod spawn;
+pub mod stats;
+pub mod warm;
+pub mod watchdog;
+pub mod workspace;
+use crate::error::XarError;
 use crate::mount::directory::Directory;
+use crate::mount::events::{self, Event};
+use crate::mount::lock::{lockfile, touch_unlocked, Lock, DEFAULT_LEASE};
+use crate::mount::mounter::Mounter;
+use crate::mount::owner::Owner;
+use crate::mount::permission::{Credentials, Grant};
+use crate::policy;
+use crate::sys::{Clock, System};
+use crate::version;
 use crate::xar::Xar;
 
+use failure::ResultExt;
+use std::ffi::OsString;
+use std::fs;
 use std::os::unix::process::ExitStatusExt;
-use std::process::Command;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::thread;
 use std::time::{Duration, Instant};
 
+pub const SQUASHFUSE_BIN: &str = "squashfuse_ll";
+const DEFAULT_FUSE_TIMEOUT: u64 = 870;
+const READY_POLL: Duration = Duration::from_millis(100);
+const MAX_READY_WAIT: Duration = Duration::from_millis(50);
+// How long squashfuse has to make the mount available.
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(9);
+// How long a mounter that never became ready has to exit after SIGTERM.
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+// Settings for how an archive is mounted which don't come from its header.
+#[derive(Clone, Default)]
+pub struct MountOptions {
+    // SELinux context applied to every file in the mount.
+    pub context: Option<String>,
+    // Overrides the FUSE_TIMEOUT header field.
+    pub timeout: Option<u64>,
+    // Spawn squashfuse even if mounting failed recently.
+    pub skip_failure_cache: bool,
+    // Fail rather than fall back when the mount namespace can't be determined.
+    pub require_ns_id: bool,
+    // cgroup v2 the squashfuse daemon is moved into.
+    pub cgroup: Option<PathBuf>,
+    // Drop supplementary groups before starting squashfuse.
+    pub drop_groups: bool,
+    // Unmount anything other than squashfuse found on the mountpoint.
+    pub force_remount: bool,
+    // Name the mount after the archive's content hash as well as its UUID, and
+    // link it as the current version once mounted.
+    pub versioned: bool,
+    // Mount once for all users under <mount_root>/system, root only.
+    pub system: bool,
+    // Named payload other than the main one, set by Xar::select_section.
+    pub section: Option<String>,
+    // Mount archives whose VERSION is outside the supported formats.
+    pub ignore_version: bool,
+    // Fail, rather than warn, when the mount turns out to be writable.
+    pub require_ro: bool,
+    // Overrides the policy's and header's naming strategy.
+    pub naming: Option<naming::NamingStrategy>,
+    // Scheduling priority and I/O priority squashfuse runs with.
+    pub nice: Option<i32>,
+    pub ionice: Option<spawn::IoPriority>,
+}
+
+// Resolve a header supplied path inside the mount, refusing paths which
+// escape it either lexically or through symlinks.
+pub(crate) fn resolve_in_mount(
+    mount: &Directory,
+    field: &str,
+    relative: &str,
+) -> Result<PathBuf, failure::Error> {
+    let escapes = Path::new(relative)
+        .components()
+        .any(|component| match component {
+            Component::Normal(_) => false,
+            _ => true,
+        });
+    if escapes {
+        bail!(
+            "{} {} must be a relative path inside the archive",
+            field,
+            relative
+        );
+    }
+
+    let root = mount.path.canonicalize()?;
+    let path = root.join(relative);
+    if !path.exists() {
+        bail!("{} {} does not exist in the archive", field, relative);
+    }
+    let path = path.canonicalize()?;
+    if !path.starts_with(&root) {
+        bail!("{} {} resolves outside the archive", field, relative);
+    }
+    Ok(path)
+}
+
 impl Xar {
-    pub fn mount(&self, mount: &Directory) -> Result<(), failure::Error> {
-        let lock = mount.lock_and_mkdir()?;
+    // Block until another process has mounted the archive and its target can
+    // be opened, for orchestration that only needs to observe the mount.
+    pub fn wait_until_ready(
+        &self,
+        mount: &Directory,
+        timeout: Duration,
+    ) -> Result<PathBuf, failure::Error> {
+        let start = Instant::now();
+        loop {
+            // target_path fails until the archive is mounted.
+            let ready = self.target_path(mount).and_then(|target| {
+                std::fs::File::open(&target)?;
+                Ok(target)
+            });
+            match ready {
+                Ok(target) => return Ok(target),
+                Err(e) if start.elapsed() > timeout => {
+                    bail!(
+                        "{} not ready after {}s: {}",
+                        mount.path.display(),
+                        timeout.as_secs(),
+                        e
+                    )
+                }
+                Err(_) => thread::sleep(READY_POLL),
+            }
+        }
+    }
 
+    // Absolute path of XAREXEC_TARGET, which requires the archive be mounted.
+    pub fn target_path(&self, mount: &Directory) -> Result<PathBuf, failure::Error> {
         if !mount.is_mounted()? {
-            let opts = vec![
-                format!("offset={}", self.header.offset),
-                format!("timeout={}", 870),
-            ];
+            bail!("{} is not mounted", mount.path.display());
+        }
+        resolve_in_mount(mount, "XAREXEC_TARGET", &self.header.xarexec_target)
+    }
+
+    fn fuse_timeout(&self) -> u64 {
+        self.mount_options
+            .timeout
+            .or(self.header.fuse_timeout)
+            .unwrap_or(DEFAULT_FUSE_TIMEOUT)
+    }
+
+    // Under sudo squashfuse runs as the invoking user, like the mount
+    // directory. The kernel mounter needs root.
+    fn run_as(&self, mount: &Directory, mounter: Mounter) -> Option<Owner> {
+        match mounter {
+            Mounter::Kernel => None,
+            _ if mount.owner.is_effective() => None,
+            _ => Some(mount.owner),
+        }
+    }
+
+    fn daemon_credentials(&self, mount: &Directory, mounter: Mounter) -> Credentials {
+        let credentials = match self.run_as(mount, mounter) {
+            Some(owner) => Credentials::of(owner),
+            None => Credentials::current(),
+        };
+        if self.mount_options.drop_groups {
+            credentials.without_groups()
+        } else {
+            credentials
+        }
+    }
+
+    fn mount_args(&self, mount: &Directory, mounter: Mounter) -> Vec<OsString> {
+        let mut args = Vec::new();
+        let mut opts = vec!["ro".to_string()];
+        match mounter {
+            Mounter::Squashfuse => {
+                let capabilities = capabilities::squashfuse();
+                opts.push(format!("offset={}", self.header.offset));
+                if capabilities.timeout {
+                    opts.push(format!("timeout={}", self.fuse_timeout()));
+                }
+                // An archive only its group may read gets its contents
+                // owned by that group too, not the daemon's primary one.
+                let credentials = self.daemon_credentials(mount, mounter);
+                if let Ok(Grant::Group(gid)) = permission::check(&self.source_path(), &credentials)
+                {
+                    if capabilities.uid_gid && gid != credentials.owner.gid {
+                        opts.push(format!("gid={}", gid));
+                    }
+                }
+            }
+            Mounter::Erofsfuse => {
+                args.push(OsString::from(format!("--offset={}", self.header.offset)));
+            }
+            Mounter::Kernel => {
+                args.push(OsString::from("-terofs"));
+                opts.push("loop".to_string());
+                opts.push(format!("offset={}", self.header.offset));
+            }
+        }
+        if (self.mount_options.system || mount.system) && mounter != Mounter::Kernel {
+            opts.push("allow_other".to_string());
+        }
+        if let Some(context) = &self.mount_options.context {
+            // MLS category lists contain commas, which must be quoted.
+            if context.contains(',') {
+                opts.push(format!("context=\"{}\"", context));
+            } else {
+                opts.push(format!("context={}", context));
+            }
+        }
+        args.push(OsString::from(format!("-o{}", opts.join(","))));
+        args.push(OsString::from(self.child_source_path()));
+        args.push(OsString::from(&mount.path));
+        args
+    }
+
+    // Describe every step mount would take, without executing any of them.
+    pub fn plan_mount(&self, mount: &Directory) -> Result<Vec<String>, failure::Error> {
+        let mut steps = mount.plan();
+        if mount.is_mounted()? {
+            steps.push(format!("skip mount, {} is mounted", mount.path.display()));
+        } else {
+            let mounter = self.mounter();
+            let args: Vec<String> = self
+                .mount_args(mount, mounter)
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            steps.push(format!("{} {}", mounter.program(), args.join(" ")));
+            steps.push(format!("wait for {} to be mounted", mount.path.display()));
+        }
+        steps.push(format!(
+            "renew lease on {}",
+            lockfile(&mount.path).display()
+        ));
+        Ok(steps)
+    }
+
+    // Whether this archive may be mounted at all, going by its format and
+    // the admin policy.
+    fn check_admissible(&self) -> Result<(), failure::Error> {
+        if !self.mount_options.ignore_version {
+            version::check_supported(&self.header.version)?;
+        }
+        policy::load()?.check(self)
+    }
+
+    fn lease(&self) -> Duration {
+        match self.header.lease_duration {
+            Some(secs) => Duration::from_secs(secs),
+            None => DEFAULT_LEASE,
+        }
+    }
+
+    // Skip locking entirely when the mount is already live, only touching the
+    // lockfile to keep it from expiring. Returns whether mounting was needed.
+    pub fn mount_if_needed(&self, mount: &Directory) -> Result<bool, failure::Error> {
+        if !mount.is_mounted()? {
+            return self.mount(mount);
+        }
+        self.check_admissible().map_err(|e| self.failed(mount, e))?;
+
+        if let Err(e) = touch_unlocked(&mount.path) {
+            debug!(self.logger, "Failed to renew lease"; "error" => e.to_string());
+        }
+        Ok(false)
+    }
+
+    // Returns whether this call spawned squashfuse, rather than finding the
+    // archive already mounted.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, mount), fields(mount = %mount.path.display()))
+    )]
+    pub fn mount(&self, mount: &Directory) -> Result<bool, failure::Error> {
+        self.mount_until(mount, None)
+    }
+
+    // Like mount, but give up at `deadline` whether waiting for the lock,
+    // for squashfuse, which is then killed, or for the mount to appear. The
+    // error is XarError::Timeout naming the phase.
+    pub fn mount_with_deadline(
+        &self,
+        mount: &Directory,
+        deadline: Instant,
+    ) -> Result<bool, failure::Error> {
+        self.mount_until(mount, Some(deadline))
+    }
+
+    fn mount_until(
+        &self,
+        mount: &Directory,
+        deadline: Option<Instant>,
+    ) -> Result<bool, failure::Error> {
+        self.try_mount(mount, deadline)
+            .map_err(|e| self.failed(mount, e))
+            .with_context(|_| {
+                format!(
+                    "while mounting {} on {}",
+                    self.archive.display(),
+                    mount.path.display()
+                )
+            })
+            .map_err(failure::Error::from)
+    }
+
+    fn try_mount(
+        &self,
+        mount: &Directory,
+        deadline: Option<Instant>,
+    ) -> Result<bool, failure::Error> {
+        // The system directory and its lockfiles are root's, which keeps other
+        // users from mounting or unmounting there.
+        if (self.mount_options.system || mount.system) && !nix::unistd::geteuid().is_root() {
+            bail!("System mounts can only be made by root");
+        }
+        self.check_admissible()?;
+        let lock = mount.lock_and_mkdir_until(deadline)?;
+        mount.check_foreign(self.mount_options.force_remount)?;
+
+        let needs_mount = !mount.is_mounted()?;
+        if needs_mount && !self.mount_options.skip_failure_cache {
+            failures::check(&mount.path)?;
+        }
+        match self.start(mount, needs_mount, deadline) {
+            Ok(()) if needs_mount => {
+                failures::clear(&mount.path);
+                if let Err(e) = origin::record(&mount.path, self) {
+                    debug!(
+                        self.logger,
+                        "Failed to record origin";
+                        "error" => e.to_string()
+                    );
+                }
+            }
+            Ok(()) => (),
+            Err(e) => {
+                // Running out of the caller's time says nothing about the
+                // archive.
+                let overrun = deadline.map_or(false, |deadline| Instant::now() >= deadline);
+                if needs_mount && !overrun {
+                    if let Err(record_error) = failures::record(&mount.path, &e) {
+                        debug!(
+                            self.logger,
+                            "Failed to record failure";
+                            "error" => record_error.to_string()
+                        );
+                    }
+                }
+                return Err(e);
+            }
+        }
 
+        self.verify_read_only(mount)?;
+
+        // Renew the lease on the lockfile
+        lock.renew(self.lease())?;
+        mount.publish()?;
+
+        if needs_mount {
+            events::emit(Event::Mounted {
+                mount: mount.path.clone(),
+                archive: self.archive.clone(),
+                uuid: self.header.uuid.clone(),
+            });
+        }
+        Ok(needs_mount)
+    }
+
+    // Spawn squashfuse if needed, then wait for the mount to be available.
+    fn start(
+        &self,
+        mount: &Directory,
+        needs_mount: bool,
+        deadline: Option<Instant>,
+    ) -> Result<(), failure::Error> {
+        if needs_mount {
+            self.check_not_replaced()?;
+            daemons::check_cap(&mount.path)?;
             debug!(
                 self.logger,
                 "Mounting";
-                "mount" => mount.path.to_str().unwrap_or_default(),
-                "archive" => &self.archive.to_str().unwrap_or_default()
+                "mount" => mount.path.display().to_string(),
+                "archive" => self.archive.display().to_string()
+            );
+            let mounter = self.mounter();
+            if mounter == Mounter::Squashfuse {
+                let capabilities = capabilities::squashfuse();
+                debug!(
+                    self.logger,
+                    "Squashfuse";
+                    "version" => capabilities.version.as_deref().unwrap_or("unknown"),
+                    "options" => capabilities.options().join(",")
+                );
+            }
+            permission::check(
+                &self.source_path(),
+                &self.daemon_credentials(mount, mounter),
+            )?;
+            #[cfg(feature = "tracing")]
+            tracing::debug!(archive = %self.archive.display(), "spawning {}", mounter.program());
+            let mut cmd = Command::new(mounter.program());
+            cmd.args(self.mount_args(mount, mounter));
+            let _procs = match &self.mount_options.cgroup {
+                Some(cgroup) => Some(cgroup::join_on_exec(&mut cmd, cgroup)?),
+                None => None,
+            };
+            spawn::deprioritize(&mut cmd, self.mount_options.nice, self.mount_options.ionice);
+            spawn::harden(
+                &mut cmd,
+                self.mount_options.drop_groups,
+                self.run_as(mount, mounter),
+                self.source_fd(),
             );
-            let mut cmd = Command::new("squashfuse_ll")
-                .arg(format!("-o{}", opts.join(",")))
-                .arg(&self.archive)
-                .arg(&mount.path)
-                .spawn()?;
+            spawn::stdout_to_stderr(&mut cmd)?;
+            let stderr = spawn::capture_stderr(&mut cmd)?;
+            let mut cmd = cmd
+                .spawn()
+                .with_context(|_| format!("while spawning {}", mounter.program()))?;
 
-            let status = cmd.wait()?;
+            let status = spawn::wait_until(&mut cmd, deadline, mounter.program())?;
+            let stderr = spawn::captured(stderr);
+            if !stderr.is_empty() {
+                debug!(
+                    self.logger,
+                    "Mounter stderr";
+                    "program" => mounter.program(),
+                    "stderr" => &stderr
+                );
+            }
             if !status.success() {
+                let stderr = if stderr.is_empty() {
+                    String::new()
+                } else {
+                    format!(": {}", stderr)
+                };
                 match status.code() {
-                    Some(code) => bail!("Exited with status code: {}", code),
-                    None => bail!("Process terminated by signal: {:?}", status.signal()),
+                    Some(code) => bail!("Exited with status code: {}{}", code, stderr),
+                    None => bail!(
+                        "Process terminated by signal: {:?}{}",
+                        status.signal(),
+                        stderr
+                    ),
                 }
             }
         } else {
             debug!(
                 self.logger,
                 "Mounted";
-                "mount" => mount.path.to_str().unwrap_or_default(),
+                "mount" => mount.path.display().to_string(),
             );
+            return self.wait_for_mount(mount, deadline);
         }
 
-        // Wait for up to 9 seconds for mount to be available
-        let start = Instant::now();
-        let timeout = Duration::from_secs(9);
-        let sleep = Duration::from_micros(100);
-        while !mount.is_mounted()? {
-            if start.elapsed() > timeout {
-                bail!("Timed out waiting for mount");
-            }
-            thread::sleep(sleep);
+        let result = self.wait_for_mount(mount, deadline);
+        if result.is_err() {
+            self.abandon(mount);
         }
+        result
+    }
 
-        // Touch the lockfile
-        lock.touch()?;
+    // Report `error`, from any step of mounting, to subscribers.
+    fn failed(&self, mount: &Directory, error: failure::Error) -> failure::Error {
+        events::emit(Event::Failed {
+            mount: mount.path.clone(),
+            archive: self.archive.clone(),
+            error: error.to_string(),
+        });
+        error
+    }
 
+    // Undo a mount which never became ready: the daemon may still be
+    // initializing or wedged, and would otherwise hold the directory.
+    // The caller still holds the lock.
+    fn abandon(&self, mount: &Directory) {
+        let path = mount.path.display().to_string();
+        match daemons::kill_serving(&mount.path, KILL_GRACE) {
+            Ok(killed) => warn!(
+                self.logger,
+                "Killed unready mounter";
+                "mount" => &path,
+                "daemons" => killed
+            ),
+            Err(e) => warn!(
+                self.logger,
+                "Unable to kill mounter";
+                "mount" => &path,
+                "error" => e.to_string()
+            ),
+        }
+        let status = match self.mounter() {
+            // Kernel EROFS mounts aren't FUSE, fusermount refuses them.
+            Mounter::Kernel => detach(&mount.path),
+            _ => format!(
+                "{:?}",
+                Command::new("fusermount")
+                    .arg("-u")
+                    .arg("-z")
+                    .arg(&mount.path)
+                    .stderr(Stdio::null())
+                    .status()
+            ),
+        };
+        debug!(
+            self.logger,
+            "Unmounted unready mount";
+            "mount" => &path,
+            "status" => status
+        );
+        if fs::remove_dir(&mount.path).is_ok() {
+            let _ = fs::remove_file(lockfile(&mount.path));
+        }
+        events::emit(Event::Reaped {
+            path: mount.path.clone(),
+            problem: "mount never became ready",
+            action: "unmounted".to_string(),
+        });
+    }
+
+    // squashfs can't be written, so a writable mount means something other
+    // than squashfuse is serving it, or it was mounted with other options.
+    // Mounts outside our namespace's table can't be checked.
+    fn verify_read_only(&self, mount: &Directory) -> Result<(), failure::Error> {
+        let info = match mountinfo::find(&mount.path)? {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        if info.is_read_only() {
+            return Ok(());
+        }
+        if self.mount_options.require_ro {
+            bail!(
+                "{} is mounted read-write by {} from {}",
+                mount.path.display(),
+                info.fs_type,
+                info.source
+            );
+        }
+        warn!(
+            self.logger,
+            "Mount is not read-only";
+            "mount" => mount.path.display().to_string(),
+            "fs_type" => &info.fs_type,
+            "options" => info.options.join(",")
+        );
         Ok(())
     }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, mount), fields(mount = %mount.path.display()))
+    )]
+    fn wait_for_mount(
+        &self,
+        mount: &Directory,
+        deadline: Option<Instant>,
+    ) -> Result<(), failure::Error> {
+        let timeout = match deadline {
+            Some(deadline) => MOUNT_TIMEOUT.min(deadline.saturating_duration_since(Instant::now())),
+            None => MOUNT_TIMEOUT,
+        };
+        wait_until(&System, timeout, || mount.is_mounted())
+    }
+}
+
+// Lazily unmount a kernel mount, as fusermount -z does FUSE ones.
+#[cfg(target_os = "linux")]
+fn detach(path: &Path) -> String {
+    use nix::mount::{umount2, MntFlags};
+    format!("{:?}", umount2(path, MntFlags::MNT_DETACH))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn detach(_path: &Path) -> String {
+    "kernel mounts are Linux only".to_string()
+}
+
+// Poll `ready` until it's true or `timeout` passes, waking on mount table
+// changes rather than polling. Waits are capped in case a change is missed,
+// e.g. when the mount isn't in our namespace's table.
+pub fn wait_until<F>(
+    clock: &dyn Clock,
+    timeout: Duration,
+    mut ready: F,
+) -> Result<(), failure::Error>
+where
+    F: FnMut() -> Result<bool, failure::Error>,
+{
+    let start = clock.now();
+    loop {
+        let generation = mountinfo::generation();
+        if ready()? {
+            return Ok(());
+        }
+        let elapsed = clock.now() - start;
+        if elapsed >= timeout {
+            #[cfg(feature = "tracing")]
+            tracing::warn!("timed out waiting for mount");
+            return Err(XarError::Timeout {
+                phase: "waiting for mount".to_string(),
+            }
+            .into());
+        }
+        clock.wait(generation, (timeout - elapsed).min(MAX_READY_WAIT));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::ManualClock;
+
+    #[test]
+    fn wait_until_times_out_on_the_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut polls = 0;
+        let result = wait_until(&clock, Duration::from_secs(30), || {
+            polls += 1;
+            Ok(false)
+        });
+        assert!(result.is_err());
+        assert!(clock.now() - start >= Duration::from_secs(30));
+        assert!(polls > 1);
+    }
+
+    #[test]
+    fn wait_until_returns_once_ready() {
+        let clock = ManualClock::new();
+        let mut polls = 0;
+        wait_until(&clock, Duration::from_secs(30), || {
+            polls += 1;
+            Ok(polls == 3)
+        })
+        .unwrap();
+        assert_eq!(polls, 3);
+    }
 }
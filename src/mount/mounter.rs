@@ -0,0 +1,49 @@
+use crate::mount::SQUASHFUSE_BIN;
+use crate::xar::{Payload, Xar};
+
+use std::fs;
+
+pub const EROFSFUSE_BIN: &str = "erofsfuse";
+const PROC_FILESYSTEMS: &str = "/proc/filesystems";
+
+// What serves an archive's payload. squashfs is always served by
+// squashfuse. EROFS is mounted by the kernel, through a loop device, when
+// we're root and it has the driver, and by erofsfuse otherwise. Kernel
+// mounts have no daemon, so no idle timeout, and are unmounted with umount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mounter {
+    Squashfuse,
+    Erofsfuse,
+    Kernel,
+}
+
+impl Mounter {
+    pub fn program(self) -> &'static str {
+        match self {
+            Mounter::Squashfuse => SQUASHFUSE_BIN,
+            Mounter::Erofsfuse => EROFSFUSE_BIN,
+            Mounter::Kernel => "mount",
+        }
+    }
+}
+
+fn kernel_supports(fs_type: &str) -> bool {
+    match fs::read_to_string(PROC_FILESYSTEMS) {
+        Ok(filesystems) => filesystems
+            .lines()
+            .any(|line| line.split_whitespace().last() == Some(fs_type)),
+        Err(_) => false,
+    }
+}
+
+impl Xar {
+    pub fn mounter(&self) -> Mounter {
+        match self.header.payload {
+            Payload::Squashfs => Mounter::Squashfuse,
+            Payload::Erofs if nix::unistd::geteuid().is_root() && kernel_supports("erofs") => {
+                Mounter::Kernel
+            }
+            Payload::Erofs => Mounter::Erofsfuse,
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, Once};
+use std::time::Duration;
+
+const PROC_MOUNTINFO: &str = "/proc/self/mountinfo";
+const FALLBACK_POLL: Duration = Duration::from_millis(1);
+
+// One line of /proc/self/mountinfo. Reading it never touches the mounted
+// filesystems, so it's safe even when a FUSE daemon has hung.
+pub struct MountInfo {
+    pub major: u32,
+    pub minor: u32,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+    // Per mount options, e.g. ro and nosuid, then the superblock's.
+    pub options: Vec<String>,
+    pub super_options: Vec<String>,
+}
+
+impl MountInfo {
+    pub fn is_read_only(&self) -> bool {
+        self.options
+            .iter()
+            .chain(&self.super_options)
+            .any(|option| option == "ro")
+    }
+}
+
+// Spaces, tabs, newlines and backslashes are escaped as octal, e.g. \040.
+fn unescape(field: &str) -> OsString {
+    let bytes = field.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or_default();
+            if let Ok(value) = u8::from_str_radix(octal, 8) {
+                result.push(value);
+                i += 4;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    OsString::from_vec(result)
+}
+
+fn split_options(field: &str) -> Vec<String> {
+    field
+        .split(',')
+        .filter(|option| !option.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Fields are: id parent major:minor root mount_point options [optional...] - type source super_options
+fn parse_line(line: &str) -> Option<MountInfo> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let mut dev = fields.get(2)?.splitn(2, ':');
+    let major = dev.next()?.parse().ok()?;
+    let minor = dev.next()?.parse().ok()?;
+    let separator = fields.iter().position(|field| *field == "-")?;
+    Some(MountInfo {
+        major,
+        minor,
+        mount_point: PathBuf::from(unescape(fields.get(4)?)),
+        fs_type: fields.get(separator + 1)?.to_string(),
+        source: fields.get(separator + 2)?.to_string(),
+        options: split_options(fields.get(5)?),
+        super_options: split_options(fields.get(separator + 3).unwrap_or(&"")),
+    })
+}
+
+pub fn read() -> Result<Vec<MountInfo>, failure::Error> {
+    let contents = fs::read_to_string(PROC_MOUNTINFO)?;
+    Ok(contents.lines().filter_map(parse_line).collect())
+}
+
+// The most recent mount on `path`, which is the one visible there.
+pub fn find(path: &Path) -> Result<Option<MountInfo>, failure::Error> {
+    Ok(read()?
+        .into_iter()
+        .filter(|info| info.mount_point == path)
+        .last())
+}
+
+// Every mount and unmount in our namespace bumps the generation, so any
+// number of threads waiting on mounts share one poller instead of each
+// polling statfs.
+struct Changes {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+static START: Once = Once::new();
+static mut CHANGES: Option<&'static Changes> = None;
+
+// The kernel flags /proc/self/mountinfo with POLLPRI whenever the mount table
+// changes, and keeps flagging it until it's read again from the start.
+#[cfg(target_os = "linux")]
+fn poll_changes(changes: &'static Changes) -> Result<(), failure::Error> {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+
+    let mut file = fs::File::open(PROC_MOUNTINFO)?;
+    let mut buffer = Vec::new();
+    std::thread::spawn(move || loop {
+        buffer.clear();
+        if file.seek(SeekFrom::Start(0)).is_err() || file.read_to_end(&mut buffer).is_err() {
+            return;
+        }
+        let mut fd = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLPRI,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut fd, 1, -1) } < 0 {
+            return;
+        }
+        *changes.generation.lock().unwrap() += 1;
+        changes.changed.notify_all();
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn poll_changes(_changes: &'static Changes) -> Result<(), failure::Error> {
+    bail!("Mount table notifications are only supported on Linux")
+}
+
+fn changes() -> Option<&'static Changes> {
+    START.call_once(|| {
+        let changes = Box::leak(Box::new(Changes {
+            generation: Mutex::new(0),
+            changed: Condvar::new(),
+        }));
+        if poll_changes(changes).is_ok() {
+            unsafe { CHANGES = Some(changes) };
+        }
+    });
+    unsafe { CHANGES }
+}
+
+// Take before checking the condition being waited for, then pass to
+// wait_for_change so a change in between isn't missed.
+pub fn generation() -> u64 {
+    match changes() {
+        Some(changes) => *changes.generation.lock().unwrap(),
+        None => 0,
+    }
+}
+
+// Sleep until the mount table changes after `since`, or for at most `timeout`.
+// Without notifications this just sleeps a short while.
+pub fn wait_for_change(since: u64, timeout: Duration) {
+    let changes = match changes() {
+        Some(changes) => changes,
+        None => return std::thread::sleep(timeout.min(FALLBACK_POLL)),
+    };
+    let generation = changes.generation.lock().unwrap();
+    let _ = changes
+        .changed
+        .wait_timeout_while(generation, timeout, |generation| *generation == since);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fuse_lines() {
+        let line = "36 35 0:52 / /tmp/xarfuse/uid-1000/my\\040app-ns-4026531840 \
+                    ro,nosuid,nodev,relatime shared:1 master:2 - fuse.squashfuse_ll \
+                    /tmp/a.xar rw,user_id=1000,group_id=1000";
+        let info = parse_line(line).unwrap();
+        assert_eq!((info.major, info.minor), (0, 52));
+        assert_eq!(
+            info.mount_point,
+            Path::new("/tmp/xarfuse/uid-1000/my app-ns-4026531840")
+        );
+        assert_eq!(info.fs_type, "fuse.squashfuse_ll");
+        assert_eq!(info.source, "/tmp/a.xar");
+        assert_eq!(info.options, ["ro", "nosuid", "nodev", "relatime"]);
+        assert_eq!(info.super_options[0], "rw");
+        assert!(info.is_read_only());
+    }
+
+    #[test]
+    fn unescapes_octal_only() {
+        assert_eq!(unescape("a\\011b\\134c\\012"), OsString::from("a\tb\\c\n"));
+        assert_eq!(unescape("a\\xyz\\04"), OsString::from("a\\xyz\\04"));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_line("").is_none());
+        assert!(parse_line("36 35 0-52 / /mnt rw - tmpfs tmpfs rw").is_none());
+        assert!(parse_line("36 35 0:52 / /mnt rw tmpfs tmpfs rw").is_none());
+        let info = parse_line("36 35 0:52 / /mnt rw - tmpfs tmpfs").unwrap();
+        assert!(info.super_options.is_empty());
+        assert!(!info.is_read_only());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::mount::directory::{get_user_basedir, mount_dir_name, SYSTEM_BASEDIR};
+use crate::policy;
+use crate::xar::{validate_uuid, Xar};
+
+use nix::unistd::Uid;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+// Who shares a mount, which decides the <dir>/<name> it gets under the mount
+// root. Set by mount --naming, else the policy's mount_naming, else the
+// header's MOUNT_NAMING, so hosts decide over archives.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NamingStrategy {
+    // uid-N/UUID[-seed-S]-ns-M, per user and mount namespace, e.g. container.
+    UidNs,
+    // uid-N/UUID[-seed-S], per user across namespaces.
+    UidOnly,
+    // system/UUID[-seed-S], one mount for every user, made by root.
+    Global,
+    // [{root}/]DIR/NAME with {uuid}, {uid}, {seed} and {ns} replaced by
+    // their values, an absent seed or namespace by nothing. See Template.
+    Custom(String),
+}
+
+impl Default for NamingStrategy {
+    fn default() -> NamingStrategy {
+        NamingStrategy::UidNs
+    }
+}
+
+// What a mount's directory may be named after.
+pub struct Names<'a> {
+    pub uuid: &'a str,
+    pub uid: u32,
+    pub seed: Option<&'a str>,
+    pub ns: Option<&'a str>,
+}
+
+impl FromStr for NamingStrategy {
+    type Err = failure::Error;
+
+    fn from_str(value: &str) -> Result<NamingStrategy, failure::Error> {
+        match value {
+            "uid-ns" => Ok(NamingStrategy::UidNs),
+            "uid-only" => Ok(NamingStrategy::UidOnly),
+            "global" => Ok(NamingStrategy::Global),
+            template if template.contains('{') => {
+                Template::parse(template)?;
+                Ok(NamingStrategy::Custom(template.to_string()))
+            }
+            _ => bail!(
+                "naming {:?} must be uid-ns, uid-only, global or a DIR/NAME template",
+                value
+            ),
+        }
+    }
+}
+
+impl fmt::Display for NamingStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NamingStrategy::UidNs => write!(f, "uid-ns"),
+            NamingStrategy::UidOnly => write!(f, "uid-only"),
+            NamingStrategy::Global => write!(f, "global"),
+            NamingStrategy::Custom(template) => write!(f, "{}", template),
+        }
+    }
+}
+
+impl Serialize for NamingStrategy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NamingStrategy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<NamingStrategy, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Longest file name and path the kernel accepts.
+const NAME_MAX: usize = 255;
+const PATH_MAX: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    Uuid,
+    Uid,
+    Seed,
+    Ns,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Value(Placeholder),
+}
+
+// A parsed naming template, DIR/NAME below the mount root, optionally
+// spelled out as {root}/DIR/NAME.
+#[derive(Debug)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+// Decoded from an existing mount directory by Template::decode.
+#[derive(Debug, Default)]
+pub struct Decoded {
+    pub uuid: String,
+    pub uid: Option<u32>,
+    pub seed: Option<String>,
+    pub ns: Option<String>,
+}
+
+impl Placeholder {
+    fn named(name: &str) -> Option<Placeholder> {
+        match name {
+            "uuid" => Some(Placeholder::Uuid),
+            "uid" => Some(Placeholder::Uid),
+            "seed" => Some(Placeholder::Seed),
+            "ns" => Some(Placeholder::Ns),
+            _ => None,
+        }
+    }
+
+    // Whether `value` could have come from this placeholder, so decoding
+    // can tell where one ends and the next literal starts.
+    fn accepts(self, value: &str) -> bool {
+        match self {
+            Placeholder::Uuid => validate_uuid(value).is_ok(),
+            Placeholder::Uid => !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()),
+            Placeholder::Seed => !value.contains('/'),
+            Placeholder::Ns => {
+                value.is_empty()
+                    || value.chars().all(|c| c.is_ascii_digit())
+                    || (value.starts_with('c') && value[1..].chars().all(|c| c.is_ascii_hexdigit()))
+            }
+        }
+    }
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, failure::Error> {
+        let relative = template.strip_prefix("{root}/").unwrap_or(template);
+        let mut pieces = Vec::new();
+        let mut rest = relative;
+        while !rest.is_empty() {
+            let start = rest.find('{').unwrap_or_else(|| rest.len());
+            if start > 0 {
+                if rest[..start].contains('}') {
+                    bail!("naming template {:?} has an unopened }}", template);
+                }
+                pieces.push(Piece::Literal(rest[..start].to_string()));
+                rest = &rest[start..];
+                continue;
+            }
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => bail!("naming template {:?} has an unclosed {{", template),
+            };
+            let name = &rest[1..end];
+            match Placeholder::named(name) {
+                Some(placeholder) => pieces.push(Piece::Value(placeholder)),
+                None if name == "root" => {
+                    bail!(
+                        "naming template {:?} may only start with {{root}}/",
+                        template
+                    )
+                }
+                None => bail!(
+                    "naming template {:?} has unknown placeholder {{{}}}",
+                    template,
+                    name
+                ),
+            }
+            rest = &rest[end + 1..];
+        }
+
+        let components: Vec<&str> = relative.split('/').collect();
+        if components.len() != 2 || components.iter().any(|component| component.is_empty()) {
+            bail!("naming template {:?} must be [{{root}}/]DIR/NAME", template);
+        }
+        if !pieces
+            .iter()
+            .any(|piece| *piece == Piece::Value(Placeholder::Uuid))
+        {
+            bail!("naming template {:?} must contain {{uuid}}", template);
+        }
+        // Adjacent values couldn't be told apart when decoding.
+        if pieces.windows(2).any(|pair| match pair {
+            [Piece::Value(_), Piece::Value(_)] => true,
+            _ => false,
+        }) {
+            bail!(
+                "naming template {:?} needs text between placeholders",
+                template
+            );
+        }
+        Ok(Template { pieces })
+    }
+
+    // DIR/NAME for `names`, refusing anything which would escape the mount
+    // root or which the kernel would reject.
+    pub fn expand(&self, names: &Names) -> Result<(String, String), failure::Error> {
+        let mut expanded = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(literal) => expanded.push_str(literal),
+                Piece::Value(Placeholder::Uuid) => expanded.push_str(names.uuid),
+                Piece::Value(Placeholder::Uid) => expanded.push_str(&names.uid.to_string()),
+                Piece::Value(Placeholder::Seed) => {
+                    expanded.push_str(names.seed.unwrap_or_default())
+                }
+                Piece::Value(Placeholder::Ns) => expanded.push_str(names.ns.unwrap_or_default()),
+            }
+        }
+        let mut parts = expanded.splitn(2, '/');
+        let dir = parts.next().unwrap_or_default();
+        let name = parts.next().unwrap_or_default();
+        for component in &[dir, name] {
+            if component.is_empty()
+                || component.contains('/')
+                || component.contains('\0')
+                || *component == "."
+                || *component == ".."
+                || component.len() > NAME_MAX
+            {
+                bail!(
+                    "naming template expands to {:?}, not a valid DIR/NAME",
+                    expanded
+                );
+            }
+        }
+        if expanded.len() > PATH_MAX {
+            bail!(
+                "naming template expands to {} bytes, over {}",
+                expanded.len(),
+                PATH_MAX
+            );
+        }
+        Ok((dir.to_string(), name.to_string()))
+    }
+
+    // The values `dir`/`name` were expanded from, or None if this template
+    // couldn't have produced them.
+    pub fn decode(&self, dir: &str, name: &str) -> Option<Decoded> {
+        let mut decoded = Decoded::default();
+        if decode_pieces(&self.pieces, &format!("{}/{}", dir, name), &mut decoded) {
+            Some(decoded)
+        } else {
+            None
+        }
+    }
+}
+
+// Matches `pieces` against `text`, trying each split point for a value, so
+// a hyphenated {uuid} followed by `-` still decodes.
+fn decode_pieces(pieces: &[Piece], text: &str, decoded: &mut Decoded) -> bool {
+    let (piece, rest) = match pieces.split_first() {
+        Some(split) => split,
+        None => return text.is_empty(),
+    };
+    let placeholder = match piece {
+        Piece::Literal(literal) => {
+            return text.starts_with(literal.as_str())
+                && decode_pieces(rest, &text[literal.len()..], decoded)
+        }
+        Piece::Value(placeholder) => *placeholder,
+    };
+    for end in (0..=text.len()).rev() {
+        if !text.is_char_boundary(end) || !placeholder.accepts(&text[..end]) {
+            continue;
+        }
+        if !decode_pieces(rest, &text[end..], decoded) {
+            continue;
+        }
+        let value = &text[..end];
+        match placeholder {
+            Placeholder::Uuid => decoded.uuid = value.to_string(),
+            Placeholder::Uid => decoded.uid = value.parse().ok(),
+            Placeholder::Seed if !value.is_empty() => decoded.seed = Some(value.to_string()),
+            Placeholder::Ns if !value.is_empty() => decoded.ns = Some(value.to_string()),
+            _ => (),
+        }
+        return true;
+    }
+    false
+}
+
+impl NamingStrategy {
+    // Whether the mount namespace is part of the name, and so needs finding.
+    pub fn uses_namespace(&self) -> bool {
+        match self {
+            NamingStrategy::UidNs => true,
+            NamingStrategy::Custom(template) => template.contains("{ns}"),
+            _ => false,
+        }
+    }
+
+    // The directory under the mount root, and the mount's name in it.
+    pub fn components(&self, names: &Names) -> Result<(String, String), failure::Error> {
+        let seed = names
+            .seed
+            .filter(|seed| !seed.is_empty() && !seed.contains('/'));
+        let (dir, name) = match self {
+            NamingStrategy::UidNs => (
+                get_user_basedir(Uid::from_raw(names.uid)),
+                mount_dir_name(names.uuid, seed, names.ns),
+            ),
+            NamingStrategy::UidOnly => (
+                get_user_basedir(Uid::from_raw(names.uid)),
+                mount_dir_name(names.uuid, seed, None),
+            ),
+            NamingStrategy::Global => (
+                SYSTEM_BASEDIR.to_string(),
+                mount_dir_name(names.uuid, seed, None),
+            ),
+            NamingStrategy::Custom(template) => {
+                Template::parse(template)?.expand(&Names { seed, ..*names })?
+            }
+        };
+        Ok((dir, name))
+    }
+
+    // What a custom template's mount at `mount_point` was named after. The
+    // fixed strategies are decoded by daemons::uuid_of.
+    pub fn decode(&self, mount_point: &Path) -> Option<Decoded> {
+        let template = match self {
+            NamingStrategy::Custom(template) => Template::parse(template).ok()?,
+            _ => return None,
+        };
+        let name = mount_point.file_name()?.to_str()?;
+        let dir = mount_point.parent()?.file_name()?.to_str()?;
+        template.decode(dir, name)
+    }
+}
+
+impl Xar {
+    pub fn naming(&self) -> Result<NamingStrategy, failure::Error> {
+        if let Some(naming) = &self.mount_options.naming {
+            return Ok(naming.clone());
+        }
+        if let Some(naming) = policy::load()?.mount_naming {
+            return Ok(naming);
+        }
+        Ok(self.header.mount_naming.clone().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "01234567-89ab-cdef-0123-456789abcdef";
+
+    fn names<'a>(seed: Option<&'a str>, ns: Option<&'a str>) -> Names<'a> {
+        Names {
+            uuid: UUID,
+            uid: 1000,
+            seed,
+            ns,
+        }
+    }
+
+    #[test]
+    fn expanded_names_decode() {
+        let template = Template::parse("{root}/xar-{uid}/{uuid}-{seed}-{ns}").unwrap();
+        let (dir, name) = template
+            .expand(&names(Some("s1"), Some("4026531840")))
+            .unwrap();
+        assert_eq!(dir, "xar-1000");
+        assert_eq!(name, format!("{}-s1-4026531840", UUID));
+        let decoded = template.decode(&dir, &name).unwrap();
+        assert_eq!(decoded.uuid, UUID);
+        assert_eq!(decoded.uid, Some(1000));
+        assert_eq!(decoded.seed.as_deref(), Some("s1"));
+        assert_eq!(decoded.ns.as_deref(), Some("4026531840"));
+
+        // Absent values expand to nothing and decode as absent.
+        let (dir, name) = template.expand(&names(None, None)).unwrap();
+        assert_eq!(name, format!("{}--", UUID));
+        let decoded = template.decode(&dir, &name).unwrap();
+        assert_eq!(decoded.uuid, UUID);
+        assert_eq!(decoded.seed, None);
+        assert_eq!(decoded.ns, None);
+
+        assert!(template.decode("xar-1000", "not-a-mount").is_none());
+        assert!(template.decode("other", &name).is_none());
+    }
+
+    #[test]
+    fn unsafe_expansions_are_refused() {
+        let escape = Template::parse("../{uuid}").unwrap();
+        assert!(escape.expand(&names(None, None)).is_err());
+        let seeded = Template::parse("{seed}/{uuid}").unwrap();
+        assert!(seeded.expand(&names(Some(".."), None)).is_err());
+        assert!(seeded.expand(&names(Some("a\0b"), None)).is_err());
+        let long = Template::parse(&format!("xar/{}{{uuid}}", "x".repeat(NAME_MAX))).unwrap();
+        assert!(long.expand(&names(None, None)).is_err());
+    }
+
+    #[test]
+    fn malformed_templates_are_refused() {
+        for template in &[
+            "xar/{uid}",
+            "xar-{uid}",
+            "a/b/{uuid}",
+            "xar/{uuid}{seed}",
+            "xar/{uuid",
+            "xar/{name}",
+            "x/{root}/{uuid}",
+        ] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+        assert!("xar/{uid}".parse::<NamingStrategy>().is_err());
+        assert!("{root}/xar/{uuid}".parse::<NamingStrategy>().is_ok());
+    }
+}
@@ -0,0 +1,50 @@
+use crate::mount::lock::originfile;
+use crate::xar::Xar;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+// The archive a mount was made from, for tooling running inside it, e.g.
+// crash reporters and self-updaters looking for their source archive.
+pub struct Origin {
+    pub archive: PathBuf,
+    pub dev: u64,
+    pub ino: u64,
+}
+
+// Recorded as `archive=<canonical path>`, `dev=<device>` and `ino=<inode>`.
+pub fn record(mount: &PathBuf, xar: &Xar) -> Result<(), failure::Error> {
+    let attr = fs::metadata(xar.source_path())?;
+    fs::write(
+        originfile(mount),
+        format!(
+            "archive={}\ndev={}\nino={}\n",
+            xar.archive.display(),
+            attr.dev(),
+            attr.ino()
+        ),
+    )?;
+    Ok(())
+}
+
+pub fn read(mount: &PathBuf) -> Option<Origin> {
+    let contents = fs::read_to_string(originfile(mount)).ok()?;
+    let mut archive = None;
+    let mut dev = None;
+    let mut ino = None;
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("archive"), Some(value)) => archive = Some(PathBuf::from(value)),
+            (Some("dev"), Some(value)) => dev = value.parse().ok(),
+            (Some("ino"), Some(value)) => ino = value.parse().ok(),
+            _ => (),
+        }
+    }
+    Some(Origin {
+        archive: archive?,
+        dev: dev?,
+        ino: ino?,
+    })
+}
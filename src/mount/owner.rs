@@ -0,0 +1,92 @@
+use nix::unistd::{getegid, geteuid, Gid, Uid};
+use std::env;
+use std::ffi::CStr;
+
+// Set to 0 to make mounts for root even when run through sudo.
+const XAR_SUDO_OWNER: &str = "XAR_SUDO_OWNER";
+const SUDO_UID: &str = "SUDO_UID";
+const SUDO_GID: &str = "SUDO_GID";
+// Most systems allow far fewer, getgrouplist says when there are more.
+const MAX_GROUPS: usize = 65536;
+
+// Who a mount directory belongs to, and who squashfuse runs as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Uid,
+    pub gid: Gid,
+}
+
+fn sudo_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok()?.parse().ok()
+}
+
+impl Owner {
+    pub fn effective() -> Owner {
+        Owner {
+            uid: geteuid(),
+            gid: getegid(),
+        }
+    }
+
+    // The user who ran sudo, so their later unprivileged runs find mounts
+    // they own rather than root's, or the effective user otherwise.
+    pub fn invoking() -> Owner {
+        let sudo = || -> Option<Owner> {
+            if !geteuid().is_root() || env::var(XAR_SUDO_OWNER).ok().as_deref() == Some("0") {
+                return None;
+            }
+            let uid: u32 = sudo_var(SUDO_UID)?;
+            let gid: u32 = sudo_var(SUDO_GID)?;
+            if uid == 0 {
+                return None;
+            }
+            Some(Owner {
+                uid: Uid::from_raw(uid),
+                gid: Gid::from_raw(gid),
+            })
+        };
+        sudo().unwrap_or_else(Owner::effective)
+    }
+
+    pub fn is_effective(self) -> bool {
+        self == Owner::effective()
+    }
+
+    // The owner's supplementary groups, looked up by name in the group
+    // database. Empty when the uid has no passwd entry.
+    #[cfg(target_os = "linux")]
+    pub fn groups(self) -> Vec<libc::gid_t> {
+        let passwd = unsafe { libc::getpwuid(self.uid.as_raw()) };
+        if passwd.is_null() {
+            return Vec::new();
+        }
+        let name = unsafe { CStr::from_ptr((*passwd).pw_name) }.to_owned();
+        let mut groups = vec![0 as libc::gid_t; 64];
+        loop {
+            let mut count = groups.len() as libc::c_int;
+            let res = unsafe {
+                libc::getgrouplist(
+                    name.as_ptr(),
+                    self.gid.as_raw(),
+                    groups.as_mut_ptr(),
+                    &mut count,
+                )
+            };
+            if res >= 0 {
+                groups.truncate(count as usize);
+                return groups;
+            }
+            if groups.len() >= MAX_GROUPS {
+                return Vec::new();
+            }
+            let len = (count as usize).max(groups.len() * 2).min(MAX_GROUPS);
+            groups.resize(len, 0);
+        }
+    }
+
+    // getgrouplist takes ints on macOS, and only the primary group is kept.
+    #[cfg(not(target_os = "linux"))]
+    pub fn groups(self) -> Vec<libc::gid_t> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,106 @@
+use crate::error::XarError;
+use crate::mount::owner::Owner;
+
+use nix::unistd::Gid;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+// The user, group and supplementary groups squashfuse will run with.
+pub struct Credentials {
+    pub owner: Owner,
+    pub groups: Vec<libc::gid_t>,
+}
+
+impl Credentials {
+    // Our own, as inherited by squashfuse unless it switches user.
+    pub fn current() -> Credentials {
+        let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        let mut groups = vec![0 as libc::gid_t; count.max(0) as usize];
+        let count = unsafe { libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr()) };
+        groups.truncate(count.max(0) as usize);
+        Credentials {
+            owner: Owner::effective(),
+            groups,
+        }
+    }
+
+    pub fn of(owner: Owner) -> Credentials {
+        Credentials {
+            owner,
+            groups: owner.groups(),
+        }
+    }
+
+    pub fn without_groups(self) -> Credentials {
+        Credentials {
+            owner: self.owner,
+            groups: Vec::new(),
+        }
+    }
+}
+
+// Which permission bits let the credentials read the archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grant {
+    Root,
+    Owner,
+    // The primary group, or a supplementary one.
+    Group(Gid),
+    Other,
+}
+
+// Check `path` is readable with `credentials` the way the kernel would,
+// ignoring ACLs, so a failure names the missing permission rather than
+// surfacing as squashfuse exiting.
+pub fn check(path: &Path, credentials: &Credentials) -> Result<Grant, failure::Error> {
+    let attr = fs::metadata(path)?;
+    let (uid, gid) = (
+        credentials.owner.uid.as_raw(),
+        credentials.owner.gid.as_raw(),
+    );
+    let mode = attr.mode();
+    let unreadable = |reason: String| -> failure::Error {
+        XarError::Unreadable {
+            path: path.to_string_lossy().into_owned(),
+            reason,
+        }
+        .into()
+    };
+
+    if uid == 0 {
+        return Ok(Grant::Root);
+    }
+    // Like the kernel, only the most specific class applies.
+    if attr.uid() == uid {
+        if mode & 0o400 == 0 {
+            return Err(unreadable(format!(
+                "owner uid {} lacks read permission",
+                uid
+            )));
+        }
+        return Ok(Grant::Owner);
+    }
+    if attr.gid() == gid || credentials.groups.contains(&attr.gid()) {
+        if mode & 0o040 == 0 {
+            return Err(unreadable(format!(
+                "group {} lacks read permission",
+                attr.gid()
+            )));
+        }
+        return Ok(Grant::Group(Gid::from_raw(attr.gid())));
+    }
+    if mode & 0o004 == 0 {
+        let reason = if mode & 0o040 != 0 {
+            format!(
+                "uid {} isn't in group {}, the only one that may read it",
+                uid,
+                attr.gid()
+            )
+        } else {
+            format!("it isn't readable by uid {}", uid)
+        };
+        return Err(unreadable(reason));
+    }
+    Ok(Grant::Other)
+}
@@ -0,0 +1,145 @@
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PROC: &str = "/proc";
+const UNUSED_POLL: Duration = Duration::from_millis(100);
+
+// A process holding something under a mountpoint, which unmounting would
+// break or be refused by.
+pub struct Process {
+    pub pid: u32,
+    pub uid: u32,
+    pub comm: String,
+    // What refers to the mount: cwd, exe, root, fd or maps.
+    pub uses: Vec<&'static str>,
+}
+
+fn link_under(link: &Path, mount: &Path) -> bool {
+    fs::read_link(link)
+        .map(|target| target.starts_with(mount))
+        .unwrap_or(false)
+}
+
+// Mapped files are the last field, after the inode.
+fn maps_under(maps: &Path, mount: &Path) -> bool {
+    let contents = match fs::read(maps) {
+        Ok(contents) => contents,
+        Err(_) => return false,
+    };
+    String::from_utf8_lossy(&contents).lines().any(|line| {
+        line.find('/')
+            .map(|start| Path::new(&line[start..]).starts_with(mount))
+            .unwrap_or(false)
+    })
+}
+
+fn inspect(dir: &Path, pid: u32, mount: &Path) -> Option<Process> {
+    let mut uses = Vec::new();
+    for link in &["cwd", "exe", "root"] {
+        if link_under(&dir.join(link), mount) {
+            uses.push(*link);
+        }
+    }
+    let fds = fs::read_dir(dir.join("fd")).ok();
+    let has_fd = fds
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .any(|fd| link_under(&fd.path(), mount));
+    if has_fd {
+        uses.push("fd");
+    }
+    if maps_under(&dir.join("maps"), mount) {
+        uses.push("maps");
+    }
+    if uses.is_empty() {
+        return None;
+    }
+    Some(Process {
+        pid,
+        uid: fs::metadata(dir).ok()?.uid(),
+        comm: fs::read_to_string(dir.join("comm"))
+            .map(|comm| comm.trim_end().to_string())
+            .unwrap_or_default(),
+        uses,
+    })
+}
+
+// Every process, other than this one, using `mount`. Processes whose /proc
+// entries we can't read, usually other users', are missed.
+pub fn using(mount: &Path) -> Result<Vec<Process>, failure::Error> {
+    let mount: PathBuf = mount.canonicalize().unwrap_or_else(|_| mount.to_path_buf());
+    let own = std::process::id();
+    let mut processes = Vec::new();
+    for entry in fs::read_dir(PROC)? {
+        let entry = entry?;
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) if pid != own => pid,
+            _ => continue,
+        };
+        if let Some(process) = inspect(&entry.path(), pid, &mount) {
+            processes.push(process);
+        }
+    }
+    Ok(processes)
+}
+
+// Accepts TERM, SIGTERM or a number.
+pub fn parse_signal(name: &str) -> Result<Signal, failure::Error> {
+    if let Ok(number) = name.parse::<i32>() {
+        return Ok(Signal::from_c_int(number)?);
+    }
+    let name = name.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).map_err(|_| format_err!("unknown signal {}", name))
+}
+
+pub fn signal_all(
+    logger: &slog::Logger,
+    processes: &[Process],
+    signal: Signal,
+) -> Result<(), failure::Error> {
+    for process in processes {
+        debug!(
+            logger,
+            "Signalling";
+            "pid" => process.pid,
+            "comm" => &process.comm,
+            "signal" => format!("{:?}", signal)
+        );
+        match kill(Pid::from_raw(process.pid as i32), signal) {
+            // It exited in the meantime.
+            Err(nix::Error::Sys(Errno::ESRCH)) => (),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+// Wait until nothing uses `mount`, returning the processes still using it
+// after `timeout`.
+pub fn wait_until_unused(mount: &Path, timeout: Duration) -> Result<Vec<Process>, failure::Error> {
+    let start = Instant::now();
+    loop {
+        let processes = using(mount)?;
+        if processes.is_empty() || start.elapsed() >= timeout {
+            return Ok(processes);
+        }
+        thread::sleep(UNUSED_POLL);
+    }
+}
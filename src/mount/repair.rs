@@ -0,0 +1,410 @@
+use crate::mount::events::{self, Event};
+use crate::mount::lock::{failfile, is_expired_at, lockfile, originfile, pinfile, Lock};
+use crate::mount::owner::Owner;
+use crate::mount::schema;
+use crate::mount::watchdog;
+use crate::mount::workspace;
+
+use nix::unistd::{self, Gid, Uid};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+const LOCKFILE_PREFIX: &str = "lockfile.";
+const USER_PREFIX: &str = "uid-";
+const STATE_PREFIXES: &[&str] = &["pinned.", "failed.", "origin.", "work.", "held.", "holder."];
+
+pub struct Repair {
+    pub path: PathBuf,
+    pub problem: &'static str,
+    pub action: String,
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}, {}",
+            self.path.display(),
+            self.problem,
+            self.action
+        )
+    }
+}
+
+// Unmounting is lazy, an aborted connection can't answer the kernel's
+// questions about whether it's busy.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn force_unmount(mount: &Path) -> Command {
+    let mut cmd = Command::new("umount");
+    cmd.arg("-f").arg(mount);
+    cmd
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+fn force_unmount(mount: &Path) -> Command {
+    let mut cmd = Command::new("fusermount");
+    cmd.arg("-u").arg("-z").arg(mount);
+    cmd
+}
+
+// An ordinary unmount, which leaves mounts something is still using alone.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn unmount(mount: &Path) -> Command {
+    let mut cmd = Command::new("umount");
+    cmd.arg(mount);
+    cmd
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+fn unmount(mount: &Path) -> Command {
+    let mut cmd = Command::new("fusermount");
+    cmd.arg("-u").arg(mount);
+    cmd
+}
+
+// Once squashfuse dies or its connection under /sys/fs/fuse/connections is
+// aborted, every access to the mountpoint fails with ENOTCONN.
+fn is_disconnected(error: &failure::Error) -> bool {
+    match error.downcast_ref::<io::Error>() {
+        Some(e) => e.raw_os_error() == Some(libc::ENOTCONN),
+        None => false,
+    }
+}
+
+// Anything but a clean ENOENT, including a hung mount, means the path exists.
+fn exists(path: &Path) -> bool {
+    match watchdog::symlink_metadata(path) {
+        Ok(_) => true,
+        Err(e) => match e.downcast_ref::<io::Error>() {
+            Some(e) => e.kind() != io::ErrorKind::NotFound,
+            None => true,
+        },
+    }
+}
+
+// Whose directory is being repaired and, with `drop`, that unmounts run as
+// them. fusermount only lets a user unmount their own mounts, so a janitor
+// dropping to each owner can't unmount anything the owner couldn't.
+#[derive(Clone, Copy)]
+struct Repairer {
+    owner: Owner,
+    drop: bool,
+}
+
+// Locking may create the lockfile, which must stay usable by its owner when
+// we're root repairing someone else's directory.
+fn lock(mount: &PathBuf, repairer: Repairer) -> Result<Lock, failure::Error> {
+    let lock = Lock::directory(mount)?;
+    if !repairer.owner.is_effective() {
+        unistd::chown(
+            &lockfile(mount),
+            Some(repairer.owner.uid),
+            Some(repairer.owner.gid),
+        )?;
+    }
+    Ok(lock)
+}
+
+fn repair_entry(
+    logger: &slog::Logger,
+    userdir: &Path,
+    name: &str,
+    repairer: Repairer,
+) -> Result<Option<Repair>, failure::Error> {
+    if let Some(mount_dir) = name.strip_prefix(LOCKFILE_PREFIX) {
+        let mount = userdir.join(mount_dir);
+        if exists(&mount) {
+            return Ok(None);
+        }
+        let _lock = lock(&mount, repairer)?;
+        // Someone may have created the mount while we waited on the lock.
+        if exists(&mount) {
+            return Ok(None);
+        }
+        fs::remove_file(lockfile(&mount))?;
+        return Ok(Some(Repair {
+            path: lockfile(&mount),
+            problem: "lockfile without a mount directory",
+            action: "removed".to_string(),
+        }));
+    }
+    if STATE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+        return Ok(None);
+    }
+
+    let mount = userdir.join(name);
+    let attr = match watchdog::symlink_metadata(&mount) {
+        Ok(attr) => attr,
+        Err(ref e) if watchdog::is_unresponsive(e) => {
+            return Ok(Some(Repair {
+                path: mount,
+                problem: "unresponsive",
+                action: "skipped, see unmount --abort".to_string(),
+            }));
+        }
+        Err(ref e) if is_disconnected(e) => {
+            let _lock = lock(&mount, repairer)?;
+            debug!(logger, "Unmounting"; "mount" => mount.display().to_string());
+            let mut cmd = force_unmount(&mount);
+            if repairer.drop {
+                cmd.uid(repairer.owner.uid.as_raw())
+                    .gid(repairer.owner.gid.as_raw());
+            }
+            let status = cmd.status()?;
+            let action = if status.success() {
+                "unmounted".to_string()
+            } else {
+                format!("unmount failed: {}", status)
+            };
+            return Ok(Some(Repair {
+                path: mount,
+                problem: "FUSE connection aborted",
+                action,
+            }));
+        }
+        Err(e) => return Err(e),
+    };
+
+    // After a user is renumbered their old mount directories keep the old
+    // uid. Empty ones can be removed, they're recreated with the right owner.
+    // Live mounts report the owner from inside the archive, so skip those.
+    let unmounted = attr.dev() == fs::metadata(userdir)?.dev();
+    if unmounted && attr.is_dir() && attr.uid() != repairer.owner.uid.as_raw() {
+        let _lock = lock(&mount, repairer)?;
+        let action = match fs::remove_dir(&mount) {
+            Ok(()) => "removed".to_string(),
+            Err(e) => format!("not removed: {}", e),
+        };
+        return Ok(Some(Repair {
+            path: mount,
+            problem: "directory owned by another uid",
+            action,
+        }));
+    }
+    Ok(None)
+}
+
+// Find and fix leftovers in a user's directory, e.g. <mount_root>/uid-N. Each
+// fix is made holding the affected mount's lock.
+pub fn repair(logger: &slog::Logger, userdir: &Path) -> Result<Vec<Repair>, failure::Error> {
+    let repairer = Repairer {
+        owner: Owner::invoking(),
+        drop: false,
+    };
+    visit(logger, userdir, repairer, repair_entry)
+}
+
+// Unmount and remove the expired, unpinned mounts in a user's directory,
+// along with their workspaces and state files.
+pub fn collect(logger: &slog::Logger, userdir: &Path) -> Result<Vec<Repair>, failure::Error> {
+    let repairer = Repairer {
+        owner: Owner::invoking(),
+        drop: false,
+    };
+    visit(logger, userdir, repairer, collect_entry)
+}
+
+// Unmount and remove a mount whose lease has expired, unless it's pinned,
+// its lock is held or whatever uses it keeps it busy. These are the rules
+// stats counts expired mounts by, checked again holding the lock.
+fn collect_entry(
+    logger: &slog::Logger,
+    userdir: &Path,
+    name: &str,
+    repairer: Repairer,
+) -> Result<Option<Repair>, failure::Error> {
+    let mount = match name.strip_prefix(LOCKFILE_PREFIX) {
+        Some(mount_dir) => userdir.join(mount_dir),
+        None => return Ok(None),
+    };
+    let is_collectable = |mount: &PathBuf| -> Result<bool, failure::Error> {
+        Ok(exists(mount) && !pinfile(mount).exists() && is_expired_at(&lockfile(mount))?)
+    };
+    if !is_collectable(&mount)? {
+        return Ok(None);
+    }
+    // A lock someone holds means the mount is in use.
+    let _lock = match Lock::directory_until(logger, &mount, Some(Instant::now())) {
+        Ok(lock) => lock,
+        Err(_) => return Ok(None),
+    };
+    if !is_collectable(&mount)? {
+        return Ok(None);
+    }
+
+    let attr = watchdog::symlink_metadata(&mount)?;
+    if attr.dev() != fs::metadata(userdir)?.dev() {
+        debug!(logger, "Unmounting"; "mount" => mount.display().to_string());
+        let mut cmd = unmount(&mount);
+        if repairer.drop {
+            cmd.uid(repairer.owner.uid.as_raw())
+                .gid(repairer.owner.gid.as_raw());
+        }
+        let status = cmd.status()?;
+        if !status.success() {
+            return Ok(Some(Repair {
+                path: mount,
+                problem: "lease expired",
+                action: format!("unmount failed: {}", status),
+            }));
+        }
+    } else if attr.uid() != repairer.owner.uid.as_raw() {
+        // Left for repair, which knows about renumbered users.
+        return Ok(None);
+    }
+    workspace::remove(logger, &mount)?;
+    fs::remove_dir(&mount)?;
+    for state in &[originfile(&mount), failfile(&mount), lockfile(&mount)] {
+        match fs::remove_file(state) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            result => result?,
+        }
+    }
+    Ok(Some(Repair {
+        path: mount,
+        problem: "lease expired",
+        action: "removed".to_string(),
+    }))
+}
+
+// Run `fix` on every entry of a user's directory, once its owner and schema
+// check out.
+fn visit<F>(
+    logger: &slog::Logger,
+    userdir: &Path,
+    repairer: Repairer,
+    fix: F,
+) -> Result<Vec<Repair>, failure::Error>
+where
+    F: Fn(&slog::Logger, &Path, &str, Repairer) -> Result<Option<Repair>, failure::Error>,
+{
+    let mut repairs = Vec::new();
+    let entries = match fs::read_dir(userdir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(repairs),
+        Err(e) => return Err(e.into()),
+    };
+
+    let attr = fs::metadata(userdir)?;
+    if attr.uid() != repairer.owner.uid.as_raw() {
+        repairs.push(Repair {
+            path: userdir.to_path_buf(),
+            problem: "directory owned by another uid",
+            action: format!("needs `chown {} {}`", repairer.owner.uid, userdir.display()),
+        });
+        return Ok(repairs);
+    }
+    if !schema::is_readable(userdir) {
+        repairs.push(Repair {
+            path: userdir.to_path_buf(),
+            problem: "written by a newer xarfuse",
+            action: "skipped".to_string(),
+        });
+        return Ok(repairs);
+    }
+
+    for entry in entries {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        match fix(logger, userdir, &name, repairer) {
+            Ok(Some(repair)) => {
+                debug!(logger, "Repaired"; "repair" => repair.to_string());
+                if repair.action == "removed" || repair.action == "unmounted" {
+                    events::emit(Event::Reaped {
+                        path: repair.path.clone(),
+                        problem: repair.problem,
+                        action: repair.action.clone(),
+                    });
+                }
+                repairs.push(repair);
+            }
+            Ok(None) => (),
+            Err(e) => {
+                warn!(logger, "Unable to repair"; "entry" => name, "error" => e.to_string())
+            }
+        }
+    }
+    Ok(repairs)
+}
+
+// Repair every user's directory under `root`, for janitors running as root.
+// Only uid-N directories owned by uid N are entered; anything else is
+// reported and left alone. With `drop`, unmounts run as each directory's
+// owner.
+pub fn repair_all_users(
+    logger: &slog::Logger,
+    root: &Path,
+    drop: bool,
+) -> Result<Vec<Repair>, failure::Error> {
+    all_users(logger, root, drop, repair_entry)
+}
+
+// collect for every user's directory under `root`, entered as for
+// repair_all_users.
+pub fn collect_all_users(
+    logger: &slog::Logger,
+    root: &Path,
+    drop: bool,
+) -> Result<Vec<Repair>, failure::Error> {
+    all_users(logger, root, drop, collect_entry)
+}
+
+fn all_users<F>(
+    logger: &slog::Logger,
+    root: &Path,
+    drop: bool,
+    fix: F,
+) -> Result<Vec<Repair>, failure::Error>
+where
+    F: Fn(&slog::Logger, &Path, &str, Repairer) -> Result<Option<Repair>, failure::Error> + Copy,
+{
+    if !unistd::geteuid().is_root() {
+        bail!("Working on every user's directory needs root");
+    }
+    let mut repairs = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let uid: u32 = match name.strip_prefix(USER_PREFIX).map(str::parse) {
+            Some(Ok(uid)) => uid,
+            _ => continue,
+        };
+        let userdir = entry.path();
+        // Not followed, a symlink could lead anywhere.
+        let attr = fs::symlink_metadata(&userdir)?;
+        if !attr.is_dir() || attr.uid() != uid {
+            repairs.push(Repair {
+                path: userdir,
+                problem: "unexpected owner or file type",
+                action: "skipped".to_string(),
+            });
+            continue;
+        }
+        let repairer = Repairer {
+            owner: Owner {
+                uid: Uid::from_raw(uid),
+                gid: Gid::from_raw(attr.gid()),
+            },
+            drop,
+        };
+        match visit(logger, &userdir, repairer, fix) {
+            Ok(mut repaired) => repairs.append(&mut repaired),
+            Err(e) => {
+                warn!(logger, "Unable to repair"; "dir" => userdir.display().to_string(), "error" => e.to_string())
+            }
+        }
+    }
+    Ok(repairs)
+}
@@ -0,0 +1,70 @@
+use crate::error::XarError;
+
+use std::fs;
+use std::io;
+
+// Descriptors a single mount holds at once: the archive handle and file, the
+// lockfile and a directory being verified.
+pub const FDS_PER_MOUNT: u64 = 4;
+
+#[cfg(target_os = "linux")]
+const FD_DIR: &str = "/proc/self/fd";
+#[cfg(not(target_os = "linux"))]
+const FD_DIR: &str = "/dev/fd";
+
+fn nofile() -> io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+pub fn open_fds() -> io::Result<u64> {
+    // Less the descriptor read_dir itself holds.
+    Ok(fs::read_dir(FD_DIR)?.count().saturating_sub(1) as u64)
+}
+
+// Raise the soft nofile limit to `wanted`, or as close as allowed. Beyond the
+// hard limit needs CAP_SYS_RESOURCE, without it settle for the hard limit.
+pub fn raise_nofile(logger: &slog::Logger, wanted: u64) -> io::Result<u64> {
+    let limit = nofile()?;
+    let current = limit.rlim_cur as u64;
+    if current >= wanted {
+        return Ok(current);
+    }
+    let mut raised = libc::rlimit {
+        rlim_cur: wanted as libc::rlim_t,
+        rlim_max: limit.rlim_max.max(wanted as libc::rlim_t),
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+        raised = libc::rlimit {
+            rlim_cur: limit.rlim_max,
+            rlim_max: limit.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    debug!(
+        logger,
+        "Raised nofile limit";
+        "from" => current,
+        "to" => raised.rlim_cur as u64
+    );
+    Ok(raised.rlim_cur as u64)
+}
+
+// Fail with a clear error before a mount would run out of descriptors, rather
+// than with EMFILE from somewhere inside it.
+pub fn check_headroom(needed: u64) -> Result<(), failure::Error> {
+    let limit = nofile()?.rlim_cur as u64;
+    let open = open_fds()?;
+    if open + needed > limit {
+        return Err(XarError::TooManyOpenFiles { open, limit }.into());
+    }
+    Ok(())
+}
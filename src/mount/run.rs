@@ -0,0 +1,60 @@
+use crate::mount::directory::Directory;
+use crate::mount::resolve_in_mount;
+use crate::xar::Xar;
+
+use std::ffi::OsString;
+use std::process::{Child, Command, ExitStatus};
+
+impl Xar {
+    // Start every command against a single mount and wait for all of them.
+    // Each command is a path inside the archive followed by its arguments.
+    // When `unmount` is set the mount is released once the last child exits.
+    pub fn run_many(
+        &self,
+        mount: &Directory,
+        commands: &[Vec<OsString>],
+        unmount: bool,
+    ) -> Result<Vec<ExitStatus>, failure::Error> {
+        self.mount_if_needed(mount)?;
+
+        let mut children: Vec<Child> = Vec::new();
+        for command in commands {
+            let (program, args) = match command.split_first() {
+                Some(split) => split,
+                None => bail!("empty command"),
+            };
+            let program = match program.to_str() {
+                Some(program) => program,
+                None => bail!("command {:?} must be UTF-8", program),
+            };
+            let path = resolve_in_mount(mount, "command", program)?;
+            debug!(
+                self.logger,
+                "Starting";
+                "command" => path.display().to_string()
+            );
+            match Command::new(&path).args(args).spawn() {
+                Ok(child) => children.push(child),
+                Err(e) => {
+                    // Don't leave the commands already started unattended.
+                    for child in &mut children {
+                        let _ = child.wait();
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let mut statuses = Vec::new();
+        for child in &mut children {
+            let status = child.wait()?;
+            debug!(self.logger, "Exited"; "pid" => child.id(), "status" => status.to_string());
+            statuses.push(status);
+        }
+
+        if unmount {
+            mount.unmount()?;
+        }
+        Ok(statuses)
+    }
+}
@@ -0,0 +1,102 @@
+use crate::mount::owner::Owner;
+
+use nix::fcntl::{self, FlockArg};
+use nix::unistd::chown;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+// Each user's directory records the version of the layout and state file
+// formats it was written with, as `schema=<version>`. Directories from before
+// the marker are version 0.
+pub const SCHEMA_FILE: &str = ".schema";
+// 1: key=value lockfiles with leases, and pinned, failed, origin and work
+// state beside each mount directory.
+pub const CURRENT: u32 = 1;
+
+// Migrations from version i to i + 1, applied in order to directories written
+// by older binaries while the schema file is locked.
+const MIGRATIONS: &[fn(&Path) -> Result<(), failure::Error>] = &[migrate_0_to_1];
+
+// Version 0 lockfiles are empty or already hold leases, which readers treat
+// alike, so there's nothing to convert.
+fn migrate_0_to_1(_userdir: &Path) -> Result<(), failure::Error> {
+    Ok(())
+}
+
+fn parse(contents: &str) -> Result<u32, failure::Error> {
+    for line in contents.lines() {
+        if let Some(version) = line.strip_prefix("schema=") {
+            return Ok(version.trim().parse()?);
+        }
+    }
+    Ok(0)
+}
+
+fn read_from(file: &mut File) -> Result<u32, failure::Error> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut contents)?;
+    parse(&contents)
+}
+
+// The version `userdir` was written with, 0 when it predates versioning.
+pub fn read(userdir: &Path) -> Result<u32, failure::Error> {
+    match fs::read_to_string(userdir.join(SCHEMA_FILE)) {
+        Ok(contents) => parse(&contents),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Whether this binary understands `userdir`. Readers such as repair and
+// stats skip directories it doesn't.
+pub fn is_readable(userdir: &Path) -> bool {
+    match read(userdir) {
+        Ok(version) => version <= CURRENT,
+        Err(_) => false,
+    }
+}
+
+// Bring `userdir` up to the current version before it's written to, refusing
+// directories from a newer xarfuse whose formats we'd misread.
+pub fn ensure(logger: &slog::Logger, userdir: &Path, owner: Owner) -> Result<(), failure::Error> {
+    if read(userdir).ok() == Some(CURRENT) {
+        return Ok(());
+    }
+
+    let path = userdir.join(SCHEMA_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)?;
+    if !owner.is_effective() {
+        chown(&path, Some(owner.uid), Some(owner.gid))?;
+    }
+    fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+
+    let version = read_from(&mut file)?;
+    if version > CURRENT {
+        bail!(
+            "{} was written by a newer xarfuse, schema {} rather than {}",
+            userdir.display(),
+            version,
+            CURRENT
+        );
+    }
+    for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        debug!(logger, "Migrating"; "dir" => userdir.display().to_string(), "from" => from);
+        migrate(userdir)?;
+    }
+    if version < CURRENT {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(format!("schema={}\n", CURRENT).as_bytes())?;
+    }
+    Ok(())
+}
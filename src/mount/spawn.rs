@@ -0,0 +1,211 @@
+use crate::error::XarError;
+use crate::exec::EnvPolicy;
+use crate::mount::owner::Owner;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Everything else in the caller's environment is withheld from squashfuse.
+const SQUASHFUSE_ENV: &[&str] = &["PATH", "LANG", "LC_ALL", "LC_CTYPE"];
+// Bounds the descriptors marked close-on-exec when the limit is unbounded.
+const MAX_FDS: libc::c_int = 65536;
+// How often a child with a deadline is checked on.
+const CHILD_POLL: Duration = Duration::from_millis(10);
+// From linux/ioprio.h.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+// An I/O scheduling class and, except for idle, a level from 0, highest, to
+// 7, written CLASS[:LEVEL] as for ionice, e.g. best-effort:7 or idle.
+#[derive(Clone, Copy, Debug)]
+pub struct IoPriority {
+    class: u32,
+    level: u32,
+}
+
+impl FromStr for IoPriority {
+    type Err = failure::Error;
+
+    fn from_str(value: &str) -> Result<IoPriority, failure::Error> {
+        let mut parts = value.splitn(2, ':');
+        let class = match parts.next().unwrap_or_default() {
+            "realtime" | "rt" | "1" => 1,
+            "best-effort" | "be" | "2" => 2,
+            "idle" | "3" => 3,
+            class => bail!("I/O class {} must be realtime, best-effort or idle", class),
+        };
+        let level = match parts.next() {
+            Some(level) => level.parse()?,
+            None => 4,
+        };
+        if level > 7 {
+            bail!("I/O priority level {} must be between 0 and 7", level);
+        }
+        Ok(IoPriority { class, level })
+    }
+}
+
+// Start squashfuse with as little of our state as possible: a minimal
+// environment, default signal dispositions, no inherited descriptors beyond
+// stdio and `inherit`, and, when `drop_groups` is set, no supplementary
+// groups. With `run_as` it switches to that user and their groups.
+// Registered last, so earlier pre_exec hooks may still use their
+// descriptors and privileges.
+pub fn harden(
+    cmd: &mut Command,
+    drop_groups: bool,
+    run_as: Option<Owner>,
+    inherit: Option<libc::c_int>,
+) {
+    let env_policy = EnvPolicy {
+        clear: true,
+        allow: SQUASHFUSE_ENV.iter().map(|name| name.to_string()).collect(),
+        set: Vec::new(),
+    };
+    env_policy.apply(cmd);
+
+    // Looked up now, the group database can't be read safely after fork.
+    let groups = match run_as {
+        Some(owner) if !drop_groups => owner.groups(),
+        _ => Vec::new(),
+    };
+
+    unsafe {
+        cmd.pre_exec(move || {
+            // Ignored signals stay ignored across exec.
+            for signal in 1..32 {
+                if signal != libc::SIGKILL && signal != libc::SIGSTOP {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+            }
+
+            // Marking rather than closing keeps std's exec error pipe working.
+            let limit = libc::sysconf(libc::_SC_OPEN_MAX);
+            let limit = if limit <= 0 || limit > MAX_FDS as libc::c_long {
+                MAX_FDS
+            } else {
+                limit as libc::c_int
+            };
+            for fd in 3..limit {
+                if Some(fd) == inherit {
+                    libc::fcntl(fd, libc::F_SETFD, 0);
+                } else {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+            }
+
+            if let Some(owner) = run_as {
+                if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                    || libc::setgid(owner.gid.as_raw()) != 0
+                    || libc::setuid(owner.uid.as_raw()) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+            } else if drop_groups && libc::setgroups(0, std::ptr::null()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+// Send a helper's stdout to our stderr, so stdout only ever carries the
+// output xarfuse was asked for, e.g. `$(xarfuse mount -n ...)`.
+pub fn stdout_to_stderr(cmd: &mut Command) -> Result<(), failure::Error> {
+    let fd = unsafe { libc::fcntl(libc::STDERR_FILENO, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    cmd.stdout(Stdio::from(unsafe { File::from_raw_fd(fd) }));
+    Ok(())
+}
+
+// Lower squashfuse's CPU and I/O priority. Set before exec rather than on the
+// child afterwards, so the daemon squashfuse forks inherits them.
+pub fn deprioritize(cmd: &mut Command, nice: Option<i32>, ionice: Option<IoPriority>) {
+    if nice.is_none() && ionice.is_none() {
+        return;
+    }
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            #[cfg(target_os = "linux")]
+            {
+                if let Some(ionice) = ionice {
+                    let priority = (ionice.class << IOPRIO_CLASS_SHIFT) | ionice.level;
+                    if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+// Send a helper's stderr to an unlinked file rather than a pipe, which a
+// daemonized child could hold open. Read it back with `captured`.
+pub fn capture_stderr(cmd: &mut Command) -> Result<File, failure::Error> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or_default();
+    let name = format!("xarfuse-stderr.{}.{}", std::process::id(), nanos);
+    let path = env::temp_dir().join(name);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    cmd.stderr(file.try_clone()?);
+    Ok(file)
+}
+
+pub fn captured(mut file: File) -> String {
+    let mut output = String::new();
+    if file.seek(SeekFrom::Start(0)).is_ok() {
+        let mut bytes = Vec::new();
+        let _ = file.read_to_end(&mut bytes);
+        output = String::from_utf8_lossy(&bytes).trim_end().to_string();
+    }
+    output
+}
+
+// Wait for `child` to exit, killing it if it's still running at `deadline`.
+pub fn wait_until(
+    child: &mut Child,
+    deadline: Option<Instant>,
+    program: &str,
+) -> Result<ExitStatus, failure::Error> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(child.wait()?),
+    };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(XarError::Timeout {
+                phase: format!("spawning {}", program),
+            }
+            .into());
+        }
+        std::thread::sleep(CHILD_POLL);
+    }
+}
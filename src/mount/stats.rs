@@ -0,0 +1,142 @@
+use crate::mount::directory::SYSTEM_BASEDIR;
+use crate::mount::lock::{is_expired_at, pinfile};
+use crate::mount::schema;
+use crate::mount::watchdog;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const LOCKFILE_PREFIX: &str = "lockfile.";
+const WORKSPACE_PREFIX: &str = "work.";
+const STATE_PREFIXES: &[&str] = &["pinned.", "failed.", "origin.", "held.", "holder."];
+const USER_PREFIX: &str = "uid-";
+
+// Usage of one or more mount roots, gathered without taking any locks.
+#[derive(Default)]
+pub struct Stats {
+    pub roots: Vec<PathBuf>,
+    // Live mounts per owner, uid-N or system.
+    pub mounts: BTreeMap<String, usize>,
+    // Bytes allocated to workspaces, which live in the mount root.
+    pub workspace_bytes: u64,
+    pub workspaces: usize,
+    pub oldest_lock: Option<Duration>,
+    pub newest_lock: Option<Duration>,
+    // Unpinned mounts whose lease has expired, mount directories left behind
+    // by an unmount, and lockfiles without a mount directory.
+    pub expired: usize,
+    pub unmounted: usize,
+    pub orphaned_locks: usize,
+}
+
+// Space allocated to a tree, counted in blocks so sparse files aren't
+// overcounted. Entries vanishing underneath us are skipped.
+fn allocated(path: &Path) -> io::Result<u64> {
+    let attr = fs::symlink_metadata(path)?;
+    let mut bytes = attr.blocks() * 512;
+    if attr.is_dir() {
+        for entry in fs::read_dir(path)? {
+            match allocated(&entry?.path()) {
+                Ok(size) => bytes += size,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+fn age(path: &Path, now: SystemTime) -> io::Result<Duration> {
+    let modified = fs::symlink_metadata(path)?.modified()?;
+    Ok(now.duration_since(modified).unwrap_or_default())
+}
+
+impl Stats {
+    fn add_basedir(self: &mut Stats, logger: &slog::Logger, basedir: &Path, owner: &str) {
+        if !schema::is_readable(basedir) {
+            debug!(logger, "Skipping"; "dir" => basedir.display().to_string(), "reason" => "schema");
+            return;
+        }
+        let entries = match fs::read_dir(basedir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!(logger, "Skipping"; "dir" => basedir.display().to_string(), "error" => e.to_string());
+                return;
+            }
+        };
+        let dev = match fs::metadata(basedir) {
+            Ok(attr) => attr.dev(),
+            Err(_) => return,
+        };
+        let now = SystemTime::now();
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let path = entry.path();
+            if let Some(mount_dir) = name.strip_prefix(LOCKFILE_PREFIX) {
+                if let Ok(age) = age(&path, now) {
+                    self.oldest_lock = self.oldest_lock.max(Some(age));
+                    self.newest_lock = Some(self.newest_lock.map_or(age, |newest| newest.min(age)));
+                }
+                let mount = basedir.join(mount_dir);
+                if !mount.exists() {
+                    self.orphaned_locks += 1;
+                } else if !pinfile(&mount).exists() && is_expired_at(&path).unwrap_or(false) {
+                    self.expired += 1;
+                }
+                continue;
+            }
+            if name.starts_with(WORKSPACE_PREFIX) {
+                match allocated(&path) {
+                    Ok(bytes) => {
+                        self.workspaces += 1;
+                        self.workspace_bytes += bytes;
+                    }
+                    Err(e) => {
+                        debug!(logger, "Unable to size"; "workspace" => path.display().to_string(), "error" => e.to_string())
+                    }
+                }
+                continue;
+            }
+            if STATE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+                continue;
+            }
+
+            // A hung mount is still a mount.
+            match watchdog::symlink_metadata(&path) {
+                Ok(ref attr) if attr.is_dir() && attr.dev() == dev => self.unmounted += 1,
+                Ok(ref attr) if !attr.is_dir() => (),
+                _ => *self.mounts.entry(owner.to_string()).or_insert(0) += 1,
+            }
+        }
+    }
+}
+
+// Summarize the per-user and system directories under each of `roots`.
+pub fn gather(logger: &slog::Logger, roots: &[PathBuf]) -> Stats {
+    let mut stats = Stats::default();
+    for root in roots {
+        let entries = match fs::read_dir(root) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        stats.roots.push(root.clone());
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if name == SYSTEM_BASEDIR || name.starts_with(USER_PREFIX) {
+                stats.add_basedir(logger, &entry.path(), &name);
+            }
+        }
+    }
+    stats
+}
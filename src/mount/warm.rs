@@ -0,0 +1,83 @@
+use crate::mount::directory::Directory;
+use crate::mount::resolve_in_mount;
+use crate::xar::Xar;
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Read every file under `path`, so later reads come from the page cache.
+// Symlinks aren't followed, they may point outside the mount or loop.
+fn warm_path(path: &Path) -> Result<(), failure::Error> {
+    let file_type = fs::symlink_metadata(path)?.file_type();
+    if file_type.is_dir() {
+        for entry in fs::read_dir(path)? {
+            warm_path(&entry?.path())?;
+        }
+    } else if file_type.is_file() {
+        io::copy(&mut File::open(path)?, &mut io::sink())?;
+    }
+    Ok(())
+}
+
+// Pre-fault `paths`, relative to the mount's root. Missing paths are logged
+// rather than failing, as warming is only an optimisation.
+pub fn warm(logger: &slog::Logger, mount: &Directory, paths: &[String]) {
+    for path in paths {
+        let result = resolve_in_mount(mount, "warm path", path).and_then(|path| warm_path(&path));
+        if let Err(e) = result {
+            warn!(logger, "Unable to warm"; "path" => path, "error" => e.to_string());
+        }
+    }
+}
+
+impl Xar {
+    // Paths to warm from --warm, or otherwise the header's WARM_PATHS.
+    // Either is a colon separated list.
+    pub fn warm_paths(&self, requested: Option<&str>) -> Vec<String> {
+        requested
+            .or_else(|| self.header.warm_paths.as_deref())
+            .map(|list| {
+                list.split(':')
+                    .filter(|path| !path.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Warm `paths` from a detached `xarfuse warm`, so mount returns at once.
+    pub fn warm_in_background(
+        &self,
+        mount: &Directory,
+        paths: &[String],
+    ) -> Result<(), failure::Error> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let mut cmd = Command::new(std::env::current_exe()?);
+        cmd.arg("warm")
+            .arg(&mount.path)
+            .args(paths.iter().map(OsString::from))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::setsid();
+                Ok(())
+            });
+        }
+        let child = cmd.spawn()?;
+        debug!(
+            self.logger,
+            "Warming in the background";
+            "mount" => mount.path.display().to_string(),
+            "pid" => child.id()
+        );
+        Ok(())
+    }
+}
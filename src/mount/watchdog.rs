@@ -0,0 +1,56 @@
+use crate::error::XarError;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// A hung squashfuse daemon wedges any syscall touching its mountpoint, so
+// every probe of a path which may be under a FUSE mount goes through here.
+const XAR_PROBE_TIMEOUT: &str = "XAR_PROBE_TIMEOUT";
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn timeout() -> Duration {
+    env::var(XAR_PROBE_TIMEOUT)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PROBE_TIMEOUT)
+}
+
+// Run `probe` on a helper thread, giving up on it with XarError::Unresponsive
+// after the timeout. A probe which never returns leaks its thread, which is
+// the price of not hanging the caller.
+pub fn probe<T, F>(path: &Path, probe: F) -> Result<T, failure::Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, failure::Error> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(probe());
+    });
+    let timeout = timeout();
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(XarError::Unresponsive {
+            path: path.to_string_lossy().into_owned(),
+            secs: timeout.as_secs(),
+        }
+        .into()),
+    }
+}
+
+pub fn symlink_metadata(path: &Path) -> Result<fs::Metadata, failure::Error> {
+    let owned = PathBuf::from(path);
+    probe(path, move || Ok(fs::symlink_metadata(&owned)?))
+}
+
+pub fn is_unresponsive(error: &failure::Error) -> bool {
+    match error.downcast_ref::<XarError>() {
+        Some(XarError::Unresponsive { .. }) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,35 @@
+use crate::mount::directory::create_private_directory;
+use crate::mount::lock::state_file;
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+// Names the workspace in the environment of targets and hooks.
+pub const XAR_WORKSPACE: &str = "XAR_WORKSPACE";
+
+// Writable scratch space tied to a mount's lifetime, work.<mount dir> beside
+// it. Only its owner may enter it, and it's removed when the archive is
+// unmounted.
+pub fn path(mount: &Path) -> PathBuf {
+    state_file(&mount.to_path_buf(), "work")
+}
+
+// Create the workspace if needed, checking an existing one is ours.
+pub fn allocate(logger: &slog::Logger, mount: &Path) -> Result<PathBuf, failure::Error> {
+    let workspace = path(mount);
+    create_private_directory(logger, &workspace)?;
+    Ok(workspace)
+}
+
+pub fn remove(logger: &slog::Logger, mount: &Path) -> Result<(), failure::Error> {
+    let workspace = path(mount);
+    match fs::remove_dir_all(&workspace) {
+        Ok(()) => {
+            debug!(logger, "Removed workspace"; "workspace" => workspace.display().to_string());
+            Ok(())
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,153 @@
+use crate::exec::matches;
+use crate::mount::naming::NamingStrategy;
+use crate::xar::Xar;
+
+use failure::ResultExt;
+use nix::unistd::getuid;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+
+const DEFAULT_POLICY: &str = "/etc/xarfuse/policy.toml";
+// Path of a policy file, which must exist when set. It replaces the system
+// policy when root sets it, and is only added to it for anyone else, who
+// could otherwise lift an administrator's restrictions.
+const XAR_POLICY: &str = "XAR_POLICY";
+
+// Restrictions an administrator places on the archives mount and exec
+// accept. Unset fields allow anything, and unknown ones are rejected so a
+// misspelt restriction isn't silently ignored.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    // MOUNT_ROOT values archives may ask for. Archives without one use the
+    // default roots and are always allowed.
+    pub allowed_mount_roots: Option<Vec<String>>,
+    // Refuse the short alphanumeric ids legacy builders emit.
+    #[serde(default)]
+    pub require_uuid: bool,
+    // Archives can't be signed yet, so this refuses every archive.
+    #[serde(default)]
+    pub require_signature: bool,
+    // Patterns, where * matches anything, XAREXEC_TARGET must match one of.
+    pub allowed_targets: Option<Vec<String>>,
+    // How mounts are shared on this host, over the archive's MOUNT_NAMING.
+    pub mount_naming: Option<NamingStrategy>,
+}
+
+fn read(path: &str, required: bool) -> Result<Policy, failure::Error> {
+    let text = match fs::read_to_string(path) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound && !required => return Ok(Policy::default()),
+        result => result.with_context(|_| format!("while reading policy {}", path))?,
+    };
+    Ok(toml::from_str(&text).with_context(|_| format!("while parsing policy {}", path))?)
+}
+
+pub fn load() -> Result<Policy, failure::Error> {
+    let extra = match env::var(XAR_POLICY) {
+        Ok(path) if getuid().is_root() => return read(&path, true),
+        Ok(path) => Some(read(&path, true)?),
+        Err(_) => None,
+    };
+    let system = read(DEFAULT_POLICY, false)?;
+    Ok(match extra {
+        Some(extra) => system.tightened_by(extra),
+        None => system,
+    })
+}
+
+// Only those allowed by both, or by either where the other allows anything.
+fn intersect(a: Option<Vec<String>>, b: Option<Vec<String>>) -> Option<Vec<String>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.into_iter().filter(|value| b.contains(value)).collect()),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Policy {
+    // Everything this policy refuses and everything `other` does too. Naming
+    // isn't a restriction, the system's choice stands.
+    fn tightened_by(self, other: Policy) -> Policy {
+        Policy {
+            allowed_mount_roots: intersect(self.allowed_mount_roots, other.allowed_mount_roots),
+            require_uuid: self.require_uuid || other.require_uuid,
+            require_signature: self.require_signature || other.require_signature,
+            allowed_targets: intersect(self.allowed_targets, other.allowed_targets),
+            mount_naming: self.mount_naming.or(other.mount_naming),
+        }
+    }
+
+    pub fn check(&self, xar: &Xar) -> Result<(), failure::Error> {
+        let header = &xar.header;
+        let archive = xar.archive.display();
+        if let (Some(allowed), Some(root)) = (&self.allowed_mount_roots, &header.mount_root) {
+            if !allowed.iter().any(|allowed| allowed == root) {
+                bail!(
+                    "MOUNT_ROOT {} of {} is not allowed by policy",
+                    root,
+                    archive
+                );
+            }
+        }
+        if self.require_uuid && header.parsed_uuid().is_none() {
+            bail!(
+                "UUID {} of {} is not a full UUID, which policy requires",
+                header.uuid,
+                archive
+            );
+        }
+        if self.require_signature {
+            bail!("{} is unsigned, policy requires signed archives", archive);
+        }
+        if let Some(allowed) = &self.allowed_targets {
+            if !allowed
+                .iter()
+                .any(|pattern| matches(pattern, &header.xarexec_target))
+            {
+                bail!(
+                    "XAREXEC_TARGET {} of {} is not allowed by policy",
+                    header.xarexec_target,
+                    archive
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Option<Vec<String>> {
+        Some(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    #[test]
+    fn extra_policy_only_tightens() {
+        let system = Policy {
+            require_signature: true,
+            allowed_targets: strings(&["bin/*", "tools/*"]),
+            ..Policy::default()
+        };
+        let extra = Policy {
+            require_uuid: true,
+            allowed_targets: strings(&["tools/*", "other/*"]),
+            allowed_mount_roots: strings(&["/mnt/xar"]),
+            ..Policy::default()
+        };
+        let policy = system.tightened_by(extra);
+        assert!(policy.require_signature);
+        assert!(policy.require_uuid);
+        assert_eq!(policy.allowed_targets, strings(&["tools/*"]));
+        assert_eq!(policy.allowed_mount_roots, strings(&["/mnt/xar"]));
+
+        let lifted = Policy {
+            require_signature: true,
+            ..Policy::default()
+        }
+        .tightened_by(Policy::default());
+        assert!(lifted.require_signature);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::mount::directory::Directory;
+use crate::xar::Xar;
+
+use pyo3::exceptions::PyOSError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::path::PathBuf;
+
+fn to_py_err(err: failure::Error) -> PyErr {
+    PyOSError::new_err(err.to_string())
+}
+
+#[pyclass(name = "Xar")]
+struct PyXar {
+    xar: Xar,
+}
+
+impl PyXar {
+    fn directory(&self) -> PyResult<Directory> {
+        Directory::from_xar(&self.xar, self.xar.logger.clone()).map_err(to_py_err)
+    }
+}
+
+#[pymethods]
+impl PyXar {
+    #[new]
+    fn new(path: &str) -> PyResult<Self> {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let xar = Xar::from_file(PathBuf::from(path), logger).map_err(to_py_err)?;
+        Ok(PyXar { xar })
+    }
+
+    #[getter]
+    fn header(&self, py: Python) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        if let toml::Value::Table(table) =
+            toml::Value::try_from(&self.xar.header).map_err(|e| to_py_err(e.into()))?
+        {
+            for (key, value) in table {
+                match value {
+                    toml::Value::String(s) => dict.set_item(key, s)?,
+                    other => dict.set_item(key, other.to_string())?,
+                }
+            }
+        }
+        Ok(dict.to_object(py))
+    }
+
+    fn mountpoint(&self) -> PyResult<String> {
+        Ok(self.directory()?.path.to_string_lossy().into_owned())
+    }
+
+    fn mount(&self) -> PyResult<String> {
+        let mount = self.directory()?;
+        self.xar.mount(&mount).map_err(to_py_err)?;
+        Ok(mount.path.to_string_lossy().into_owned())
+    }
+
+    fn unmount(&self) -> PyResult<()> {
+        self.directory()?.unmount().map_err(to_py_err)
+    }
+}
+
+#[pymodule]
+fn xarfuse(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyXar>()?;
+    Ok(())
+}
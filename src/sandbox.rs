@@ -0,0 +1,241 @@
+// Restrictions applied just before exec'ing an archive's target: seccomp
+// filters and Landlock filesystem confinement. A seccomp profile is either a
+// built-in name or a JSON file in seccompiler's format, whose filter named
+// "main", or only filter, is used.
+
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+mod seccomp {
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule, TargetArch,
+    };
+    use std::collections::BTreeMap;
+    use std::convert::{TryFrom, TryInto};
+    use std::ffi::OsStr;
+    use std::fs::File;
+
+    // Syscalls which only modify the filesystem, refused outright.
+    const MODIFYING: &[libc::c_long] = &[
+        libc::SYS_openat2,
+        libc::SYS_truncate,
+        libc::SYS_mkdirat,
+        libc::SYS_unlinkat,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_linkat,
+        libc::SYS_symlinkat,
+        libc::SYS_fchmodat,
+        libc::SYS_fchownat,
+        libc::SYS_mknodat,
+        libc::SYS_utimensat,
+        libc::SYS_setxattr,
+        libc::SYS_lsetxattr,
+        libc::SYS_removexattr,
+        libc::SYS_lremovexattr,
+    ];
+    // Their legacy forms, which newer architectures don't have.
+    #[cfg(target_arch = "x86_64")]
+    const LEGACY_MODIFYING: &[libc::c_long] = &[
+        libc::SYS_creat,
+        libc::SYS_mkdir,
+        libc::SYS_rmdir,
+        libc::SYS_unlink,
+        libc::SYS_rename,
+        libc::SYS_link,
+        libc::SYS_symlink,
+        libc::SYS_chmod,
+        libc::SYS_chown,
+        libc::SYS_lchown,
+        libc::SYS_mknod,
+        libc::SYS_utime,
+        libc::SYS_utimes,
+    ];
+    #[cfg(not(target_arch = "x86_64"))]
+    const LEGACY_MODIFYING: &[libc::c_long] = &[];
+    const WRITE_FLAGS: &[libc::c_int] =
+        &[libc::O_WRONLY, libc::O_RDWR, libc::O_CREAT, libc::O_TRUNC];
+
+    fn arch() -> Result<TargetArch, failure::Error> {
+        TargetArch::try_from(std::env::consts::ARCH)
+            .map_err(|_| format_err!("seccomp is unsupported on {}", std::env::consts::ARCH))
+    }
+
+    fn compile(
+        rules: BTreeMap<i64, Vec<SeccompRule>>,
+        errno: i32,
+    ) -> Result<BpfProgram, failure::Error> {
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(errno as u32),
+            arch()?,
+        )?;
+        Ok(filter.try_into()?)
+    }
+
+    // Sockets other than unix ones fail with EACCES.
+    fn no_network() -> Result<BpfProgram, failure::Error> {
+        let not_unix = SeccompCondition::new(
+            0,
+            SeccompCmpArgLen::Dword,
+            SeccompCmpOp::Ne,
+            libc::AF_UNIX as u64,
+        )?;
+        let mut rules = BTreeMap::new();
+        rules.insert(libc::SYS_socket, vec![SeccompRule::new(vec![not_unix])?]);
+        compile(rules, libc::EACCES)
+    }
+
+    // Opening for writing and anything else which modifies the filesystem
+    // fails with EROFS.
+    fn read_only_fs() -> Result<BpfProgram, failure::Error> {
+        // Each rule matches one write flag in the open flags argument.
+        let opening = |flags_arg: u8| -> Result<Vec<SeccompRule>, failure::Error> {
+            WRITE_FLAGS
+                .iter()
+                .map(|flag| {
+                    let flag = *flag as u64;
+                    let condition = SeccompCondition::new(
+                        flags_arg,
+                        SeccompCmpArgLen::Dword,
+                        SeccompCmpOp::MaskedEq(flag),
+                        flag,
+                    )?;
+                    Ok(SeccompRule::new(vec![condition])?)
+                })
+                .collect()
+        };
+        let mut rules = BTreeMap::new();
+        rules.insert(libc::SYS_openat, opening(2)?);
+        #[cfg(target_arch = "x86_64")]
+        rules.insert(libc::SYS_open, opening(1)?);
+        for syscall in MODIFYING.iter().chain(LEGACY_MODIFYING) {
+            rules.insert(*syscall, Vec::new());
+        }
+        compile(rules, libc::EROFS)
+    }
+
+    fn from_json(path: &OsStr) -> Result<BpfProgram, failure::Error> {
+        let file = File::open(path)?;
+        let mut filters = seccompiler::compile_from_json(file, arch()?)?;
+        if filters.len() == 1 {
+            return Ok(filters.drain().next().unwrap().1);
+        }
+        filters.remove("main").ok_or_else(|| {
+            format_err!(
+                "{} has several filters, name the one to use main",
+                path.to_string_lossy()
+            )
+        })
+    }
+
+    pub fn install(profile: &OsStr) -> Result<(), failure::Error> {
+        let program = match profile.to_str() {
+            Some("no-network") => no_network()?,
+            Some("read-only-fs") => read_only_fs()?,
+            _ => from_json(profile)?,
+        };
+        // Also sets no_new_privs, which unprivileged filters require.
+        seccompiler::apply_filter(&program)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+mod confinement {
+    use landlock::{
+        Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+    use std::path::{Path, PathBuf};
+
+    // What dynamically linked and interpreted targets need besides the
+    // mount: the loader, libraries, interpreters and the loader's cache.
+    // Those missing on this host are skipped.
+    const SYSTEM_READABLE: &[&str] = &[
+        "/usr",
+        "/lib",
+        "/lib64",
+        "/lib32",
+        "/bin",
+        "/sbin",
+        "/etc/ld.so.cache",
+        "/dev/null",
+        "/dev/urandom",
+    ];
+
+    // Everything outside `readable` and `writable` becomes inaccessible,
+    // besides reading and executing SYSTEM_READABLE. Kernels without
+    // Landlock, or with an older version of it, enforce what they can, so
+    // this only fails on errors rather than missing support.
+    pub fn confine(
+        logger: &slog::Logger,
+        readable: &[PathBuf],
+        writable: &[PathBuf],
+    ) -> Result<(), failure::Error> {
+        let abi = ABI::V1;
+        let mut ruleset = Ruleset::new()
+            .handle_access(AccessFs::from_all(abi))?
+            .create()?;
+        let system = SYSTEM_READABLE
+            .iter()
+            .map(Path::new)
+            .filter(|path| path.exists())
+            .map(Path::to_path_buf);
+        // Reading includes executing.
+        for path in system.chain(readable.iter().cloned()) {
+            ruleset = ruleset.add_rule(PathBeneath::new(
+                PathFd::new(path)?,
+                AccessFs::from_read(abi),
+            ))?;
+        }
+        for path in writable {
+            ruleset = ruleset.add_rule(PathBeneath::new(
+                PathFd::new(path)?,
+                AccessFs::from_all(abi),
+            ))?;
+        }
+        match ruleset.restrict_self()?.ruleset {
+            RulesetStatus::FullyEnforced => (),
+            RulesetStatus::PartiallyEnforced => {
+                warn!(logger, "Landlock confinement is only partially enforced")
+            }
+            RulesetStatus::NotEnforced => warn!(
+                logger,
+                "Landlock is unavailable, the target is not confined"
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub use confinement::confine;
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub use seccomp::install;
+
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+pub fn install(_profile: &std::ffi::OsStr) -> Result<(), failure::Error> {
+    bail!("this xarfuse was built without the sandbox feature, or not for Linux")
+}
+
+// The interpreter a script names on its #! line, which a confined target
+// must be able to execute even when it lives outside the system paths.
+pub fn interpreter(script: &std::path::Path) -> Option<std::path::PathBuf> {
+    use std::io::Read;
+    let mut line = [0; 256];
+    let read = std::fs::File::open(script).ok()?.read(&mut line).ok()?;
+    let line = line[..read].strip_prefix(b"#!")?;
+    let line = &line[..line.iter().position(|&c| c == b'\n').unwrap_or(line.len())];
+    let line = String::from_utf8_lossy(line);
+    line.split_whitespace().next().map(std::path::PathBuf::from)
+}
+
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+pub fn confine(
+    _logger: &slog::Logger,
+    _readable: &[std::path::PathBuf],
+    _writable: &[std::path::PathBuf],
+) -> Result<(), failure::Error> {
+    bail!("this xarfuse was built without the sandbox feature, or not for Linux")
+}
@@ -0,0 +1,76 @@
+use crate::mount::directory::{create_private_directory, Directory};
+use crate::xar::Xar;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Built by build.rs, empty when mksquashfs wasn't available at build time.
+const SELFTEST_XAR: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/selftest.xar"));
+const SENTINEL: &str = "sentinel";
+const SENTINEL_CONTENTS: &str = "xarfuse selftest\n";
+
+// Removes the directory holding the written archive however the selftest
+// ends.
+struct Scratch(PathBuf);
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Mount the embedded archive, read its sentinel, run its target and unmount
+// it, exercising the whole installation end to end.
+pub fn run(logger: &slog::Logger) -> Result<(), failure::Error> {
+    if SELFTEST_XAR.is_empty() {
+        bail!("This xarfuse was built without mksquashfs, selftest is unavailable");
+    }
+
+    // Only this user may enter the directory, and the archive must be new,
+    // so nobody else can substitute what gets mounted.
+    let scratch = env::temp_dir().join(format!("xarfuse-selftest.{}", std::process::id()));
+    create_private_directory(logger, &scratch)?;
+    let scratch = Scratch(scratch);
+    let archive = scratch.0.join("selftest.xar");
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&archive)?
+        .write_all(SELFTEST_XAR)?;
+    info!(logger, "Wrote archive"; "archive" => archive.display().to_string());
+
+    let xar = Xar::from_file(archive, logger.clone())?;
+    let mount = Directory::from_xar(&xar, logger.clone())?;
+    xar.mount(&mount)?;
+    info!(logger, "Mounted"; "mount" => mount.path.display().to_string());
+
+    let result = check_mount(logger, &xar, &mount);
+    mount.unmount()?;
+    info!(logger, "Unmounted"; "mount" => mount.path.display().to_string());
+    result
+}
+
+fn check_mount(logger: &slog::Logger, xar: &Xar, mount: &Directory) -> Result<(), failure::Error> {
+    let contents = fs::read_to_string(mount.path.join(SENTINEL))?;
+    if contents != SENTINEL_CONTENTS {
+        bail!(
+            "Sentinel contains {:?}, expected {:?}",
+            contents,
+            SENTINEL_CONTENTS
+        );
+    }
+    info!(logger, "Read sentinel");
+
+    let target = xar.target_path(mount)?;
+    let status = Command::new(&target).status()?;
+    if !status.success() {
+        bail!("Target {} failed: {}", target.display(), status);
+    }
+    info!(logger, "Ran target"; "target" => target.display().to_string());
+    Ok(())
+}
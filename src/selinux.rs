@@ -0,0 +1,46 @@
+use std::fs;
+use std::path::Path;
+
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+const PROC_SELF_CONTEXT: &str = "/proc/self/attr/current";
+
+pub fn is_enforcing() -> bool {
+    fs::read_to_string(SELINUX_ENFORCE)
+        .map(|enforce| enforce.trim() == "1")
+        .unwrap_or(false)
+}
+
+pub fn current_context() -> Option<String> {
+    fs::read_to_string(PROC_SELF_CONTEXT)
+        .ok()
+        .map(|context| context.trim_end_matches('\0').trim().to_string())
+}
+
+#[cfg(target_os = "linux")]
+pub fn file_context(path: &Path) -> Option<String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let name = CString::new("security.selinux").unwrap();
+    let mut value = vec![0u8; 256];
+    let size = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if size < 0 {
+        return None;
+    }
+    value.truncate(size as usize);
+    let context = String::from_utf8_lossy(&value);
+    Some(context.trim_end_matches('\0').to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn file_context(_path: &Path) -> Option<String> {
+    None
+}
@@ -0,0 +1,61 @@
+#[cfg(any(feature = "gzip", feature = "xz", feature = "zstd"))]
+use std::io::Read;
+
+pub const GZIP: u16 = 1;
+pub const LZMA: u16 = 2;
+pub const LZO: u16 = 3;
+pub const XZ: u16 = 4;
+pub const LZ4: u16 = 5;
+pub const ZSTD: u16 = 6;
+
+pub fn name(compression: u16) -> &'static str {
+    match compression {
+        GZIP => "gzip",
+        LZMA => "lzma",
+        LZO => "lzo",
+        XZ => "xz",
+        LZ4 => "lz4",
+        ZSTD => "zstd",
+        _ => "unknown",
+    }
+}
+
+// Decompress a single block, which expands to at most `capacity` bytes.
+// Each compressor is only available when its cargo feature is enabled.
+pub fn decompress(
+    compression: u16,
+    data: &[u8],
+    capacity: usize,
+) -> Result<Vec<u8>, failure::Error> {
+    let mut out = Vec::with_capacity(capacity);
+    match compression {
+        #[cfg(feature = "gzip")]
+        GZIP => {
+            flate2::read::ZlibDecoder::new(data).read_to_end(&mut out)?;
+        }
+        #[cfg(feature = "xz")]
+        XZ => {
+            xz2::read::XzDecoder::new(data).read_to_end(&mut out)?;
+        }
+        #[cfg(feature = "xz")]
+        LZMA => {
+            let stream = xz2::stream::Stream::new_lzma_decoder(u64::max_value())?;
+            xz2::read::XzDecoder::new_stream(data, stream).read_to_end(&mut out)?;
+        }
+        #[cfg(feature = "lz4")]
+        LZ4 => {
+            out = lz4_flex::block::decompress(data, capacity)
+                .map_err(|e| format_err!("lz4 decompression failed: {}", e))?;
+        }
+        #[cfg(feature = "zstd")]
+        ZSTD => {
+            zstd::stream::read::Decoder::new(data)?.read_to_end(&mut out)?;
+        }
+        other => bail!(
+            "Unsupported squashfs compression: {} ({}), rebuild with the matching feature",
+            name(other),
+            other
+        ),
+    }
+    Ok(out)
+}
@@ -0,0 +1,120 @@
+use crate::squashfs::inode::{Data, Inode};
+use crate::squashfs::SquashFs;
+use crate::sys::{Fs, System};
+
+use std::env;
+use std::fs::{self, File, Permissions};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+// Caps what may be extracted into tmpfs, which is backed by memory.
+const XAR_EXTRACT_TMPFS_MAX_BYTES: &str = "XAR_EXTRACT_TMPFS_MAX_BYTES";
+// tmpfs allocates whole pages.
+const PAGE_SIZE: u64 = 4096;
+// From linux/magic.h
+#[cfg(target_os = "linux")]
+const TMPFS_MAGIC: i64 = 0x0102_1994;
+
+// Bytes `inode` will take once extracted, from the sizes recorded in its
+// inodes, each rounded up to a page.
+pub fn extracted_size(fs: &SquashFs, inode: &Inode) -> Result<u64, failure::Error> {
+    let mut size = (inode.size() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    if let Data::Directory { .. } = &inode.data {
+        for entry in fs.read_dir(inode)? {
+            size += extracted_size(fs, &fs.inode(entry.inode)?)?;
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(target_os = "linux")]
+fn is_tmpfs(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::statfs(c_path.as_ptr(), &mut stat) };
+    res == 0 && stat.f_type as i64 == TMPFS_MAGIC
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_tmpfs(_path: &Path) -> bool {
+    false
+}
+
+// Refuse to extract `size` bytes into `dest` when they won't fit, or when
+// dest is on tmpfs and they exceed XAR_EXTRACT_TMPFS_MAX_BYTES. Filling
+// /dev/shm takes memory from everything else on the machine.
+pub fn check_space(dest: &Path, size: u64) -> Result<(), failure::Error> {
+    // dest itself may not exist yet.
+    let existing = match dest.ancestors().find(|path| path.exists()) {
+        Some(existing) => existing,
+        None => return Ok(()),
+    };
+    let free = System.free(existing)?;
+    if size > free.bytes {
+        bail!(
+            "Extracting needs {} bytes but {} has {} free",
+            size,
+            existing.display(),
+            free.bytes
+        );
+    }
+    if !is_tmpfs(existing) {
+        return Ok(());
+    }
+    let cap = env::var(XAR_EXTRACT_TMPFS_MAX_BYTES)
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok());
+    if let Some(cap) = cap {
+        if size > cap {
+            bail!(
+                "Extracting needs {} bytes, above {} ({}) for tmpfs {}; extract to a \
+                 disk-backed directory instead",
+                size,
+                cap,
+                XAR_EXTRACT_TMPFS_MAX_BYTES,
+                existing.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+// Recreate `inode` at `dest`, recursing into directories. Permissions are
+// applied after a directory's contents so read-only directories extract.
+pub fn extract(
+    fs: &SquashFs,
+    inode: &Inode,
+    dest: &Path,
+    logger: &slog::Logger,
+) -> Result<(), failure::Error> {
+    match &inode.data {
+        Data::Directory { .. } => {
+            if !dest.is_dir() {
+                fs::create_dir(dest)?;
+            }
+            for entry in fs.read_dir(inode)? {
+                extract(fs, &fs.inode(entry.inode)?, &dest.join(&entry.name), logger)?;
+            }
+        }
+        Data::File { .. } => {
+            let mut file = File::create(dest)?;
+            fs.read_file(inode, &mut file)?;
+        }
+        Data::Symlink { target } => {
+            symlink(target, dest)?;
+            return Ok(());
+        }
+        Data::Device { .. } | Data::Ipc => {
+            warn!(logger, "Skipping special file"; "path" => dest.display().to_string());
+            return Ok(());
+        }
+    }
+
+    fs::set_permissions(dest, Permissions::from_mode(u32::from(inode.permissions)))?;
+    Ok(())
+}
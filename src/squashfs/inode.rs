@@ -0,0 +1,257 @@
+use crate::squashfs::metadata::MetadataReader;
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Directory,
+    File,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+}
+
+impl Kind {
+    // Directory entries and inodes share the basic type numbering; extended
+    // inode types are offset by 7.
+    pub fn from_type(inode_type: u16) -> Result<Kind, failure::Error> {
+        match inode_type {
+            1 | 8 => Ok(Kind::Directory),
+            2 | 9 => Ok(Kind::File),
+            3 | 10 => Ok(Kind::Symlink),
+            4 | 11 => Ok(Kind::BlockDevice),
+            5 | 12 => Ok(Kind::CharDevice),
+            6 | 13 => Ok(Kind::Fifo),
+            7 | 14 => Ok(Kind::Socket),
+            other => Err(format_err!("Unknown squashfs inode type {}", other)),
+        }
+    }
+
+    // The S_IFMT bits for this kind of file.
+    pub fn file_type(self) -> u32 {
+        match self {
+            Kind::Directory => 0o040_000,
+            Kind::File => 0o100_000,
+            Kind::Symlink => 0o120_000,
+            Kind::BlockDevice => 0o060_000,
+            Kind::CharDevice => 0o020_000,
+            Kind::Fifo => 0o010_000,
+            Kind::Socket => 0o140_000,
+        }
+    }
+
+    pub fn as_char(self) -> char {
+        match self {
+            Kind::Directory => 'd',
+            Kind::File => '-',
+            Kind::Symlink => 'l',
+            Kind::BlockDevice => 'b',
+            Kind::CharDevice => 'c',
+            Kind::Fifo => 'p',
+            Kind::Socket => 's',
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FragmentRef {
+    pub index: u32,
+    pub offset: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum Data {
+    Directory {
+        block_index: u32,
+        block_offset: u16,
+        size: u32,
+    },
+    File {
+        blocks_start: u64,
+        size: u64,
+        fragment: Option<FragmentRef>,
+        block_sizes: Vec<u32>,
+    },
+    Symlink {
+        target: OsString,
+    },
+    Device {
+        rdev: u32,
+    },
+    Ipc,
+}
+
+#[derive(Clone, Debug)]
+pub struct Inode {
+    pub kind: Kind,
+    pub permissions: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+    pub inode_number: u32,
+    pub data: Data,
+}
+
+impl Inode {
+    pub fn read(
+        reader: &mut MetadataReader,
+        block_size: u32,
+        ids: &[u32],
+    ) -> Result<Inode, failure::Error> {
+        let inode_type = reader.u16()?;
+        let permissions = reader.u16()?;
+        let uid = lookup_id(ids, reader.u16()?)?;
+        let gid = lookup_id(ids, reader.u16()?)?;
+        let mtime = reader.u32()?;
+        let inode_number = reader.u32()?;
+
+        let data = match inode_type {
+            1 => {
+                let block_index = reader.u32()?;
+                let _link_count = reader.u32()?;
+                let size = u32::from(reader.u16()?);
+                let block_offset = reader.u16()?;
+                Data::Directory {
+                    block_index,
+                    block_offset,
+                    size,
+                }
+            }
+            8 => {
+                let _link_count = reader.u32()?;
+                let size = reader.u32()?;
+                let block_index = reader.u32()?;
+                let _parent = reader.u32()?;
+                let _index_count = reader.u16()?;
+                let block_offset = reader.u16()?;
+                Data::Directory {
+                    block_index,
+                    block_offset,
+                    size,
+                }
+            }
+            2 => {
+                let blocks_start = u64::from(reader.u32()?);
+                let fragment_index = reader.u32()?;
+                let fragment_offset = reader.u32()?;
+                let size = u64::from(reader.u32()?);
+                read_file(
+                    reader,
+                    block_size,
+                    blocks_start,
+                    size,
+                    fragment_index,
+                    fragment_offset,
+                )?
+            }
+            9 => {
+                let blocks_start = reader.u64()?;
+                let size = reader.u64()?;
+                let _sparse = reader.u64()?;
+                let _link_count = reader.u32()?;
+                let fragment_index = reader.u32()?;
+                let fragment_offset = reader.u32()?;
+                let _xattr = reader.u32()?;
+                read_file(
+                    reader,
+                    block_size,
+                    blocks_start,
+                    size,
+                    fragment_index,
+                    fragment_offset,
+                )?
+            }
+            3 | 10 => {
+                let _link_count = reader.u32()?;
+                let target_size = reader.u32()? as usize;
+                let target = OsString::from_vec(reader.read_vec(target_size)?);
+                Data::Symlink { target }
+            }
+            4 | 5 | 11 | 12 => {
+                let _link_count = reader.u32()?;
+                let rdev = reader.u32()?;
+                Data::Device { rdev }
+            }
+            6 | 7 | 13 | 14 => Data::Ipc,
+            other => bail!("Unknown squashfs inode type {}", other),
+        };
+
+        Ok(Inode {
+            kind: Kind::from_type(inode_type)?,
+            permissions,
+            uid,
+            gid,
+            mtime,
+            inode_number,
+            data,
+        })
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.kind.file_type() | u32::from(self.permissions & 0o7777)
+    }
+
+    pub fn size(&self) -> u64 {
+        match &self.data {
+            Data::Directory { size, .. } => u64::from(*size),
+            Data::File { size, .. } => *size,
+            Data::Symlink { target } => target.len() as u64,
+            Data::Device { .. } | Data::Ipc => 0,
+        }
+    }
+
+    // Render the mode like `ls -l`, e.g. drwxr-xr-x.
+    pub fn mode_string(&self) -> String {
+        let mut mode = String::with_capacity(10);
+        mode.push(self.kind.as_char());
+        for shift in &[6, 3, 0] {
+            let bits = (self.permissions >> shift) & 0o7;
+            mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            mode.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+        }
+        mode
+    }
+}
+
+fn lookup_id(ids: &[u32], index: u16) -> Result<u32, failure::Error> {
+    ids.get(usize::from(index))
+        .cloned()
+        .ok_or_else(|| format_err!("Squashfs id index {} out of range", index))
+}
+
+fn read_file(
+    reader: &mut MetadataReader,
+    block_size: u32,
+    blocks_start: u64,
+    size: u64,
+    fragment_index: u32,
+    fragment_offset: u32,
+) -> Result<Data, failure::Error> {
+    let block_size = u64::from(block_size);
+    let (fragment, blocks) = if fragment_index == NO_FRAGMENT {
+        (None, (size + block_size - 1) / block_size)
+    } else {
+        let fragment = FragmentRef {
+            index: fragment_index,
+            offset: fragment_offset,
+        };
+        (Some(fragment), size / block_size)
+    };
+
+    let mut block_sizes = Vec::with_capacity(blocks as usize);
+    for _ in 0..blocks {
+        block_sizes.push(reader.u32()?);
+    }
+    Ok(Data::File {
+        blocks_start,
+        size,
+        fragment,
+        block_sizes,
+    })
+}
@@ -0,0 +1,109 @@
+use crate::squashfs::compression;
+use crate::squashfs::SquashFs;
+
+use std::convert::TryInto;
+
+pub const METADATA_SIZE: usize = 8192;
+pub(crate) const METADATA_UNCOMPRESSED: u16 = 0x8000;
+
+// Reads a stream of bytes spanning consecutive metadata blocks, starting
+// `offset` bytes into the uncompressed block at `block`.
+pub struct MetadataReader<'a> {
+    fs: &'a SquashFs,
+    next_block: u64,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<'a> MetadataReader<'a> {
+    pub fn new(
+        fs: &'a SquashFs,
+        block: u64,
+        offset: usize,
+    ) -> Result<MetadataReader<'a>, failure::Error> {
+        let mut reader = MetadataReader {
+            fs,
+            next_block: block,
+            buffer: Vec::new(),
+            position: 0,
+        };
+        reader.load_block()?;
+        if offset > reader.buffer.len() {
+            bail!("Metadata offset {} beyond block at {}", offset, block);
+        }
+        reader.position = offset;
+        Ok(reader)
+    }
+
+    fn load_block(&mut self) -> Result<(), failure::Error> {
+        let mut header = [0; 2];
+        self.fs.read_at(self.next_block, &mut header)?;
+        let header = u16::from_le_bytes(header);
+        let size = usize::from(header & !METADATA_UNCOMPRESSED);
+
+        let mut data = vec![0; size];
+        self.fs.read_at(self.next_block + 2, &mut data)?;
+        self.buffer = if header & METADATA_UNCOMPRESSED != 0 {
+            data
+        } else {
+            compression::decompress(self.fs.superblock.compression, &data, METADATA_SIZE)?
+        };
+        self.position = 0;
+        self.next_block += 2 + size as u64;
+        Ok(())
+    }
+
+    pub fn read_exact(&mut self, out: &mut [u8]) -> Result<(), failure::Error> {
+        let mut written = 0;
+        while written < out.len() {
+            if self.position == self.buffer.len() {
+                self.load_block()?;
+                if self.buffer.is_empty() {
+                    bail!("Empty metadata block before {}", self.next_block);
+                }
+            }
+            let count = (out.len() - written).min(self.buffer.len() - self.position);
+            out[written..written + count]
+                .copy_from_slice(&self.buffer[self.position..self.position + count]);
+            written += count;
+            self.position += count;
+        }
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self, len: usize) -> Result<Vec<u8>, failure::Error> {
+        let mut out = vec![0; len];
+        self.read_exact(&mut out)?;
+        Ok(out)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, failure::Error> {
+        let mut bytes = [0; 2];
+        self.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, failure::Error> {
+        let mut bytes = [0; 4];
+        self.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, failure::Error> {
+        let mut bytes = [0; 8];
+        self.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+pub fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes(bytes[..2].try_into().unwrap())
+}
+
+pub fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+pub fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
@@ -0,0 +1,469 @@
+pub mod compression;
+pub mod extract;
+pub mod inode;
+pub mod metadata;
+pub mod tar;
+
+use crate::squashfs::inode::{Data, Inode, Kind};
+use crate::squashfs::metadata::{le_u16, le_u32, le_u64, MetadataReader, METADATA_SIZE};
+use crate::xar::Xar;
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path};
+
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+const SUPERBLOCK_SIZE: usize = 96;
+const ID_ENTRY_SIZE: usize = 4;
+const FRAGMENT_ENTRY_SIZE: usize = 16;
+const DIRECTORY_HEADER_SIZE: usize = 12;
+const DIRECTORY_ENTRY_SIZE: usize = 8;
+// Data block and fragment sizes flag stored blocks in bit 24.
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+const DATA_SIZE_MASK: u32 = DATA_UNCOMPRESSED - 1;
+
+// Table locations are relative to the start of the squashfs image.
+pub struct Superblock {
+    pub inode_count: u32,
+    pub modification_time: u32,
+    pub block_size: u32,
+    pub fragment_entry_count: u32,
+    pub compression: u16,
+    pub flags: u16,
+    pub id_count: u16,
+    pub root_inode: u64,
+    pub bytes_used: u64,
+    pub id_table_start: u64,
+    pub inode_table_start: u64,
+    pub directory_table_start: u64,
+    pub fragment_table_start: u64,
+}
+
+impl Superblock {
+    fn parse(bytes: &[u8]) -> Result<Superblock, failure::Error> {
+        let magic = le_u32(&bytes[0..]);
+        if magic != SQUASHFS_MAGIC {
+            bail!("Bad squashfs magic: {:#x}", magic);
+        }
+        let (major, minor) = (le_u16(&bytes[28..]), le_u16(&bytes[30..]));
+        if major != 4 {
+            bail!("Unsupported squashfs version: {}.{}", major, minor);
+        }
+        Ok(Superblock {
+            inode_count: le_u32(&bytes[4..]),
+            modification_time: le_u32(&bytes[8..]),
+            block_size: le_u32(&bytes[12..]),
+            fragment_entry_count: le_u32(&bytes[16..]),
+            compression: le_u16(&bytes[20..]),
+            flags: le_u16(&bytes[24..]),
+            id_count: le_u16(&bytes[26..]),
+            root_inode: le_u64(&bytes[32..]),
+            bytes_used: le_u64(&bytes[40..]),
+            id_table_start: le_u64(&bytes[48..]),
+            inode_table_start: le_u64(&bytes[64..]),
+            directory_table_start: le_u64(&bytes[72..]),
+            fragment_table_start: le_u64(&bytes[80..]),
+        })
+    }
+}
+
+pub struct Fragment {
+    pub start: u64,
+    pub size: u32,
+}
+
+pub struct DirEntry {
+    pub name: OsString,
+    pub kind: Kind,
+    pub inode: u64,
+}
+
+// A read-only view of the squashfs image embedded in an archive.
+pub struct SquashFs {
+    file: File,
+    offset: u64,
+    pub superblock: Superblock,
+    ids: Vec<u32>,
+    pub fragments: Vec<Fragment>,
+}
+
+impl SquashFs {
+    pub fn open(path: &Path, offset: u64) -> Result<SquashFs, failure::Error> {
+        let file = File::open(path)?;
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        file.read_exact_at(&mut bytes, offset)?;
+        let superblock = Superblock::parse(&bytes)?;
+
+        let mut fs = SquashFs {
+            file,
+            offset,
+            superblock,
+            ids: Vec::new(),
+            fragments: Vec::new(),
+        };
+        fs.ids = fs
+            .read_table(
+                fs.superblock.id_table_start,
+                usize::from(fs.superblock.id_count),
+                ID_ENTRY_SIZE,
+            )?
+            .chunks(ID_ENTRY_SIZE)
+            .map(le_u32)
+            .collect();
+        fs.fragments = fs
+            .read_table(
+                fs.superblock.fragment_table_start,
+                fs.superblock.fragment_entry_count as usize,
+                FRAGMENT_ENTRY_SIZE,
+            )?
+            .chunks(FRAGMENT_ENTRY_SIZE)
+            .map(|entry| Fragment {
+                start: le_u64(entry),
+                size: le_u32(&entry[8..]),
+            })
+            .collect();
+        Ok(fs)
+    }
+
+    pub fn from_xar(xar: &Xar) -> Result<SquashFs, failure::Error> {
+        SquashFs::open(&xar.source_path(), xar.header.offset)
+    }
+
+    pub fn read_at(&self, position: u64, buf: &mut [u8]) -> Result<(), failure::Error> {
+        self.file.read_exact_at(buf, self.offset + position)?;
+        Ok(())
+    }
+
+    // Lookup tables are an array of pointers to metadata blocks holding
+    // `count` fixed size entries.
+    fn read_table(
+        &self,
+        start: u64,
+        count: usize,
+        entry_size: usize,
+    ) -> Result<Vec<u8>, failure::Error> {
+        let size = count * entry_size;
+        let blocks = (size + METADATA_SIZE - 1) / METADATA_SIZE;
+        if blocks == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut pointers = vec![0; blocks * 8];
+        self.read_at(start, &mut pointers)?;
+        let mut table = Vec::with_capacity(size);
+        for pointer in pointers.chunks(8) {
+            let len = (size - table.len()).min(METADATA_SIZE);
+            let mut reader = MetadataReader::new(self, le_u64(pointer), 0)?;
+            table.extend(reader.read_vec(len)?);
+        }
+        Ok(table)
+    }
+
+    // Inode references hold the metadata block offset in the upper bits and the
+    // offset within the uncompressed block in the lower 16 bits.
+    pub fn inode(&self, reference: u64) -> Result<Inode, failure::Error> {
+        let block = self.superblock.inode_table_start + (reference >> 16);
+        let mut reader = MetadataReader::new(self, block, (reference & 0xffff) as usize)?;
+        Inode::read(&mut reader, self.superblock.block_size, &self.ids)
+    }
+
+    pub fn root(&self) -> Result<Inode, failure::Error> {
+        self.inode(self.superblock.root_inode)
+    }
+
+    pub fn read_dir(&self, dir: &Inode) -> Result<Vec<DirEntry>, failure::Error> {
+        let (block_index, block_offset, size) = match dir.data {
+            Data::Directory {
+                block_index,
+                block_offset,
+                size,
+            } => (block_index, block_offset, size),
+            _ => bail!("Inode {} is not a directory", dir.inode_number),
+        };
+
+        // The listing size counts 3 bytes for the implicit . and .. entries.
+        let mut entries = Vec::new();
+        let mut remaining = (size as usize).saturating_sub(3);
+        if remaining == 0 {
+            return Ok(entries);
+        }
+
+        let block = self.superblock.directory_table_start + u64::from(block_index);
+        let mut reader = MetadataReader::new(self, block, usize::from(block_offset))?;
+        let malformed = || format_err!("Malformed directory listing in inode {}", dir.inode_number);
+        while remaining > 0 {
+            let count = reader.u32()? + 1;
+            let start = u64::from(reader.u32()?);
+            let _inode_number = reader.u32()?;
+            remaining = remaining
+                .checked_sub(DIRECTORY_HEADER_SIZE)
+                .ok_or_else(malformed)?;
+
+            for _ in 0..count {
+                let offset = u64::from(reader.u16()?);
+                let _inode_offset = reader.u16()?;
+                let kind = Kind::from_type(reader.u16()?)?;
+                let name_size = usize::from(reader.u16()?) + 1;
+                let name = OsString::from_vec(reader.read_vec(name_size)?);
+                remaining = remaining
+                    .checked_sub(DIRECTORY_ENTRY_SIZE + name_size)
+                    .ok_or_else(malformed)?;
+                entries.push(DirEntry {
+                    name,
+                    kind,
+                    inode: (start << 16) | offset,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn lookup(&self, path: &Path) -> Result<Inode, failure::Error> {
+        let mut inode = self.root()?;
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::Normal(name) => {
+                    let entry = self
+                        .read_dir(&inode)?
+                        .into_iter()
+                        .find(|entry| entry.name.as_os_str() == name)
+                        .ok_or_else(|| {
+                            format_err!("No such file in archive: {}", path.display())
+                        })?;
+                    inode = self.inode(entry.inode)?;
+                }
+                _ => bail!("Unsupported path in archive: {}", path.display()),
+            }
+        }
+        Ok(inode)
+    }
+
+    fn read_block(&self, position: u64, size_field: u32) -> Result<Vec<u8>, failure::Error> {
+        let mut data = vec![0; (size_field & DATA_SIZE_MASK) as usize];
+        self.read_at(position, &mut data)?;
+        if size_field & DATA_UNCOMPRESSED != 0 {
+            return Ok(data);
+        }
+        compression::decompress(
+            self.superblock.compression,
+            &data,
+            self.superblock.block_size as usize,
+        )
+    }
+
+    // Stream the contents of a regular file, block by block, to `out`.
+    pub fn read_file(&self, inode: &Inode, out: &mut dyn Write) -> Result<(), failure::Error> {
+        let (blocks_start, size, fragment, block_sizes) = match &inode.data {
+            Data::File {
+                blocks_start,
+                size,
+                fragment,
+                block_sizes,
+            } => (*blocks_start, *size, fragment, block_sizes),
+            _ => bail!("Inode {} is not a regular file", inode.inode_number),
+        };
+
+        let block_size = u64::from(self.superblock.block_size);
+        let mut position = blocks_start;
+        let mut remaining = size;
+        for &block in block_sizes {
+            let len = remaining.min(block_size) as usize;
+            if block & DATA_SIZE_MASK == 0 {
+                // Sparse block
+                out.write_all(&vec![0; len])?;
+            } else {
+                let data = self.read_block(position, block)?;
+                if data.len() < len {
+                    bail!("Short data block in inode {}", inode.inode_number);
+                }
+                out.write_all(&data[..len])?;
+                position += u64::from(block & DATA_SIZE_MASK);
+            }
+            remaining -= len as u64;
+        }
+
+        if let Some(fragment) = fragment {
+            let entry = self
+                .fragments
+                .get(fragment.index as usize)
+                .ok_or_else(|| format_err!("Fragment {} out of range", fragment.index))?;
+            let data = self.read_block(entry.start, entry.size)?;
+            let start = fragment.offset as usize;
+            let end = start + remaining as usize;
+            if end > data.len() {
+                bail!("Short fragment for inode {}", inode.inode_number);
+            }
+            out.write_all(&data[start..end])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::squashfs::metadata::METADATA_UNCOMPRESSED;
+
+    const CONTENTS: &[u8] = b"hello\n";
+    // Where the image starts in the archive, after the header.
+    const OFFSET: u64 = 7;
+
+    fn push16(image: &mut Vec<u8>, value: u16) {
+        image.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push32(image: &mut Vec<u8>, value: u32) {
+        image.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push64(image: &mut Vec<u8>, value: u64) {
+        image.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_metadata(image: &mut Vec<u8>, block: &[u8]) -> u64 {
+        let start = image.len() as u64;
+        push16(image, block.len() as u16 | METADATA_UNCOMPRESSED);
+        image.extend_from_slice(block);
+        start
+    }
+
+    // The basic inode header, with uid and gid both the first id.
+    fn inode_header(block: &mut Vec<u8>, inode_type: u16, permissions: u16, number: u32) {
+        push16(block, inode_type);
+        push16(block, permissions);
+        push16(block, 0);
+        push16(block, 0);
+        push32(block, 1_600_000_000);
+        push32(block, number);
+    }
+
+    // An uncompressed image holding only /hello, in a single data block.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; SUPERBLOCK_SIZE];
+        let data_start = image.len() as u32;
+        image.extend_from_slice(CONTENTS);
+
+        let mut inodes = Vec::new();
+        inode_header(&mut inodes, 1, 0o755, 1);
+        push32(&mut inodes, 0);
+        push32(&mut inodes, 2);
+        // The listing below, plus 3 for . and ..
+        push16(&mut inodes, 12 + 8 + 5 + 3);
+        push16(&mut inodes, 0);
+        push32(&mut inodes, 1);
+        let file_offset = inodes.len() as u16;
+        inode_header(&mut inodes, 2, 0o644, 2);
+        push32(&mut inodes, data_start);
+        push32(&mut inodes, 0xffff_ffff);
+        push32(&mut inodes, 0);
+        push32(&mut inodes, CONTENTS.len() as u32);
+        push32(&mut inodes, CONTENTS.len() as u32 | DATA_UNCOMPRESSED);
+        let inode_table = push_metadata(&mut image, &inodes);
+
+        let mut listing = Vec::new();
+        push32(&mut listing, 0);
+        push32(&mut listing, 0);
+        push32(&mut listing, 2);
+        push16(&mut listing, file_offset);
+        push16(&mut listing, 0);
+        push16(&mut listing, 2);
+        push16(&mut listing, 4);
+        listing.extend_from_slice(b"hello");
+        let directory_table = push_metadata(&mut image, &listing);
+
+        let mut ids = Vec::new();
+        push32(&mut ids, 1000);
+        let ids_block = push_metadata(&mut image, &ids);
+        let id_table = image.len() as u64;
+        push64(&mut image, ids_block);
+
+        let mut superblock = Vec::new();
+        push32(&mut superblock, SQUASHFS_MAGIC);
+        push32(&mut superblock, 2);
+        push32(&mut superblock, 1_600_000_000);
+        push32(&mut superblock, 128 * 1024);
+        push32(&mut superblock, 0);
+        push16(&mut superblock, 1);
+        push16(&mut superblock, 17);
+        push16(&mut superblock, 0);
+        push16(&mut superblock, 1);
+        push16(&mut superblock, 4);
+        push16(&mut superblock, 0);
+        push64(&mut superblock, 0);
+        push64(&mut superblock, image.len() as u64);
+        push64(&mut superblock, id_table);
+        push64(&mut superblock, u64::max_value());
+        push64(&mut superblock, inode_table);
+        push64(&mut superblock, directory_table);
+        push64(&mut superblock, image.len() as u64);
+        push64(&mut superblock, u64::max_value());
+        image[..SUPERBLOCK_SIZE].copy_from_slice(&superblock);
+        image
+    }
+
+    // Write `image` after OFFSET bytes of header, removed when dropped.
+    struct Archive(std::path::PathBuf);
+
+    impl Archive {
+        fn new(name: &str, image: &[u8]) -> Archive {
+            let path = std::env::temp_dir().join(format!(
+                "xarfuse-squashfs-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let mut contents = vec![b'#'; OFFSET as usize];
+            contents.extend_from_slice(image);
+            std::fs::write(&path, contents).unwrap();
+            Archive(path)
+        }
+    }
+
+    impl Drop for Archive {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn reads_listing_and_contents() {
+        let archive = Archive::new("read", &image());
+        let fs = SquashFs::open(&archive.0, OFFSET).unwrap();
+        assert_eq!(fs.superblock.inode_count, 2);
+
+        let root = fs.root().unwrap();
+        assert_eq!(root.kind, Kind::Directory);
+        assert_eq!(root.mode_string(), "drwxr-xr-x");
+        assert_eq!(root.uid, 1000);
+        let entries = fs.read_dir(&root).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "hello");
+        assert_eq!(entries[0].kind, Kind::File);
+
+        let file = fs.lookup(Path::new("/hello")).unwrap();
+        assert_eq!(file.inode_number, 2);
+        assert_eq!(file.size(), CONTENTS.len() as u64);
+        assert_eq!(file.mode(), 0o100_644);
+        let mut contents: Vec<u8> = Vec::new();
+        fs.read_file(&file, &mut contents).unwrap();
+        assert_eq!(contents, CONTENTS);
+
+        assert!(fs.lookup(Path::new("/missing")).is_err());
+        assert!(fs.lookup(Path::new("../hello")).is_err());
+        assert!(fs.read_file(&root, &mut Vec::<u8>::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_other_images() {
+        let mut image = image();
+        image[28] = 3;
+        let archive = Archive::new("version", &image);
+        assert!(SquashFs::open(&archive.0, OFFSET).is_err());
+        image[0] = 0;
+        let archive = Archive::new("magic", &image);
+        assert!(SquashFs::open(&archive.0, OFFSET).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::squashfs::inode::{Data, Inode, Kind};
+use crate::squashfs::SquashFs;
+
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+
+const BLOCK_SIZE: usize = 512;
+// ustar's name and linkname fields, and the largest size its 11 octal digits
+// hold. Anything larger goes in a PAX extended header.
+const NAME_SIZE: usize = 100;
+const MAX_USTAR_SIZE: u64 = 0o777_7777_7777;
+
+// Octal, zero padded to fill all but the field's trailing NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    let digits = digits.as_bytes();
+    // Values too large for the field were moved to the PAX header.
+    let digits = &digits[digits.len().saturating_sub(field.len() - 1)..];
+    field[..digits.len()].copy_from_slice(digits);
+}
+
+// "LEN KEY=VALUE\n", where LEN counts its own digits.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+struct Entry<'a> {
+    name: &'a [u8],
+    typeflag: u8,
+    size: u64,
+    link: &'a [u8],
+    device: (u32, u32),
+}
+
+fn header(entry: &Entry, inode: &Inode) -> [u8; BLOCK_SIZE] {
+    let mut block = [0; BLOCK_SIZE];
+    let name = &entry.name[..entry.name.len().min(NAME_SIZE)];
+    block[..name.len()].copy_from_slice(name);
+    octal(&mut block[100..108], u64::from(inode.permissions & 0o7777));
+    octal(&mut block[108..116], u64::from(inode.uid));
+    octal(&mut block[116..124], u64::from(inode.gid));
+    octal(&mut block[124..136], entry.size.min(MAX_USTAR_SIZE));
+    octal(&mut block[136..148], u64::from(inode.mtime));
+    block[156] = entry.typeflag;
+    let link = &entry.link[..entry.link.len().min(NAME_SIZE)];
+    block[157..157 + link.len()].copy_from_slice(link);
+    block[257..265].copy_from_slice(b"ustar\x0000");
+    octal(&mut block[329..337], u64::from(entry.device.0));
+    octal(&mut block[337..345], u64::from(entry.device.1));
+    // The checksum is computed with its own field as spaces.
+    block[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = block.iter().map(|byte| u64::from(*byte)).sum();
+    octal(&mut block[148..155], checksum);
+    block
+}
+
+fn write_data(out: &mut dyn Write, data: &[u8]) -> Result<(), failure::Error> {
+    out.write_all(data)?;
+    pad(out, data.len() as u64)
+}
+
+fn pad(out: &mut dyn Write, written: u64) -> Result<(), failure::Error> {
+    let remainder = (written % BLOCK_SIZE as u64) as usize;
+    if remainder != 0 {
+        out.write_all(&[0; BLOCK_SIZE][remainder..])?;
+    }
+    Ok(())
+}
+
+fn write_entry(
+    fs: &SquashFs,
+    inode: &Inode,
+    entry: &Entry,
+    out: &mut dyn Write,
+) -> Result<(), failure::Error> {
+    let mut pax = Vec::new();
+    if entry.name.len() > NAME_SIZE {
+        pax.extend(pax_record("path", entry.name));
+    }
+    if entry.link.len() > NAME_SIZE {
+        pax.extend(pax_record("linkpath", entry.link));
+    }
+    if entry.size > MAX_USTAR_SIZE {
+        pax.extend(pax_record("size", entry.size.to_string().as_bytes()));
+    }
+    if !pax.is_empty() {
+        let extended = Entry {
+            name: b"././@PaxHeader",
+            typeflag: b'x',
+            size: pax.len() as u64,
+            link: b"",
+            device: (0, 0),
+        };
+        out.write_all(&header(&extended, inode))?;
+        write_data(out, &pax)?;
+    }
+    out.write_all(&header(entry, inode))?;
+    if let Data::File { .. } = inode.data {
+        fs.read_file(inode, out)?;
+        pad(out, entry.size)?;
+    }
+    Ok(())
+}
+
+fn append(
+    fs: &SquashFs,
+    inode: &Inode,
+    name: &[u8],
+    out: &mut dyn Write,
+    logger: &slog::Logger,
+) -> Result<(), failure::Error> {
+    let (typeflag, link, device) = match (&inode.data, inode.kind) {
+        (Data::Directory { .. }, _) => (b'5', &b""[..], (0, 0)),
+        (Data::File { .. }, _) => (b'0', &b""[..], (0, 0)),
+        (Data::Symlink { target }, _) => (b'2', target.as_bytes(), (0, 0)),
+        (Data::Device { rdev }, kind) => {
+            let typeflag = if kind == Kind::BlockDevice {
+                b'4'
+            } else {
+                b'3'
+            };
+            let major = (rdev >> 8) & 0xfff;
+            let minor = (rdev & 0xff) | ((rdev >> 12) & 0xfff00);
+            (typeflag, &b""[..], (major, minor))
+        }
+        (Data::Ipc, Kind::Fifo) => (b'6', &b""[..], (0, 0)),
+        (Data::Ipc, _) => {
+            warn!(logger, "Skipping socket"; "path" => String::from_utf8_lossy(name).into_owned());
+            return Ok(());
+        }
+    };
+    let size = match inode.data {
+        Data::File { size, .. } => size,
+        _ => 0,
+    };
+    let entry = Entry {
+        name,
+        typeflag,
+        size,
+        link,
+        device,
+    };
+    write_entry(fs, inode, &entry, out)?;
+
+    if let Data::Directory { .. } = inode.data {
+        for child in fs.read_dir(inode)? {
+            let mut path = name.to_vec();
+            path.extend_from_slice(child.name.as_bytes());
+            if child.kind == Kind::Directory {
+                path.push(b'/');
+            }
+            append(fs, &fs.inode(child.inode)?, &path, out, logger)?;
+        }
+    }
+    Ok(())
+}
+
+// Write the whole image as a tar stream, keeping owners, modes and mtimes,
+// which extracting as an unprivileged user can't. Paths too long for ustar
+// get a PAX extended header. Sockets can't be archived and are skipped.
+pub fn write_tar(
+    fs: &SquashFs,
+    out: &mut dyn Write,
+    logger: &slog::Logger,
+) -> Result<(), failure::Error> {
+    append(fs, &fs.root()?, b"./", out, logger)?;
+    out.write_all(&[0; 2 * BLOCK_SIZE])?;
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use crate::exec::ExecOptions;
+use crate::mount::directory::Directory;
+use crate::xar::Xar;
+
+use nix::errno::Errno;
+use nix::sys::signal::{killpg, sigprocmask, SigSet, SigmaskHow, Signal};
+use nix::unistd::{getpgrp, setpgid, tcsetpgrp, Pid};
+use std::ffi::OsString;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+// Passed on to the target's process group.
+const FORWARDED: &[Signal] = &[
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGHUP,
+    Signal::SIGWINCH,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+];
+
+// The signals supervise handles, blocked along with the mask to restore for
+// the target.
+pub struct Held {
+    signals: SigSet,
+    previous: SigSet,
+}
+
+// Block the signals supervise handles. This must happen before any thread is
+// started, the logger's included: a mask only covers the thread setting it
+// and those it starts later, and any thread with a signal unblocked would
+// take it with the default disposition and kill us. Left blocked, we exit
+// once the target does.
+pub fn hold_signals() -> Result<Held, failure::Error> {
+    let mut signals = SigSet::empty();
+    for signal in FORWARDED {
+        signals.add(*signal);
+    }
+    signals.add(Signal::SIGCHLD);
+    // Background groups calling tcsetpgrp get SIGTTOU, us included.
+    signals.add(Signal::SIGTTOU);
+    let mut previous = SigSet::empty();
+    sigprocmask(SigmaskHow::SIG_BLOCK, Some(&signals), Some(&mut previous))?;
+    Ok(Held { signals, previous })
+}
+
+impl Xar {
+    // Like exec, but stay resident: run the target in its own process group,
+    // forward signals to that group and return its exit code, 128 plus the
+    // signal if it was killed. Signals are `held` from before mounting, so a
+    // Ctrl-C mid-mount reaches the target once it starts rather than killing
+    // us with the mount half made. With `tty` the target's group becomes the
+    // terminal's foreground group, and ours again once it exits.
+    pub fn supervise(
+        &self,
+        held: Held,
+        mount: &Directory,
+        args: &[OsString],
+        options: &ExecOptions,
+        tty: bool,
+    ) -> Result<i32, failure::Error> {
+        if tty && unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+            bail!("--tty needs a terminal on stdin");
+        }
+        let Held {
+            signals: held,
+            previous,
+        } = held;
+
+        let (mut cmd, _, _) = self.prepare(mount, args, options)?;
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setpgid(0, 0) != 0
+                    || (tty && libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpid()) != 0)
+                    || libc::pthread_sigmask(
+                        libc::SIG_SETMASK,
+                        previous.as_ref(),
+                        std::ptr::null_mut(),
+                    ) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn()?;
+        let group = Pid::from_raw(child.id() as i32);
+        // Also from here, so signals forwarded before the child gets to it
+        // still reach its group. Fails harmlessly once it has exec'd.
+        let _ = setpgid(group, group);
+
+        let status = loop {
+            let signal = held.wait()?;
+            match signal {
+                // Also sent for squashfuse, started while mounting.
+                Signal::SIGCHLD => match child.try_wait()? {
+                    Some(status) => break status,
+                    None => continue,
+                },
+                Signal::SIGTTOU => continue,
+                _ => (),
+            }
+            debug!(
+                self.logger,
+                "Forwarding";
+                "signal" => format!("{:?}", signal),
+                "pgid" => group.as_raw()
+            );
+            match killpg(group, signal) {
+                // It exited in the meantime.
+                Err(nix::Error::Sys(Errno::ESRCH)) => (),
+                result => result?,
+            }
+        };
+        if tty {
+            tcsetpgrp(libc::STDIN_FILENO, getpgrp())?;
+        }
+        debug!(self.logger, "Exited"; "pid" => child.id(), "status" => status.to_string());
+        Ok(match (status.code(), status.signal()) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        })
+    }
+}
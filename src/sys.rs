@@ -0,0 +1,305 @@
+// The little of the host the mount path's decisions depend on: time,
+// environment variables and who we are, and file attributes, ownership,
+// small files and free space. The
+// mount code takes these as traits, `System` being the real host, so
+// timeouts, seeds and permission checks can be exercised against the
+// doubles below without root or a tmpfs.
+use crate::mount::mountinfo;
+use crate::mount::owner::Owner;
+
+use nix::sys::stat;
+use nix::sys::statvfs::statvfs;
+use nix::unistd::geteuid;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+    // Sleep up to `timeout`, waking early if the mount table changes after
+    // `generation`.
+    fn wait(&self, generation: u64, timeout: Duration);
+}
+
+pub trait Env {
+    fn var_os(&self, name: &str) -> Option<OsString>;
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.var_os(name).and_then(|value| value.into_string().ok())
+    }
+
+    // The effective user, who owns what we create.
+    fn euid(&self) -> u32;
+    // The user mounts are made for, who differs from the effective one under
+    // sudo, see Owner::invoking.
+    fn invoking_uid(&self) -> u32;
+}
+
+// What's checked of a path, its mode including the file type.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Attr {
+    pub uid: u32,
+    pub mode: u32,
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl Attr {
+    pub fn is_dir(&self) -> bool {
+        self.mode & libc::S_IFMT as u32 == libc::S_IFDIR as u32
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & libc::S_IFMT as u32 == libc::S_IFREG as u32
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Free {
+    pub bytes: u64,
+    pub inodes: u64,
+    // Zero where inodes are allocated dynamically.
+    pub total_inodes: u64,
+}
+
+pub trait Fs {
+    fn attr(&self, path: &Path) -> Result<Attr, failure::Error>;
+    // attr of a symlink itself rather than what it points to.
+    fn symlink_attr(&self, path: &Path) -> Result<Attr, failure::Error>;
+    // Create a file holding `contents` unless `path` exists, returning
+    // whether it was created.
+    fn create_new(&self, path: &Path, contents: &[u8], mode: u32) -> Result<bool, failure::Error>;
+    fn read_to_string(&self, path: &Path) -> Result<String, failure::Error>;
+    fn mkdir(&self, path: &Path, mode: u32) -> Result<(), failure::Error>;
+    fn free(&self, path: &Path) -> Result<Free, failure::Error>;
+    // Whether we may create entries in `path`, failing for read-only mounts.
+    fn writable(&self, path: &Path) -> Result<(), failure::Error>;
+}
+
+pub struct System;
+
+impl Clock for System {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wait(&self, generation: u64, timeout: Duration) {
+        mountinfo::wait_for_change(generation, timeout)
+    }
+}
+
+impl Env for System {
+    fn var_os(&self, name: &str) -> Option<OsString> {
+        env::var_os(name)
+    }
+
+    fn euid(&self) -> u32 {
+        geteuid().as_raw()
+    }
+
+    fn invoking_uid(&self) -> u32 {
+        Owner::invoking().uid.as_raw()
+    }
+}
+
+fn to_attr(attr: fs::Metadata) -> Attr {
+    Attr {
+        uid: attr.uid(),
+        mode: attr.permissions().mode(),
+        dev: attr.dev(),
+        ino: attr.ino(),
+    }
+}
+
+impl Fs for System {
+    fn attr(&self, path: &Path) -> Result<Attr, failure::Error> {
+        Ok(to_attr(fs::metadata(path)?))
+    }
+
+    fn symlink_attr(&self, path: &Path) -> Result<Attr, failure::Error> {
+        Ok(to_attr(fs::symlink_metadata(path)?))
+    }
+
+    fn create_new(&self, path: &Path, contents: &[u8], mode: u32) -> Result<bool, failure::Error> {
+        let created = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(path);
+        match created {
+            Ok(mut file) => {
+                file.write_all(contents)?;
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<String, failure::Error> {
+        Ok(fs::read_to_string(path)?)
+    }
+
+    fn mkdir(&self, path: &Path, mode: u32) -> Result<(), failure::Error> {
+        nix::unistd::mkdir(path, stat::Mode::from_bits_truncate(mode as libc::mode_t))?;
+        Ok(())
+    }
+
+    fn free(&self, path: &Path) -> Result<Free, failure::Error> {
+        let stat = statvfs(path)?;
+        Ok(Free {
+            bytes: stat.blocks_available() as u64 * stat.fragment_size() as u64,
+            inodes: stat.files_available() as u64,
+            total_inodes: stat.files() as u64,
+        })
+    }
+
+    // access(2) reports EROFS for read-only filesystems as well as EACCES.
+    fn writable(&self, path: &Path) -> Result<(), failure::Error> {
+        nix::unistd::access(path, nix::unistd::AccessFlags::W_OK)?;
+        Ok(())
+    }
+}
+
+// A clock that only moves when told to, or by the full timeout of a wait.
+pub struct ManualClock(Mutex<Instant>);
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock(Mutex::new(Instant::now()))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+
+    fn wait(&self, _generation: u64, timeout: Duration) {
+        self.advance(timeout);
+    }
+}
+
+// Variables for a process run by `uid`, without sudo.
+#[derive(Default)]
+pub struct MapEnv {
+    pub vars: BTreeMap<String, String>,
+    pub uid: u32,
+}
+
+impl Env for MapEnv {
+    fn var_os(&self, name: &str) -> Option<OsString> {
+        self.vars.get(name).map(OsString::from)
+    }
+
+    fn euid(&self) -> u32 {
+        self.uid
+    }
+
+    fn invoking_uid(&self) -> u32 {
+        self.uid
+    }
+}
+
+// Paths, their attributes and the contents of files, without symlinks.
+// mkdir and create_new add entries owned by `uid`, and every path reports
+// the same free space and writability.
+#[derive(Default)]
+pub struct MapFs {
+    pub uid: u32,
+    pub attrs: Mutex<BTreeMap<PathBuf, Attr>>,
+    pub files: Mutex<BTreeMap<PathBuf, Vec<u8>>>,
+    pub free: Free,
+    pub read_only: bool,
+}
+
+impl MapFs {
+    // Add a directory with `uid` and `mode`, the permission bits alone.
+    pub fn add_dir(&self, path: &str, uid: u32, mode: u32) {
+        let attr = Attr {
+            uid,
+            mode: libc::S_IFDIR as u32 | mode,
+            ..Attr::default()
+        };
+        self.attrs.lock().unwrap().insert(PathBuf::from(path), attr);
+    }
+}
+
+impl Fs for MapFs {
+    fn attr(&self, path: &Path) -> Result<Attr, failure::Error> {
+        match self.attrs.lock().unwrap().get(path) {
+            Some(attr) => Ok(*attr),
+            None => bail!("{} not found", path.display()),
+        }
+    }
+
+    fn symlink_attr(&self, path: &Path) -> Result<Attr, failure::Error> {
+        self.attr(path)
+    }
+
+    fn create_new(&self, path: &Path, contents: &[u8], mode: u32) -> Result<bool, failure::Error> {
+        let mut attrs = self.attrs.lock().unwrap();
+        if attrs.contains_key(path) {
+            return Ok(false);
+        }
+        let attr = Attr {
+            uid: self.uid,
+            mode: libc::S_IFREG as u32 | mode,
+            ..Attr::default()
+        };
+        attrs.insert(path.to_path_buf(), attr);
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), contents.to_vec());
+        Ok(true)
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<String, failure::Error> {
+        match self.files.lock().unwrap().get(path) {
+            Some(contents) => Ok(String::from_utf8(contents.clone())?),
+            None => bail!("{} not found", path.display()),
+        }
+    }
+
+    fn mkdir(&self, path: &Path, mode: u32) -> Result<(), failure::Error> {
+        let mut attrs = self.attrs.lock().unwrap();
+        if attrs.contains_key(path) {
+            bail!("{} exists", path.display());
+        }
+        let attr = Attr {
+            uid: self.uid,
+            mode: libc::S_IFDIR as u32 | mode,
+            ..Attr::default()
+        };
+        attrs.insert(path.to_path_buf(), attr);
+        Ok(())
+    }
+
+    fn free(&self, _path: &Path) -> Result<Free, failure::Error> {
+        Ok(self.free)
+    }
+
+    fn writable(&self, path: &Path) -> Result<(), failure::Error> {
+        if self.read_only {
+            bail!("{} is on a read-only filesystem", path.display());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+// Support bundles: one gzipped JSON file holding the command line, a scrubbed
+// environment, the FUSE mounts, and every log record of the run, whatever the
+// log level, with its time since start.
+use crate::exec::matches;
+use crate::mount::mountinfo;
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Variables whose names match these, ignoring case, have their values
+// withheld.
+const SCRUBBED: &[&str] = &[
+    "*TOKEN*",
+    "*SECRET*",
+    "*PASSW*",
+    "*KEY*",
+    "*CREDENTIAL*",
+    "*AUTH*",
+    "*COOKIE*",
+    "*SESSION*",
+];
+
+#[derive(Serialize)]
+struct Event {
+    elapsed_ms: u128,
+    level: &'static str,
+    message: String,
+    values: BTreeMap<String, String>,
+}
+
+struct Values<'a>(&'a mut BTreeMap<String, String>);
+
+impl<'a> slog::Serializer for Values<'a> {
+    fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+        self.0.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Timeline {
+    start: Instant,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Timeline {
+    pub fn new() -> Result<Timeline, failure::Error> {
+        if !cfg!(feature = "gzip") {
+            bail!("this xarfuse was built without the gzip feature trace bundles need");
+        }
+        Ok(Timeline {
+            start: Instant::now(),
+            events: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    fn record(&self, record: &slog::Record, values: &slog::OwnedKVList) {
+        use slog::KV;
+        let mut event = Event {
+            elapsed_ms: self.start.elapsed().as_millis(),
+            level: record.level().as_str(),
+            message: record.msg().to_string(),
+            values: BTreeMap::new(),
+        };
+        let _ = record
+            .kv()
+            .serialize(record, &mut Values(&mut event.values));
+        let _ = values.serialize(record, &mut Values(&mut event.values));
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event);
+        }
+    }
+}
+
+// Passes records on to `drain` after adding them to the timeline, if any.
+// Placed outside any level filter or async drain, so it sees every record
+// as it's made.
+pub struct Recorder<D> {
+    drain: D,
+    timeline: Option<Timeline>,
+}
+
+impl<D> Recorder<D> {
+    pub fn new(drain: D, timeline: Option<Timeline>) -> Recorder<D> {
+        Recorder { drain, timeline }
+    }
+}
+
+impl<D: slog::Drain> slog::Drain for Recorder<D> {
+    type Ok = D::Ok;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        if let Some(timeline) = &self.timeline {
+            timeline.record(record, values);
+        }
+        self.drain.log(record, values)
+    }
+}
+
+#[derive(Serialize)]
+struct Bundle<'a> {
+    version: &'static str,
+    args: Vec<String>,
+    environment: BTreeMap<String, String>,
+    mounts: Vec<String>,
+    timeline: &'a [Event],
+    error: Option<Vec<String>>,
+}
+
+fn scrubbed(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    SCRUBBED.iter().any(|pattern| matches(pattern, &upper))
+}
+
+fn environment() -> BTreeMap<String, String> {
+    env::vars_os()
+        .map(|(name, value)| {
+            let name = name.to_string_lossy().into_owned();
+            let value = if scrubbed(&name) {
+                "<scrubbed>".to_string()
+            } else {
+                value.to_string_lossy().into_owned()
+            };
+            (name, value)
+        })
+        .collect()
+}
+
+// FUSE mounts only, the rest of the table is rarely relevant and can be long.
+fn mounts() -> Vec<String> {
+    match mountinfo::read() {
+        Ok(mounts) => mounts
+            .iter()
+            .filter(|info| info.fs_type.starts_with("fuse"))
+            .map(|info| {
+                format!(
+                    "{} on {} type {} ({})",
+                    info.source,
+                    info.mount_point.display(),
+                    info.fs_type,
+                    info.options.join(",")
+                )
+            })
+            .collect(),
+        Err(e) => vec![format!("unable to read mountinfo: {}", e)],
+    }
+}
+
+// A --env assignment, scrubbed like the environment.
+fn assignment(value: &str) -> String {
+    match value.splitn(2, '=').next() {
+        Some(name) if scrubbed(name) => format!("{}=<scrubbed>", name),
+        _ => value.to_string(),
+    }
+}
+
+// The command line less what the target is given, which may be private: the
+// last `target_args` arguments, those of exec's target, and run-many's
+// commands. --env values are scrubbed like the environment.
+fn arguments(args: &[String], target_args: usize) -> Vec<String> {
+    let own = args.len().saturating_sub(target_args);
+    let mut scrubbed = Vec::with_capacity(args.len());
+    let mut option: Option<&str> = None;
+    for arg in &args[..own] {
+        let arg = match option.take() {
+            Some("--env") => assignment(arg),
+            // A command's values run until a ;.
+            Some(_) if arg == ";" => arg.clone(),
+            Some(_) => {
+                option = Some("--cmd");
+                "<redacted>".to_string()
+            }
+            None if arg == "--env" || arg == "--cmd" => {
+                option = Some(arg.as_str());
+                arg.clone()
+            }
+            None if arg.starts_with("--env=") => format!("--env={}", assignment(&arg[6..])),
+            None if arg.starts_with("--cmd=") => {
+                option = Some("--cmd");
+                "--cmd=<redacted>".to_string()
+            }
+            None => arg.clone(),
+        };
+        scrubbed.push(arg);
+    }
+    scrubbed.extend(args[own..].iter().map(|_| "<redacted>".to_string()));
+    scrubbed
+}
+
+// `target_args` counts the trailing arguments which are the target's.
+pub fn write(
+    path: &Path,
+    timeline: &Timeline,
+    target_args: usize,
+    error: Option<&failure::Error>,
+) -> Result<(), failure::Error> {
+    let events = match timeline.events.lock() {
+        Ok(events) => events,
+        Err(_) => bail!("the timeline was poisoned"),
+    };
+    let bundle = Bundle {
+        version: env!("CARGO_PKG_VERSION"),
+        args: arguments(
+            &env::args_os()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>(),
+            target_args,
+        ),
+        environment: environment(),
+        mounts: mounts(),
+        timeline: &events,
+        error: error.map(|e| e.iter_chain().map(|cause| cause.to_string()).collect()),
+    };
+    let file = File::create(path)?;
+    compress(file, &bundle)
+}
+
+#[cfg(feature = "gzip")]
+fn compress(file: File, bundle: &Bundle) -> Result<(), failure::Error> {
+    let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    serde_json::to_writer_pretty(&mut encoder, bundle)?;
+    encoder.finish()?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "gzip"))]
+fn compress(_file: File, _bundle: &Bundle) -> Result<(), failure::Error> {
+    bail!("this xarfuse was built without the gzip feature trace bundles need")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn arguments_keep_secrets_out() {
+        let args = strings(&[
+            "xarfuse",
+            "--trace-bundle",
+            "/tmp/bundle",
+            "exec",
+            "--env",
+            "API_TOKEN=hunter2",
+            "--env=LANG=C",
+            "a.xar",
+            "--password",
+            "hunter2",
+        ]);
+        assert_eq!(
+            arguments(&args, 2),
+            strings(&[
+                "xarfuse",
+                "--trace-bundle",
+                "/tmp/bundle",
+                "exec",
+                "--env",
+                "API_TOKEN=<scrubbed>",
+                "--env=LANG=C",
+                "a.xar",
+                "<redacted>",
+                "<redacted>",
+            ])
+        );
+        let args = strings(&[
+            "xarfuse",
+            "run-many",
+            "a.xar",
+            "--cmd",
+            "bin/login",
+            "secret",
+            ";",
+            "--unmount",
+        ]);
+        assert_eq!(
+            arguments(&args, 0),
+            strings(&[
+                "xarfuse",
+                "run-many",
+                "a.xar",
+                "--cmd",
+                "<redacted>",
+                "<redacted>",
+                ";",
+                "--unmount",
+            ])
+        );
+    }
+}
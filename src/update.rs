@@ -0,0 +1,185 @@
+// Replacing the running xarfuse with a newer release, for fleets shipping it
+// as a standalone helper rather than through a package manager. Releases are
+// described by <base>/<channel>/<arch>-<os>.json, a manifest giving the
+// version, download URL and SHA-256 of the binary, signed with ed25519 in
+// the hex file beside it with a .sig suffix. The public key is built in.
+
+// Overrides the base URL built in from XARFUSE_UPDATE_URL.
+#[cfg(feature = "self-update")]
+const XAR_UPDATE_URL: &str = "XAR_UPDATE_URL";
+
+pub enum Outcome {
+    UpToDate(String),
+    Updated { from: String, to: String },
+}
+
+#[cfg(feature = "self-update")]
+mod release {
+    use super::{Outcome, XAR_UPDATE_URL};
+
+    use ed25519_dalek::{PublicKey, Signature, Verifier};
+    use serde::Deserialize;
+    use sha2::{Digest, Sha256};
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+
+    const UPDATE_KEY: Option<&str> = option_env!("XARFUSE_UPDATE_KEY");
+    const UPDATE_URL: Option<&str> = option_env!("XARFUSE_UPDATE_URL");
+    // Generous for a static binary, and keeps a hostile server from filling
+    // the disk.
+    const MAX_BINARY: u64 = 256 * 1024 * 1024;
+    const MAX_MANIFEST: u64 = 64 * 1024;
+
+    #[derive(Deserialize)]
+    struct Manifest {
+        version: String,
+        url: String,
+        sha256: String,
+    }
+
+    fn unhex(value: &str) -> Result<Vec<u8>, failure::Error> {
+        let value = value.trim();
+        // Slicing by bytes would split multibyte characters.
+        if !value.is_ascii() {
+            bail!("invalid hex {:?}", value);
+        }
+        if value.len() % 2 != 0 {
+            bail!("odd length hex {:?}", value);
+        }
+        (0..value.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&value[i..i + 2], 16)
+                    .map_err(|_| format_err!("invalid hex {:?}", value))
+            })
+            .collect()
+    }
+
+    // Fleets commonly reach release servers through a proxy, which ureq only
+    // honors from the environment when asked to.
+    fn fetch(url: &str, limit: u64) -> Result<Vec<u8>, failure::Error> {
+        let agent = ureq::AgentBuilder::new().try_proxy_from_env(true).build();
+        let response = agent
+            .get(url)
+            .call()
+            .map_err(|e| format_err!("while fetching {}: {}", url, e))?;
+        let mut body = Vec::new();
+        response
+            .into_reader()
+            .take(limit + 1)
+            .read_to_end(&mut body)?;
+        if body.len() as u64 > limit {
+            bail!("{} is larger than {} bytes", url, limit);
+        }
+        Ok(body)
+    }
+
+    fn verified_manifest(base: &str, channel: &str) -> Result<Manifest, failure::Error> {
+        let key = match UPDATE_KEY {
+            Some(key) => PublicKey::from_bytes(&unhex(key)?)
+                .map_err(|e| format_err!("built in update key: {}", e))?,
+            None => bail!("this xarfuse was built without XARFUSE_UPDATE_KEY"),
+        };
+        let url = format!(
+            "{}/{}/{}-{}.json",
+            base.trim_end_matches('/'),
+            channel,
+            env::consts::ARCH,
+            env::consts::OS
+        );
+        let manifest = fetch(&url, MAX_MANIFEST)?;
+        let signature = fetch(&format!("{}.sig", url), MAX_MANIFEST)?;
+        let signature = Signature::try_from(&unhex(&String::from_utf8_lossy(&signature))?[..])
+            .map_err(|e| format_err!("signature of {}: {}", url, e))?;
+        key.verify(&manifest, &signature)
+            .map_err(|_| format_err!("{} is not signed by the update key", url))?;
+        Ok(serde_json::from_slice(&manifest)?)
+    }
+
+    pub fn self_update(
+        logger: &slog::Logger,
+        channel: &str,
+        force: bool,
+    ) -> Result<Outcome, failure::Error> {
+        if channel.is_empty()
+            || !channel
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            bail!(
+                "channel {:?} must be lowercase letters, digits and -",
+                channel
+            );
+        }
+        let base = match env::var(XAR_UPDATE_URL)
+            .ok()
+            .or_else(|| UPDATE_URL.map(String::from))
+        {
+            Some(base) => base,
+            None => bail!("no update URL, set {}", XAR_UPDATE_URL),
+        };
+        let current = env!("CARGO_PKG_VERSION").to_string();
+        let manifest = verified_manifest(&base, channel)?;
+        if manifest.version == current && !force {
+            return Ok(Outcome::UpToDate(current));
+        }
+
+        let binary = fetch(&manifest.url, MAX_BINARY)?;
+        let digest: String = Sha256::digest(&binary)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if digest != manifest.sha256.to_ascii_lowercase() {
+            bail!(
+                "{} has SHA-256 {}, the manifest says {}",
+                manifest.url,
+                digest,
+                manifest.sha256
+            );
+        }
+
+        // Written beside the binary, so the rename is atomic and anyone
+        // running it keeps the old inode.
+        let exe = env::current_exe()?.canonicalize()?;
+        let staged = exe.with_file_name(format!(".xarfuse-update.{}", std::process::id()));
+        debug!(
+            logger,
+            "Installing update";
+            "version" => &manifest.version,
+            "binary" => exe.display().to_string()
+        );
+        let installed = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o755)
+            .open(&staged)
+            .and_then(|mut file| {
+                file.write_all(&binary)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&staged, &exe));
+        if let Err(e) = installed {
+            let _ = fs::remove_file(&staged);
+            return Err(format_err!("while replacing {}: {}", exe.display(), e));
+        }
+        Ok(Outcome::Updated {
+            from: current,
+            to: manifest.version,
+        })
+    }
+}
+
+#[cfg(feature = "self-update")]
+pub use release::self_update;
+
+#[cfg(not(feature = "self-update"))]
+pub fn self_update(
+    _logger: &slog::Logger,
+    _channel: &str,
+    _force: bool,
+) -> Result<Outcome, failure::Error> {
+    bail!("this xarfuse was built without the self-update feature")
+}
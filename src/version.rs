@@ -0,0 +1,93 @@
+use crate::error::XarError;
+
+use std::fmt;
+use std::str::FromStr;
+
+// Oldest and newest header formats this xarfuse understands. 1.1 added per
+// architecture payloads and named sections.
+pub const MIN_FORMAT: FormatVersion = FormatVersion { major: 1, minor: 0 };
+pub const MAX_FORMAT: FormatVersion = FormatVersion { major: 1, minor: 1 };
+// Builders have long written the build time as VERSION, which predates the
+// format having versions and is read as 1.0.
+const LEGACY_TIMESTAMP: u64 = 1_000_000;
+
+// The header's VERSION, MAJOR[.MINOR].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FormatVersion {
+    pub major: u64,
+    pub minor: u64,
+}
+
+impl FromStr for FormatVersion {
+    type Err = failure::Error;
+
+    fn from_str(value: &str) -> Result<FormatVersion, failure::Error> {
+        let mut parts = value.trim().splitn(2, '.');
+        let major: u64 = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| format_err!("VERSION {:?} must be MAJOR[.MINOR]", value))?;
+        let minor: u64 = match parts.next() {
+            Some(minor) => minor
+                .parse()
+                .map_err(|_| format_err!("VERSION {:?} must be MAJOR[.MINOR]", value))?,
+            None => 0,
+        };
+        if minor == 0 && major >= LEGACY_TIMESTAMP {
+            return Ok(MIN_FORMAT);
+        }
+        Ok(FormatVersion { major, minor })
+    }
+}
+
+impl fmt::Display for FormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+// Fails for a header VERSION that doesn't parse or is outside
+// MIN_FORMAT..=MAX_FORMAT. Headers keep VERSION as written and only mounting
+// checks it, so --ignore-version also covers ones which don't parse and
+// commands which just read headers work on any.
+pub fn check_supported(version: &str) -> Result<(), failure::Error> {
+    let version: FormatVersion = version.parse()?;
+    version.check_supported()?;
+    Ok(())
+}
+
+impl FormatVersion {
+    // Fails for formats outside MIN_FORMAT..=MAX_FORMAT.
+    pub fn check_supported(self) -> Result<(), XarError> {
+        if self > MAX_FORMAT {
+            return Err(XarError::NewerFormat {
+                version: self.to_string(),
+                supported: MAX_FORMAT.to_string(),
+            });
+        }
+        if self < MIN_FORMAT {
+            return Err(XarError::OlderFormat {
+                version: self.to_string(),
+                supported: MIN_FORMAT.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_range() {
+        assert!(check_supported("1").is_ok());
+        assert!(check_supported("1.1").is_ok());
+        // A build time from before VERSION meant the format.
+        assert!(check_supported("1597766400").is_ok());
+        assert!(check_supported("1.2").is_err());
+        assert!(check_supported("0.9").is_err());
+        assert!(check_supported("next").is_err());
+    }
+}
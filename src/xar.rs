@@ -1,35 +1,335 @@
 extern crate failure;
 extern crate serde_aux;
 
-use serde::Deserialize;
-use serde_aux::prelude::deserialize_number_from_string;
+use crate::appimage;
+use crate::header;
+use crate::mount::naming::NamingStrategy;
+use crate::mount::MountOptions;
+use crate::squashfs::SquashFs;
+use crate::version::MIN_FORMAT;
+use failure::ResultExt;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_aux::prelude::{deserialize_number_from_string, deserialize_option_number_from_string};
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
 
 const DEFAULT_HEADER_SIZE: usize = 4 * 1024;
+// EROFS keeps its superblock, which starts with this, 1KiB into the image.
+const EROFS_SUPER_OFFSET: u64 = 1024;
+const EROFS_MAGIC: u32 = 0xe0f5_e1e2;
+// Named payloads, given as OFFSET_<SECTION>, which aren't architectures.
+const SECTIONS: &[&str] = &["MAIN", "DEBUG"];
+
+// The filesystem image the payload is, the header's FORMAT.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Payload {
+    Squashfs,
+    Erofs,
+}
+
+impl Default for Payload {
+    fn default() -> Payload {
+        Payload::Squashfs
+    }
+}
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "UPPERCASE")]
 pub struct XarHeader {
-    #[serde(deserialize_with = "deserialize_number_from_string")]
+    // Fat archives may give these only per architecture, see select_arch.
+    #[serde(default, deserialize_with = "deserialize_number_from_string")]
     pub offset: u64,
+    // As written, see version::check_supported.
     pub version: String,
+    #[serde(default, rename = "FORMAT")]
+    pub payload: Payload,
+    #[serde(default)]
     pub xarexec_target: String,
+    #[serde(deserialize_with = "deserialize_uuid")]
     pub uuid: String,
     pub mount_root: Option<String>,
+    // Who shares the mount, see mount/naming.rs.
+    #[serde(default)]
+    pub mount_naming: Option<NamingStrategy>,
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub lease_duration: Option<u64>,
+    // Optional build provenance, SOURCE_DATE is seconds since the epoch.
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub source_date: Option<u64>,
+    pub build_id: Option<String>,
+    pub vcs_revision: Option<String>,
+    pub builder: Option<String>,
+    // Seconds squashfuse waits idle before unmounting.
+    #[serde(
+        default,
+        alias = "IDLE_TIMEOUT",
+        deserialize_with = "deserialize_option_number_from_string"
+    )]
+    pub fuse_timeout: Option<u64>,
+    // Directory inside the archive exec --chdir starts the target in.
+    pub run_cwd: Option<String>,
+    // Script inside the archive run after the archive is first mounted.
+    pub post_mount_hook: Option<String>,
+    // Colon separated paths inside the archive read after mounting, to
+    // populate the page cache.
+    pub warm_paths: Option<String>,
+    // Fields this version doesn't know by name, including the per
+    // architecture XAREXEC_TARGET_<ARCH> and OFFSET_<ARCH>.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, String>,
+}
+
+// The UUID as written, which names mount directories and lockfiles exactly
+// as xarexec_fuse names them. It must be a full UUID, hyphenated or bare
+// hex, or a shorter alphanumeric id, which xar builders commonly emit.
+// Either way it's safe to use as a file name.
+//
+// Paths deliberately don't use the canonical lowercase form. xarexec_fuse
+// and older xarfuse builds mount the same archive at its raw UUID, so a
+// normalized name would give the archive a second mount, lockfile and lease
+// that the other side never sees.
+// Normalizing is left to comparisons, see normalize_uuid.
+pub fn validate_uuid(value: &str) -> Result<String, failure::Error> {
+    normalize_uuid(value)?;
+    Ok(value.to_string())
+}
+
+// For comparing and displaying UUIDs only: full UUIDs in lowercase
+// hyphenated form, shorter ids lowercased.
+pub fn normalize_uuid(value: &str) -> Result<String, failure::Error> {
+    if let Ok(uuid) = Uuid::parse_str(value) {
+        return Ok(uuid.to_hyphenated().to_string());
+    }
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric()) {
+        bail!("UUID {:?} must be a UUID or alphanumeric", value);
+    }
+    Ok(value.to_ascii_lowercase())
+}
+
+fn deserialize_uuid<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    validate_uuid(&value).map_err(serde::de::Error::custom)
+}
+
+impl XarHeader {
+    // The UUID as a typed value, None for short legacy ids.
+    pub fn parsed_uuid(&self) -> Option<Uuid> {
+        Uuid::parse_str(&self.uuid).ok()
+    }
+
+    // The UUID for comparison and display. Paths use the raw `uuid`.
+    pub fn normalized_uuid(&self) -> String {
+        normalize_uuid(&self.uuid).unwrap_or_else(|_| self.uuid.clone())
+    }
+
+    // Architectures with their own target or payload, e.g. X86_64.
+    pub fn architectures(&self) -> BTreeSet<&str> {
+        self.extra
+            .keys()
+            .filter_map(|key| {
+                key.strip_prefix("XAREXEC_TARGET_")
+                    .or_else(|| key.strip_prefix("OFFSET_"))
+            })
+            .filter(|arch| !SECTIONS.contains(arch))
+            .collect()
+    }
+
+    // Replace XAREXEC_TARGET and OFFSET with those for `machine`, as from
+    // uname -m, where a fat archive gives them. Either falls back to the
+    // plain field, or for OFFSET to OFFSET_MAIN, one of which is required for
+    // archives with a single payload.
+    pub fn select_arch(&mut self, machine: &str) -> Result<(), failure::Error> {
+        let arch = machine.to_ascii_uppercase();
+        if let Some(target) = self.extra.get(&format!("XAREXEC_TARGET_{}", arch)) {
+            self.xarexec_target = target.clone();
+        }
+        if let Some(offset) = self.extra.get(&format!("OFFSET_{}", arch)) {
+            self.offset = offset
+                .parse()
+                .with_context(|_| format!("while parsing OFFSET_{}", arch))?;
+        } else if let Some(offset) = self.extra.get("OFFSET_MAIN") {
+            if self.offset == 0 {
+                self.offset = offset
+                    .parse()
+                    .with_context(|_| "while parsing OFFSET_MAIN")?;
+            }
+        }
+        if self.xarexec_target.is_empty() || self.offset == 0 {
+            let architectures = self.architectures();
+            if architectures.is_empty() {
+                bail!("header must have XAREXEC_TARGET and OFFSET");
+            }
+            bail!(
+                "archive has no payload for {}, only for {}",
+                machine,
+                architectures
+                    .iter()
+                    .map(|arch| arch.to_ascii_lowercase())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+// What the archive file is. Only XARs have a header, the others get one
+// made up from their contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Xar,
+    Squashfs,
+    AppImage,
 }
 
 pub struct Xar {
     pub logger: slog::Logger,
+    pub format: Format,
+    // Absolute, with symlinks resolved, so it's valid from any directory.
     pub archive: PathBuf,
+    // The path as the caller gave it.
+    pub original: PathBuf,
     pub header: XarHeader,
+    pub mount_options: MountOptions,
+    // Pins the archive's inode from header parse until the mount completes.
+    handle: Option<File>,
+}
+
+// An O_PATH descriptor can't be read, only reopened through /proc, which is
+// enough to keep renames or replacement of the archive from swapping in a
+// different payload. Returns the handle, a readable file and canonical path.
+#[cfg(target_os = "linux")]
+fn open_handle(path: &Path) -> Result<(Option<File>, File, PathBuf), failure::Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    let handle = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(path)?;
+    let fd_path = format!("/proc/self/fd/{}", handle.as_raw_fd());
+    let file = File::open(&fd_path)?;
+    // Resolving the descriptor, rather than the path again, names the file
+    // that was opened.
+    let canonical = std::fs::read_link(&fd_path).or_else(|_| path.canonicalize())?;
+    Ok((Some(handle), file, canonical))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_handle(path: &Path) -> Result<(Option<File>, File, PathBuf), failure::Error> {
+    Ok((None, File::open(path)?, path.canonicalize()?))
+}
+
+// Where the pinned file can be reopened, also from other processes, or
+// `path` without a handle.
+#[cfg(target_os = "linux")]
+fn reopen_path(handle: &Option<File>, path: &Path) -> PathBuf {
+    use std::os::unix::io::AsRawFd;
+    match handle {
+        Some(handle) => PathBuf::from(format!(
+            "/proc/{}/fd/{}",
+            std::process::id(),
+            handle.as_raw_fd()
+        )),
+        None => path.to_path_buf(),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reopen_path(_handle: &Option<File>, path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+fn is_erofs(path: &Path, offset: u64) -> bool {
+    use std::os::unix::fs::FileExt;
+    let mut magic = [0; 4];
+    match File::open(path) {
+        Ok(file) => {
+            file.read_exact_at(&mut magic, offset + EROFS_SUPER_OFFSET)
+                .is_ok()
+                && u32::from_le_bytes(magic) == EROFS_MAGIC
+        }
+        Err(_) => false,
+    }
+}
+
+// Bytes of the image hashed into the identity of a headerless archive, which
+// covers the squashfs superblock and EROFS's, 1KiB in.
+const SUPERBLOCK_SIZE: usize = 4 * 1024;
+
+// Names a headerless image without reading all of it: its superblocks,
+// which record the image's size, creation time and root, and the file's
+// size, mtime and inode. Use content_hash where the contents matter.
+fn identity(path: &Path, offset: u64) -> Result<String, failure::Error> {
+    use sha2::{Digest, Sha256};
+    use std::os::unix::fs::FileExt;
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let mut superblock = vec![0; SUPERBLOCK_SIZE];
+    let mut read = 0;
+    while read < superblock.len() {
+        match file.read_at(&mut superblock[read..], offset + read as u64)? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&superblock[..read]);
+    for field in &[
+        metadata.size(),
+        metadata.mtime() as u64,
+        metadata.mtime_nsec() as u64,
+        metadata.dev(),
+        metadata.ino(),
+    ] {
+        hasher.update(&field.to_le_bytes());
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn hash_file(path: &Path) -> Result<String, failure::Error> {
+    use sha2::{Digest, Sha256};
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
 }
 
 impl Xar {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(logger)))]
     pub fn from_file(archive_path: PathBuf, logger: slog::Logger) -> Result<Xar, failure::Error> {
-        let file = File::open(&archive_path)?;
+        let (handle, mut file, canonical) = open_handle(&archive_path)
+            .with_context(|_| format!("while opening {}", archive_path.display()))?;
+        let mut prefix = [0; appimage::HEADER_SIZE];
+        let read = file.read(&mut prefix)?;
+        if appimage::is_appimage(&prefix[..read]) {
+            return Xar::headerless(
+                archive_path,
+                handle,
+                canonical,
+                Format::AppImage,
+                None,
+                logger,
+            );
+        }
+        file.seek(SeekFrom::Start(0))?;
         let mut reader = BufReader::with_capacity(DEFAULT_HEADER_SIZE, file);
 
         loop {
@@ -43,15 +343,219 @@ impl Xar {
                         reader.seek(SeekFrom::Start(0))?;
                         let mut buffer = vec![0; offset.try_into().unwrap()];
                         let _read = reader.read(&mut buffer)?;
-                        let header: XarHeader = toml::from_slice(&buffer)?;
+                        let text = String::from_utf8_lossy(&buffer);
+                        let normalized = header::normalize(&text).with_context(|_| {
+                            format!("while parsing the header of {}", archive_path.display())
+                        })?;
+                        let mut header: XarHeader =
+                            toml::from_str(&normalized).map_err(header::locate)?;
+                        header.select_arch(nix::sys::utsname::uname().machine())?;
+                        debug!(
+                            logger,
+                            "Parsed header";
+                            "archive" => archive_path.display().to_string(),
+                            "header" => serde_json::to_string(&header).unwrap_or_default()
+                        );
                         return Ok(Xar {
                             logger: logger,
-                            archive: PathBuf::from(&archive_path),
+                            format: Format::Xar,
+                            archive: canonical,
+                            original: archive_path,
                             header: header,
+                            mount_options: MountOptions::default(),
+                            handle,
                         });
                     }
                 }
             }
         }
     }
+
+    // A plain squashfs or EROFS image, without a header, mounted under `uuid`
+    // or one derived from it, see identity. There's no XAREXEC_TARGET, so it
+    // can't be exec'd.
+    pub fn from_squashfs(
+        image_path: PathBuf,
+        uuid: Option<&str>,
+        logger: slog::Logger,
+    ) -> Result<Xar, failure::Error> {
+        let (handle, _file, canonical) = open_handle(&image_path)
+            .with_context(|_| format!("while opening {}", image_path.display()))?;
+        Xar::headerless(
+            image_path,
+            handle,
+            canonical,
+            Format::Squashfs,
+            uuid,
+            logger,
+        )
+    }
+
+    // AppImages are exec'd through their AppRun.
+    fn headerless(
+        original: PathBuf,
+        handle: Option<File>,
+        canonical: PathBuf,
+        format: Format,
+        uuid: Option<&str>,
+        logger: slog::Logger,
+    ) -> Result<Xar, failure::Error> {
+        let source = reopen_path(&handle, &canonical);
+        let (offset, target) = match format {
+            Format::AppImage => {
+                let mut prefix = [0; appimage::HEADER_SIZE];
+                File::open(&source)?.read_exact(&mut prefix)?;
+                let offset = appimage::payload_offset(&prefix)?;
+                (offset, appimage::ENTRY_POINT.to_string())
+            }
+            _ => (0, String::new()),
+        };
+        let payload = if is_erofs(&source, offset) {
+            Payload::Erofs
+        } else {
+            SquashFs::open(&source, offset).with_context(|_| {
+                format!(
+                    "{} has no squashfs or EROFS image at offset {}",
+                    original.display(),
+                    offset
+                )
+            })?;
+            Payload::Squashfs
+        };
+        let uuid = match uuid {
+            Some(uuid) => validate_uuid(uuid)?,
+            None => normalize_uuid(&identity(&source, offset)?[..32])?,
+        };
+        let header = XarHeader {
+            offset,
+            version: MIN_FORMAT.to_string(),
+            payload,
+            xarexec_target: target,
+            uuid,
+            mount_root: None,
+            mount_naming: None,
+            lease_duration: None,
+            source_date: None,
+            build_id: None,
+            vcs_revision: None,
+            builder: None,
+            fuse_timeout: None,
+            run_cwd: None,
+            post_mount_hook: None,
+            warm_paths: None,
+            extra: BTreeMap::new(),
+        };
+        debug!(
+            logger,
+            "Using archive without a header";
+            "archive" => original.display().to_string(),
+            "format" => format!("{:?}", format),
+            "offset" => offset,
+            "uuid" => &header.uuid
+        );
+        Ok(Xar {
+            logger,
+            format,
+            archive: canonical,
+            original,
+            header,
+            mount_options: MountOptions::default(),
+            handle,
+        })
+    }
+
+    // Mount the payload at OFFSET_<NAME>, e.g. debug symbols, rather than the
+    // main one. Sections get their own mount directories.
+    pub fn select_section(&mut self, name: &str) -> Result<(), failure::Error> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!("section {:?} must be alphanumeric", name);
+        }
+        let name = name.to_ascii_lowercase();
+        if name == "main" {
+            self.mount_options.section = None;
+            return Ok(());
+        }
+        let key = format!("OFFSET_{}", name.to_ascii_uppercase());
+        self.header.offset = match self.header.extra.get(&key) {
+            Some(offset) => offset
+                .parse()
+                .with_context(|_| format!("while parsing {}", key))?,
+            None => bail!(
+                "{} has no {} section, its header lacks {}",
+                self.archive.display(),
+                name,
+                key
+            ),
+        };
+        self.mount_options.section = Some(name);
+        Ok(())
+    }
+
+    // Path squashfuse should open, which refers to the inode whose header was
+    // parsed even if the archive has since been renamed or replaced.
+    pub fn source_path(&self) -> PathBuf {
+        reopen_path(&self.handle, &self.archive)
+    }
+
+    // The pinned handle, for children which inherit it. Under sudo the
+    // mounter runs as the invoking user, who can't follow our /proc fd links
+    // but can reopen an inherited descriptor through its own.
+    pub fn source_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+        self.handle.as_ref().map(|handle| handle.as_raw_fd())
+    }
+
+    // source_path as seen from a child which inherited source_fd.
+    pub fn child_source_path(&self) -> PathBuf {
+        match self.source_fd() {
+            Some(fd) => PathBuf::from(format!("/proc/self/fd/{}", fd)),
+            None => self.archive.clone(),
+        }
+    }
+
+    // Hex SHA-256 of the whole archive. This reads every byte, so it's only
+    // computed for versioned mounts.
+    pub fn content_hash(&self) -> Result<String, failure::Error> {
+        hash_file(&self.source_path())
+    }
+
+    // Fails when the archive path no longer names the file that was parsed.
+    pub fn check_not_replaced(&self) -> Result<(), failure::Error> {
+        let handle = match &self.handle {
+            Some(handle) => handle.metadata()?,
+            None => return Ok(()),
+        };
+        match std::fs::metadata(&self.archive) {
+            Ok(current) if current.dev() == handle.dev() && current.ino() == handle.ino() => Ok(()),
+            _ => bail!(
+                "archive {} was replaced during mount",
+                self.archive.display()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuids_keep_their_spelling() {
+        let bare = "0123456789ABCDEF0123456789ABCDEF";
+        assert_eq!(validate_uuid(bare).unwrap(), bare);
+        assert_eq!(validate_uuid("d2f0c1ab").unwrap(), "d2f0c1ab");
+        assert_eq!(
+            normalize_uuid(bare).unwrap(),
+            "01234567-89ab-cdef-0123-456789abcdef"
+        );
+        assert!(validate_uuid("../etc").is_err());
+        assert!(validate_uuid("").is_err());
+    }
+
+    #[test]
+    fn headers_keep_unparsed_versions() {
+        let text = "#!/bin/sh\nOFFSET=\"4096\"\nVERSION=\"2.0-rc1\"\nUUID=\"d2f0c1ab\"\n";
+        let header: XarHeader = toml::from_str(&header::normalize(text).unwrap()).unwrap();
+        assert_eq!(header.version, "2.0-rc1");
+    }
 }